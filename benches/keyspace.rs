@@ -0,0 +1,65 @@
+// cargo bench --bench keyspace
+// prints SET/GET throughput for a growing number of threads, with a single
+// shard (the old global lock) next to the default sharded keyspace
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use kvds::app_server::parser::Command;
+use kvds::services::session::Session;
+use kvds::services::store::Store;
+use kvds::Settings;
+
+const OPS_PER_THREAD: usize = 200_000;
+
+fn run(store: Arc<Store>, threads: usize) -> f64 {
+    let started = Instant::now();
+    let handles = (0..threads)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .build()
+                    .unwrap();
+                runtime.block_on(async {
+                    let mut session = Session::default();
+                    for i in 0..OPS_PER_THREAD {
+                        let key = format!("key:{t}:{}", i % 1_000);
+                        if i % 4 == 0 {
+                            store
+                                .handle_on_memory(&mut session, Command::cmd_set(&key, "value"))
+                                .await;
+                        } else {
+                            store
+                                .handle_on_memory(&mut session, Command::cmd_get(&key))
+                                .await;
+                        }
+                    }
+                });
+            })
+        })
+        .collect::<Vec<_>>();
+    handles.into_iter().for_each(|h| h.join().unwrap());
+    (threads * OPS_PER_THREAD) as f64 / started.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(4, |n| n.get());
+    println!(
+        "{:>8} {:>16} {:>16}",
+        "threads", "1 shard ops/s", "64 shards ops/s"
+    );
+    let mut threads = 1;
+    while threads <= cores {
+        let single = run(
+            Store::new(Settings {
+                shards: 1,
+                ..Settings::default()
+            }),
+            threads,
+        );
+        let sharded = run(Store::new(Settings::default()), threads);
+        println!("{threads:>8} {single:>16.0} {sharded:>16.0}");
+        threads *= 2;
+    }
+}
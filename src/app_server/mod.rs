@@ -1,2 +1,4 @@
 pub mod parser;
+pub mod reply;
 pub mod socket_server;
+pub mod tls;
@@ -1,18 +1,114 @@
-use std::fmt::Error;
-use std::str::Chars;
+use std::fmt::{Display, Error};
+use std::slice::Iter;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command {
     PING,
-    GET { key: String },
-    SET { key: String, value: String },
-    DEL { key: String },
-    KEYS { pattern: String },
-    EXPIRE { key: String, sec: u64 },
+    GET {
+        key: String,
+    },
+    SET {
+        key: String,
+        value: String,
+    },
+    DEL {
+        key: String,
+    },
+    KEYS {
+        pattern: String,
+    },
+    EXPIRE {
+        key: String,
+        sec: u64,
+    },
     FLUSHALL,
-    TTL { key: String },
-    INCR { key: String },
-    DECR { key: String },
+    TTL {
+        key: String,
+    },
+    // like TTL in milliseconds
+    PTTL {
+        key: String,
+    },
+    INCR {
+        key: String,
+    },
+    DECR {
+        key: String,
+    },
+    SETEX {
+        key: String,
+        sec: u64,
+        value: String,
+    },
+    // SET with PXAT, an absolute deadline in unix milliseconds, how SETEX is logged
+    SETPXAT {
+        key: String,
+        value: String,
+        at: u64,
+    },
+    LPUSH {
+        key: String,
+        values: Vec<String>,
+    },
+    RPUSH {
+        key: String,
+        values: Vec<String>,
+    },
+    LPOP {
+        key: String,
+    },
+    RPOP {
+        key: String,
+    },
+    LRANGE {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    LLEN {
+        key: String,
+    },
+    MSET {
+        pairs: Vec<(String, String)>,
+    },
+    MGET {
+        keys: Vec<String>,
+    },
+    SELECT {
+        db: usize,
+    },
+    MOVE {
+        key: String,
+        db: usize,
+    },
+    SWAPDB {
+        a: usize,
+        b: usize,
+    },
+    FLUSHDB,
+    BGREWRITEAOF,
+    // absolute deadline in unix milliseconds, how expirations are logged
+    PEXPIREAT {
+        key: String,
+        at: u64,
+    },
+    SAVE,
+    BGSAVE,
+    LASTSAVE,
+    DUMP {
+        key: String,
+    },
+    // `ttl` in milliseconds, a unix time with `absttl`, 0 for none
+    RESTORE {
+        key: String,
+        ttl: u64,
+        payload: String,
+        replace: bool,
+        absttl: bool,
+    },
+    AUTH {
+        password: String,
+    },
 }
 
 impl Command {
@@ -37,6 +133,11 @@ impl Command {
             key: key.to_string(),
         }
     }
+    pub fn cmd_pttl(key: &str) -> Self {
+        Self::PTTL {
+            key: key.to_string(),
+        }
+    }
     pub fn cmd_expire(key: &str, sec: u64) -> Self {
         Self::EXPIRE {
             key: key.to_string(),
@@ -58,24 +159,115 @@ impl Command {
             key: key.to_string(),
         }
     }
+    pub fn cmd_setex(key: &str, sec: u64, value: &str) -> Self {
+        Self::SETEX {
+            key: key.to_string(),
+            sec,
+            value: value.to_string(),
+        }
+    }
+    pub fn cmd_lpush(key: &str, values: &[&str]) -> Self {
+        Self::LPUSH {
+            key: key.to_string(),
+            values: values.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+    pub fn cmd_rpush(key: &str, values: &[&str]) -> Self {
+        Self::RPUSH {
+            key: key.to_string(),
+            values: values.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+    pub fn cmd_lpop(key: &str) -> Self {
+        Self::LPOP {
+            key: key.to_string(),
+        }
+    }
+    pub fn cmd_rpop(key: &str) -> Self {
+        Self::RPOP {
+            key: key.to_string(),
+        }
+    }
+    pub fn cmd_lrange(key: &str, start: i64, stop: i64) -> Self {
+        Self::LRANGE {
+            key: key.to_string(),
+            start,
+            stop,
+        }
+    }
+    pub fn cmd_llen(key: &str) -> Self {
+        Self::LLEN {
+            key: key.to_string(),
+        }
+    }
+    pub fn cmd_mset(pairs: &[(&str, &str)]) -> Self {
+        Self::MSET {
+            pairs: pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+    pub fn cmd_mget(keys: &[&str]) -> Self {
+        Self::MGET {
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+        }
+    }
+    pub fn cmd_pexpireat(key: &str, at: u64) -> Self {
+        Self::PEXPIREAT {
+            key: key.to_string(),
+            at,
+        }
+    }
+    pub fn cmd_select(db: usize) -> Self {
+        Self::SELECT { db }
+    }
+    pub fn cmd_move(key: &str, db: usize) -> Self {
+        Self::MOVE {
+            key: key.to_string(),
+            db,
+        }
+    }
+    pub fn cmd_swapdb(a: usize, b: usize) -> Self {
+        Self::SWAPDB { a, b }
+    }
+    pub fn cmd_dump(key: &str) -> Self {
+        Self::DUMP {
+            key: key.to_string(),
+        }
+    }
+    pub fn cmd_restore(key: &str, ttl: u64, payload: &str, replace: bool) -> Self {
+        Self::RESTORE {
+            key: key.to_string(),
+            ttl,
+            payload: payload.to_string(),
+            replace,
+            absttl: false,
+        }
+    }
+    pub fn cmd_auth(password: &str) -> Self {
+        Self::AUTH {
+            password: password.to_string(),
+        }
+    }
     pub fn cmd_to_list(cmd: String) -> Result<Vec<String>, Error> {
-        let mut cmd_seq = cmd.chars();
-        let n = extract_number('*', &mut cmd_seq).ok_or(Error)?;
+        let mut cmd_seq = cmd.as_bytes().iter();
+        let n = extract_number(b'*', &mut cmd_seq).ok_or(Error)?;
         skip_new_line(&mut cmd_seq);
         let mut result = Vec::new();
         for _ in 0..n {
-            let len = extract_number('$', &mut cmd_seq).ok_or(Error)?;
+            let len = extract_number(b'$', &mut cmd_seq).ok_or(Error)?;
             skip_new_line(&mut cmd_seq);
-            result.push(extract_string(len, &mut cmd_seq));
+            result.push(extract_string(len, &mut cmd_seq).ok_or(Error)?);
             skip_new_line(&mut cmd_seq);
         }
         Ok(result)
     }
 }
 
-impl ToString for Command {
-    fn to_string(&self) -> String {
-        match self {
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
             Self::PING => "*1\r\n$4\r\nPING\r\n".to_string(),
             Self::GET { key } => format!("*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n", key.len(), key),
             Self::DEL { key } => format!("*2\r\n$3\r\nDEL\r\n${}\r\n{}\r\n", key.len(), key),
@@ -98,16 +290,87 @@ impl ToString for Command {
             ),
             Self::FLUSHALL => "*1\r\n$8\r\nFLUSHALL\r\n".to_string(),
             Self::TTL { key } => format!("*2\r\n$3\r\nTTL\r\n${}\r\n{}\r\n", key.len(), key),
+            Self::PTTL { key } => to_resp(&["PTTL", key]),
             Self::INCR { key } => format!("*2\r\n$4\r\nINCR\r\n${}\r\n{}\r\n", key.len(), key),
             Self::DECR { key } => format!("*2\r\n$4\r\nDECR\r\n${}\r\n{}\r\n", key.len(), key),
-        }
+            Self::SETEX { key, sec, value } => to_resp(&["SETEX", key, &sec.to_string(), value]),
+            Self::SETPXAT { key, value, at } => {
+                to_resp(&["SET", key, value, "PXAT", &at.to_string()])
+            }
+            Self::LPUSH { key, values } => to_resp_with("LPUSH", key, values),
+            Self::RPUSH { key, values } => to_resp_with("RPUSH", key, values),
+            Self::LPOP { key } => to_resp(&["LPOP", key]),
+            Self::RPOP { key } => to_resp(&["RPOP", key]),
+            Self::LRANGE { key, start, stop } => {
+                to_resp(&["LRANGE", key, &start.to_string(), &stop.to_string()])
+            }
+            Self::LLEN { key } => to_resp(&["LLEN", key]),
+            Self::MSET { pairs } => {
+                let mut parts = vec!["MSET"];
+                for (k, v) in pairs {
+                    parts.push(k);
+                    parts.push(v);
+                }
+                to_resp(&parts)
+            }
+            Self::MGET { keys } => {
+                let mut parts = vec!["MGET"];
+                parts.extend(keys.iter().map(|k| k.as_str()));
+                to_resp(&parts)
+            }
+            Self::SELECT { db } => to_resp(&["SELECT", &db.to_string()]),
+            Self::MOVE { key, db } => to_resp(&["MOVE", key, &db.to_string()]),
+            Self::SWAPDB { a, b } => to_resp(&["SWAPDB", &a.to_string(), &b.to_string()]),
+            Self::FLUSHDB => to_resp(&["FLUSHDB"]),
+            Self::BGREWRITEAOF => to_resp(&["BGREWRITEAOF"]),
+            Self::PEXPIREAT { key, at } => to_resp(&["PEXPIREAT", key, &at.to_string()]),
+            Self::SAVE => to_resp(&["SAVE"]),
+            Self::BGSAVE => to_resp(&["BGSAVE"]),
+            Self::LASTSAVE => to_resp(&["LASTSAVE"]),
+            Self::DUMP { key } => to_resp(&["DUMP", key]),
+            Self::RESTORE {
+                key,
+                ttl,
+                payload,
+                replace,
+                absttl,
+            } => {
+                let ttl = ttl.to_string();
+                let mut parts = vec!["RESTORE", key, &ttl, payload];
+                if *replace {
+                    parts.push("REPLACE");
+                }
+                if *absttl {
+                    parts.push("ABSTTL");
+                }
+                to_resp(&parts)
+            }
+            Self::AUTH { password } => to_resp(&["AUTH", password]),
+        };
+        f.write_str(&s)
     }
 }
 
+fn to_resp(parts: &[&str]) -> String {
+    parts
+        .iter()
+        .fold(format!("*{}\r\n", parts.len()), |mut acc, p| {
+            acc.push_str(&format!("${}\r\n{}\r\n", p.len(), p));
+            acc
+        })
+}
+
+fn to_resp_with(name: &str, key: &str, values: &[String]) -> String {
+    let mut parts = vec![name, key];
+    parts.extend(values.iter().map(|v| v.as_str()));
+    to_resp(&parts)
+}
+
 // '*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$4\r\value\r\n'
 pub fn parse_command(cmd: String) -> Result<Command, Error> {
     let mut cmd_parts = Command::cmd_to_list(cmd)?.into_iter();
-    match cmd_parts.next().ok_or(Error)?.as_str() {
+    let name = cmd_parts.next().ok_or(Error)?;
+    match name.as_str() {
         "PING" => Ok(Command::PING),
         "GET" => {
             let key = cmd_parts.next().ok_or(Error)?;
@@ -120,7 +383,13 @@ pub fn parse_command(cmd: String) -> Result<Command, Error> {
         "SET" => {
             let key = cmd_parts.next().ok_or(Error)?;
             let value = cmd_parts.next().ok_or(Error)?;
-            Ok(Command::SET { key, value })
+            match cmd_parts.next() {
+                Some(option) if option.eq_ignore_ascii_case("PXAT") => {
+                    let at = cmd_parts.next().ok_or(Error)?.parse().map_err(|_| Error)?;
+                    Ok(Command::SETPXAT { key, value, at })
+                }
+                _ => Ok(Command::SET { key, value }),
+            }
         }
         "KEYS" => {
             let pattern = cmd_parts.next().ok_or(Error)?;
@@ -135,36 +404,188 @@ pub fn parse_command(cmd: String) -> Result<Command, Error> {
         "TTL" => Ok(Command::TTL {
             key: cmd_parts.next().ok_or(Error)?,
         }),
+        "PTTL" => Ok(Command::PTTL {
+            key: cmd_parts.next().ok_or(Error)?,
+        }),
         "INCR" => Ok(Command::INCR {
             key: cmd_parts.next().ok_or(Error)?,
         }),
         "DECR" => Ok(Command::DECR {
             key: cmd_parts.next().ok_or(Error)?,
         }),
+        "SETEX" => {
+            let key = cmd_parts.next().ok_or(Error)?;
+            let sec = cmd_parts.next().ok_or(Error)?.parse().map_err(|_| Error)?;
+            let value = cmd_parts.next().ok_or(Error)?;
+            Ok(Command::SETEX { key, sec, value })
+        }
+        "LPUSH" | "RPUSH" => {
+            let key = cmd_parts.next().ok_or(Error)?;
+            let values = cmd_parts.collect::<Vec<String>>();
+            if values.is_empty() {
+                return Err(Error);
+            }
+            match name.as_str() {
+                "LPUSH" => Ok(Command::LPUSH { key, values }),
+                _ => Ok(Command::RPUSH { key, values }),
+            }
+        }
+        "LPOP" => Ok(Command::LPOP {
+            key: cmd_parts.next().ok_or(Error)?,
+        }),
+        "RPOP" => Ok(Command::RPOP {
+            key: cmd_parts.next().ok_or(Error)?,
+        }),
+        "LRANGE" => {
+            let key = cmd_parts.next().ok_or(Error)?;
+            let start = cmd_parts.next().ok_or(Error)?.parse().map_err(|_| Error)?;
+            let stop = cmd_parts.next().ok_or(Error)?.parse().map_err(|_| Error)?;
+            Ok(Command::LRANGE { key, start, stop })
+        }
+        "LLEN" => Ok(Command::LLEN {
+            key: cmd_parts.next().ok_or(Error)?,
+        }),
+        "MSET" => {
+            let mut pairs = Vec::new();
+            while let Some(key) = cmd_parts.next() {
+                pairs.push((key, cmd_parts.next().ok_or(Error)?));
+            }
+            if pairs.is_empty() {
+                return Err(Error);
+            }
+            Ok(Command::MSET { pairs })
+        }
+        "MGET" => {
+            let keys = cmd_parts.collect::<Vec<String>>();
+            if keys.is_empty() {
+                return Err(Error);
+            }
+            Ok(Command::MGET { keys })
+        }
+        "SELECT" => Ok(Command::SELECT {
+            db: cmd_parts.next().ok_or(Error)?.parse().map_err(|_| Error)?,
+        }),
+        "MOVE" => {
+            let key = cmd_parts.next().ok_or(Error)?;
+            let db = cmd_parts.next().ok_or(Error)?.parse().map_err(|_| Error)?;
+            Ok(Command::MOVE { key, db })
+        }
+        "SWAPDB" => {
+            let a = cmd_parts.next().ok_or(Error)?.parse().map_err(|_| Error)?;
+            let b = cmd_parts.next().ok_or(Error)?.parse().map_err(|_| Error)?;
+            Ok(Command::SWAPDB { a, b })
+        }
+        "FLUSHDB" => Ok(Command::FLUSHDB),
+        "BGREWRITEAOF" => Ok(Command::BGREWRITEAOF),
+        "PEXPIREAT" => {
+            let key = cmd_parts.next().ok_or(Error)?;
+            let at = cmd_parts.next().ok_or(Error)?.parse().map_err(|_| Error)?;
+            Ok(Command::PEXPIREAT { key, at })
+        }
+        "SAVE" => Ok(Command::SAVE),
+        "BGSAVE" => Ok(Command::BGSAVE),
+        "LASTSAVE" => Ok(Command::LASTSAVE),
+        "DUMP" => Ok(Command::DUMP {
+            key: cmd_parts.next().ok_or(Error)?,
+        }),
+        "RESTORE" => {
+            let key = cmd_parts.next().ok_or(Error)?;
+            let ttl = cmd_parts.next().ok_or(Error)?.parse().map_err(|_| Error)?;
+            let payload = cmd_parts.next().ok_or(Error)?;
+            let (mut replace, mut absttl) = (false, false);
+            for option in cmd_parts {
+                match option.to_uppercase().as_str() {
+                    "REPLACE" => replace = true,
+                    "ABSTTL" => absttl = true,
+                    _ => return Err(Error),
+                }
+            }
+            Ok(Command::RESTORE {
+                key,
+                ttl,
+                payload,
+                replace,
+                absttl,
+            })
+        }
+        "AUTH" => Ok(Command::AUTH {
+            password: cmd_parts.next().ok_or(Error)?,
+        }),
         _ => Err(Error),
     }
 }
 
-pub fn extract_number(starter: char, cmd: &mut Chars<'_>) -> Option<usize> {
-    if cmd.next() != Some(starter) {
+// the length of the first command in `data`, None while the rest of it has not arrived,
+// an error for a bulk longer than `max_bulk_len`. Anything not framed as a RESP array is
+// passed on whole for the parser to reject
+pub fn command_len(data: &[u8], max_bulk_len: usize) -> Result<Option<usize>, String> {
+    let mut pos = 0;
+    let count = match frame_header(data, &mut pos, b'*') {
+        None => return Ok(None),
+        Some(None) => return Ok(Some(data.len())),
+        Some(Some(count)) => count,
+    };
+    for _ in 0..count {
+        let len = match frame_header(data, &mut pos, b'$') {
+            None => return Ok(None),
+            Some(None) => return Ok(Some(data.len())),
+            Some(Some(len)) => len,
+        };
+        if len > max_bulk_len {
+            return Err(format!("Protocol error: invalid bulk length {len}"));
+        }
+        // the bulk and its "\r\n"
+        pos = match pos.checked_add(len).and_then(|end| end.checked_add(2)) {
+            Some(end) => end,
+            None => return Err(format!("Protocol error: invalid bulk length {len}")),
+        };
+        if pos > data.len() {
+            return Ok(None);
+        }
+    }
+    Ok(Some(pos))
+}
+
+// Some(None) for a malformed header, None when it is incomplete
+fn frame_header(data: &[u8], pos: &mut usize, starter: u8) -> Option<Option<usize>> {
+    let rest = &data[*pos..];
+    if rest.first()? != &starter {
+        return Some(None);
+    }
+    let end = rest.windows(2).position(|w| w == b"\r\n")?;
+    *pos += end + 2;
+    Some(
+        std::str::from_utf8(&rest[1..end])
+            .ok()
+            .and_then(|n| n.parse().ok()),
+    )
+}
+
+pub fn extract_number(starter: u8, cmd: &mut Iter<'_, u8>) -> Option<usize> {
+    if cmd.next() != Some(&starter) {
         return None;
     }
     let mut v = Vec::new();
     loop {
-        let c = cmd.next().unwrap();
-        if c.is_numeric() {
+        let c = *cmd.next()?;
+        if c.is_ascii_digit() {
             v.push(c);
         } else {
             break;
         }
     }
-    Some(v.iter().collect::<String>().parse().unwrap())
+    std::str::from_utf8(&v).ok()?.parse().ok()
 }
 
-pub fn skip_new_line(cmd: &mut Chars<'_>) -> bool {
-    cmd.next() == Some('\n') || cmd.next() == Some('\n')
+pub fn skip_new_line(cmd: &mut Iter<'_, u8>) -> bool {
+    cmd.next() == Some(&b'\n') || cmd.next() == Some(&b'\n')
 }
 
-pub fn extract_string(n: usize, cmd: &mut Chars<'_>) -> String {
-    cmd.take(n).collect::<String>()
+// bulk lengths count bytes, so values may hold multi-byte characters and "\r\n"
+pub fn extract_string(n: usize, cmd: &mut Iter<'_, u8>) -> Option<String> {
+    let bytes = cmd.take(n).copied().collect::<Vec<u8>>();
+    if bytes.len() != n {
+        return None;
+    }
+    String::from_utf8(bytes).ok()
 }
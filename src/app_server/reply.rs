@@ -0,0 +1,91 @@
+use std::fmt::Display;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn ok() -> Self {
+        Self::Simple("OK".to_string())
+    }
+
+    pub fn error(msg: &str) -> Self {
+        Self::Error(format!("ERR {msg}"))
+    }
+
+    pub fn wrong_type() -> Self {
+        Self::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+    }
+
+    pub fn not_integer() -> Self {
+        Self::error("value is not an integer or out of range")
+    }
+
+    pub fn bulk(value: &str) -> Self {
+        Self::Bulk(Some(value.to_string()))
+    }
+
+    pub fn nil() -> Self {
+        Self::Bulk(None)
+    }
+
+    pub fn strings<I: IntoIterator<Item = String>>(values: I) -> Self {
+        Self::Array(values.into_iter().map(|v| Self::Bulk(Some(v))).collect())
+    }
+
+    // a reply read by a client and the bytes it took, None until all of it has arrived
+    pub fn decode(data: &[u8]) -> Option<(Reply, usize)> {
+        let line_end = data.windows(2).position(|w| w == b"\r\n")?;
+        let line = std::str::from_utf8(&data[1..line_end]).ok()?.to_string();
+        let rest = line_end + 2;
+        match data[0] {
+            b'+' => Some((Self::Simple(line), rest)),
+            b'-' => Some((Self::Error(line), rest)),
+            b':' => Some((Self::Integer(line.parse().ok()?), rest)),
+            b'$' if line == "-1" => Some((Self::nil(), rest)),
+            b'$' => {
+                let end = rest.checked_add(line.parse::<usize>().ok()?)?;
+                let value = String::from_utf8(data.get(rest..end)?.to_vec()).ok()?;
+                let used = end.checked_add(2)?;
+                data.get(end..used)?;
+                Some((Self::Bulk(Some(value)), used))
+            }
+            b'*' => {
+                let mut used = rest;
+                let mut items = Vec::new();
+                for _ in 0..line.parse::<usize>().ok()? {
+                    let (item, len) = Self::decode(&data[used..])?;
+                    items.push(item);
+                    used += len;
+                }
+                Some((Self::Array(items), used))
+            }
+            _ => None,
+        }
+    }
+}
+
+// RESP encoding without the trailing "\r\n", the server appends it when replying
+impl Display for Reply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Simple(s) => write!(f, "+{s}"),
+            Self::Error(e) => write!(f, "-{e}"),
+            Self::Integer(i) => write!(f, ":{i}"),
+            Self::Bulk(Some(v)) => write!(f, "${}\r\n{}", v.len(), v),
+            Self::Bulk(None) => write!(f, "$-1"),
+            Self::Array(items) => {
+                write!(f, "*{}", items.len())?;
+                for item in items {
+                    write!(f, "\r\n{item}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
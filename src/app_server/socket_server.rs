@@ -1,59 +1,306 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
 
-use crate::app_server::parser::parse_command;
-use crate::services::command_handler::{handle_on_memory_and_file, PERSIST};
-use crate::services::persistence_service;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::task::JoinSet;
+
+use crate::app_server::parser::{command_len, parse_command, Command};
+use crate::app_server::tls::Tls;
+use crate::services::session::Session;
+use crate::services::store::Store;
+use crate::Settings;
+
+const DENIED: &str = "-DENIED kvds is running in protected mode because no password is set, so only loopback clients are accepted. Set `requirepass`, or turn `protected_mode` off if the network is trusted\r\n";
 
 pub struct AppServer {
     port: String,
+    store: Arc<Store>,
+    // loaded by `bind` when `tls_port` is set
+    tls: OnceLock<Arc<Tls>>,
+}
+
+pub struct Listeners {
+    pub tcp: Vec<TcpListener>,
+    pub tls: Vec<TcpListener>,
+    #[cfg(unix)]
+    pub unix: Option<UnixListener>,
 }
 
 impl AppServer {
-    pub fn new(port: &str, persist: bool) -> Self {
-        *PERSIST.write().unwrap() = persist;
-        if persist {
+    pub fn new(port: &str, store: Arc<Store>) -> Self {
+        if store.settings().persist {
             println!("----< local persistence enabled >----")
         }
         AppServer {
             port: port.to_string(),
+            store,
+            tls: OnceLock::new(),
         }
     }
 
     pub async fn start(&self) -> tokio::io::Result<()> {
-        persistence_service::load_data().await;
-        let url = format!("127.0.0.1:{}", self.port);
-        let listener = TcpListener::bind(&url).await?;
-        println!("Async server running on {url}");
-        loop {
-            match listener.accept().await {
-                Ok((socket, _)) => {
-                    tokio::spawn(async move {
-                        handle_client(socket).await;
-                    });
-                }
-                Err(e) => eprintln!("Failed to accept client: {}", e),
+        let listeners = self.bind().await?;
+        self.serve(listeners).await
+    }
+
+    // loads the persisted data and binds a listener on each `bind` address, on the
+    // `unixsocket` path, and on `tls_port` of each address; port 0 picks a free port for
+    // each address
+    pub async fn bind(&self) -> tokio::io::Result<Listeners> {
+        self.store
+            .load()
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let port: u16 = self.port.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid port {}", self.port),
+            )
+        })?;
+        let settings = self.store.settings();
+        let addrs = settings
+            .bind
+            .split_whitespace()
+            .map(|addr| {
+                addr.parse::<IpAddr>().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid bind address {addr}"),
+                    )
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut tcp = Vec::new();
+        for &ip in &addrs {
+            let listener = listen(SocketAddr::new(ip, port))?;
+            println!("Async server running on {}", listener.local_addr()?);
+            tcp.push(listener);
+        }
+        let mut tls = Vec::new();
+        if let Some(tls_port) = settings.tls_port {
+            let _ = self.tls.set(Arc::new(Tls::load(settings)?));
+            for &ip in &addrs {
+                let listener = listen(SocketAddr::new(ip, tls_port))?;
+                println!("TLS server running on {}", listener.local_addr()?);
+                tls.push(listener);
             }
         }
+        let listeners = Listeners {
+            tcp,
+            tls,
+            #[cfg(unix)]
+            unix: listen_unix(settings)?,
+        };
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no bind address or unix socket configured",
+            ));
+        }
+        Ok(listeners)
     }
+
+    pub async fn serve(&self, listeners: Listeners) -> tokio::io::Result<()> {
+        let mut accepting = JoinSet::new();
+        for listener in listeners.tcp {
+            accepting.spawn(accept(listener, self.store.clone(), None));
+        }
+        if let Some(tls) = self.tls.get() {
+            for listener in listeners.tls {
+                accepting.spawn(accept(listener, self.store.clone(), Some(tls.clone())));
+            }
+            #[cfg(unix)]
+            accepting.spawn(reload_on_hangup(tls.clone(), self.store.clone()));
+        }
+        #[cfg(unix)]
+        if let Some(listener) = listeners.unix {
+            accepting.spawn(accept_unix(listener, self.store.clone()));
+        }
+        while accepting.join_next().await.is_some() {}
+        Ok(())
+    }
+
+    // reads the TLS certificate, key and CA files again, for new connections; SIGHUP does
+    // the same
+    pub fn reload_tls(&self) -> io::Result<()> {
+        match self.tls.get() {
+            Some(tls) => tls.reload(self.store.settings()),
+            None => Err(io::Error::other("TLS is not enabled")),
+        }
+    }
+}
+
+// IPv6 listeners only take IPv6 clients, so "0.0.0.0 ::" can share a port
+fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
 }
 
-async fn handle_client(mut socket: TcpStream) {
+impl Listeners {
+    fn is_empty(&self) -> bool {
+        #[cfg(unix)]
+        if self.unix.is_some() {
+            return false;
+        }
+        self.tcp.is_empty() && self.tls.is_empty()
+    }
+}
+
+#[cfg(unix)]
+async fn reload_on_hangup(tls: Arc<Tls>, store: Arc<Store>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Ok(mut hangups) = signal(SignalKind::hangup()) else {
+        return;
+    };
+    while hangups.recv().await.is_some() {
+        match tls.reload(store.settings()) {
+            Ok(()) => println!("TLS certificates reloaded"),
+            Err(e) => eprintln!("Failed to reload the TLS certificates: {e}"),
+        }
+    }
+}
+
+// a socket left by an earlier run is replaced, `unixsocketperm` is an octal mode like "770"
+#[cfg(unix)]
+fn listen_unix(settings: &Settings) -> io::Result<Option<UnixListener>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if settings.unixsocket.is_empty() {
+        return Ok(None);
+    }
+    let path = &settings.unixsocket;
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    if !settings.unixsocketperm.is_empty() {
+        let mode = u32::from_str_radix(&settings.unixsocketperm, 8).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid unixsocketperm {}", settings.unixsocketperm),
+            )
+        })?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    println!("Async server running on {path}");
+    Ok(Some(listener))
+}
+
+// clients on the socket are on this host, so protected mode does not apply
+#[cfg(unix)]
+async fn accept_unix(listener: UnixListener, store: Arc<Store>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                tokio::spawn(handle_client(socket, store.clone(), false));
+            }
+            Err(e) => eprintln!("Failed to accept client: {}", e),
+        }
+    }
+}
+
+async fn accept(listener: TcpListener, store: Arc<Store>, tls: Option<Arc<Tls>>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, peer)) => {
+                let refused = refuses(store.settings(), peer.ip());
+                let store = store.clone();
+                let acceptor = tls.as_ref().map(|tls| tls.acceptor());
+                tokio::spawn(async move {
+                    match acceptor {
+                        None => handle_client(socket, store, refused).await,
+                        // the handshake comes first so that even a refusal can be read
+                        Some(acceptor) => match acceptor.accept(socket).await {
+                            Ok(socket) => handle_client(socket, store, refused).await,
+                            Err(e) => eprintln!("TLS handshake failed: {e}"),
+                        },
+                    }
+                });
+            }
+            Err(e) => eprintln!("Failed to accept client: {}", e),
+        }
+    }
+}
+
+// protected mode: without a password only clients on this host may connect
+pub fn refuses(settings: &Settings, peer: IpAddr) -> bool {
+    settings.protected_mode && settings.requirepass.is_empty() && !peer.to_canonical().is_loopback()
+}
+
+async fn handle_client<S>(mut socket: S, store: Arc<Store>, refused: bool)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if refused {
+        let _ = socket.write_all(DENIED.as_bytes()).await;
+        let _ = socket.shutdown().await;
+        return;
+    }
     let mut buf = [0; 1024];
+    // a command may take several reads, and one read may hold several commands
+    let mut pending = Vec::new();
+    let mut session = Session::default();
     loop {
         match socket.read(&mut buf).await {
             Ok(0) => {
                 return;
             }
             Ok(n) => {
-                let received = String::from_utf8_lossy(&buf[..n]).into_owned();
-                let cmd = parse_command(received);
-                let mut resp = match cmd {
-                    Ok(req) => handle_on_memory_and_file(req).await,
-                    Err(e) => format!("-ERR unknown command: {e}"),
-                };
-                resp.push_str("\r\n");
-                socket.write_all(resp.as_bytes()).await.unwrap();
+                let settings = store.settings();
+                pending.extend_from_slice(&buf[..n]);
+                loop {
+                    let len = match command_len(&pending, settings.proto_max_bulk_len) {
+                        Ok(Some(len)) => len,
+                        Ok(None) => break,
+                        Err(e) => {
+                            let _ = socket.write_all(format!("-ERR {e}\r\n").as_bytes()).await;
+                            return;
+                        }
+                    };
+                    // keys and values are strings, other bytes are refused rather than mangled
+                    let received = String::from_utf8(pending.drain(..len).collect())
+                        .map_err(|_| "-ERR keys and values must be valid UTF-8".to_string());
+                    let cmd = received.map(|received| {
+                        parse_command(received)
+                            .map_err(|_| "-ERR unknown command or wrong arguments".to_string())
+                    });
+                    let mut resp = match cmd.and_then(|cmd| cmd) {
+                        Ok(req) if needs_auth(&store, &session, &req) => {
+                            "-NOAUTH Authentication required.".to_string()
+                        }
+                        Ok(req) => store
+                            .handle_on_memory_and_file(&mut session, req)
+                            .await
+                            .to_string(),
+                        Err(e) => e,
+                    };
+                    resp.push_str("\r\n");
+                    if socket.write_all(resp.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+                // the rest of a command is still to come, but not without limit
+                if pending.len() > settings.client_query_buffer_limit {
+                    let _ = socket
+                        .write_all(b"-ERR Protocol error: query buffer limit exceeded\r\n")
+                        .await;
+                    return;
+                }
             }
             Err(e) => {
                 eprintln!("Failed to read from socket: {}", e);
@@ -62,3 +309,9 @@ async fn handle_client(mut socket: TcpStream) {
         }
     }
 }
+
+fn needs_auth(store: &Store, session: &Session, cmd: &Command) -> bool {
+    !store.settings().requirepass.is_empty()
+        && !session.authenticated
+        && !matches!(cmd, Command::AUTH { .. })
+}
@@ -0,0 +1,122 @@
+use crate::Settings;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use serde::Deserialize;
+use std::fmt::Display;
+use std::io;
+use std::sync::{Arc, RwLock};
+use tokio_rustls::TlsAcceptor;
+
+// whether clients must present a certificate signed by `tls_ca_cert_file`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsAuthClients {
+    Yes,
+    // a certificate is verified when a client sends one
+    Optional,
+}
+
+// how a client checks the server, and the certificate it proves itself with
+#[derive(Debug, Clone, Default)]
+pub struct ClientTls {
+    pub ca_cert_file: String,
+    // for servers verifying clients, empty for none
+    pub cert_file: String,
+    pub key_file: String,
+    // the name the server certificate must be for, the host of the url when empty
+    pub server_name: String,
+}
+
+// the acceptor for new connections, swapped when the certificate files are reloaded;
+// connections already open keep the certificate they were accepted with
+pub struct Tls(RwLock<TlsAcceptor>);
+
+impl Tls {
+    pub fn load(settings: &Settings) -> io::Result<Tls> {
+        Ok(Tls(RwLock::new(server_config(settings)?.into())))
+    }
+
+    // on failure the certificate in use is kept
+    pub fn reload(&self, settings: &Settings) -> io::Result<()> {
+        let acceptor = server_config(settings)?.into();
+        *self.0.write().unwrap() = acceptor;
+        Ok(())
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.0.read().unwrap().clone()
+    }
+}
+
+fn server_config(settings: &Settings) -> io::Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+    let builder = if settings.tls_ca_cert_file.is_empty() {
+        builder.with_no_client_auth()
+    } else {
+        let roots = roots(&settings.tls_ca_cert_file)?;
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider());
+        let verifier = match settings.tls_auth_clients {
+            TlsAuthClients::Yes => verifier,
+            TlsAuthClients::Optional => verifier.allow_unauthenticated(),
+        };
+        builder.with_client_cert_verifier(verifier.build().map_err(io::Error::other)?)
+    };
+    let config = builder
+        .with_single_cert(
+            certs(&settings.tls_cert_file)?,
+            key(&settings.tls_key_file)?,
+        )
+        .map_err(|e| invalid(&settings.tls_key_file, e))?;
+    Ok(Arc::new(config))
+}
+
+pub fn client_config(tls: &ClientTls) -> io::Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_root_certificates(roots(&tls.ca_cert_file)?);
+    let config = if tls.cert_file.is_empty() {
+        builder.with_no_client_auth()
+    } else {
+        builder
+            .with_client_auth_cert(certs(&tls.cert_file)?, key(&tls.key_file)?)
+            .map_err(|e| invalid(&tls.key_file, e))?
+    };
+    Ok(Arc::new(config))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+// PEM files, a certificate may be followed by its chain
+fn certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(path, e))?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificate found"));
+    }
+    Ok(certs)
+}
+
+fn key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| invalid(path, e))
+}
+
+fn roots(path: &str) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots.add(cert).map_err(|e| invalid(path, e))?;
+    }
+    Ok(roots)
+}
+
+fn invalid(path: &str, e: impl Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{path}: {e}"))
+}
@@ -0,0 +1,149 @@
+// cargo run --bin kvds-check-aof -- [--fix [--force]] [--key-file <file>] <wal_dir | segment file>
+// reports the first corrupted record of a log file, --fix truncates the file right before it.
+// Valid records after the corruption are only truncated with them given --force.
+// Given the log directory every segment in the manifest is checked, and only the active
+// one may be truncated. An encrypted log needs the server's encryption key file
+use kvds::services::encryption::{Cipher, Keyring};
+use kvds::services::log_format::check_log;
+use kvds::services::wal::{segment_path, Manifest};
+use std::env;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let mut fix = false;
+    let mut force = false;
+    let mut key_file = None;
+    let mut path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fix" => fix = true,
+            "--force" => force = true,
+            "--key-file" => key_file = args.next(),
+            _ => path = Some(arg),
+        }
+    }
+    let Some(path) = path else {
+        eprintln!(
+            "usage: kvds-check-aof [--fix [--force]] [--key-file <file>] <wal_dir | segment file>"
+        );
+        return ExitCode::from(2);
+    };
+    // the cipher is recorded with each sealed record, this one only matters for sealing
+    let keys = match key_file.map(|file| {
+        std::fs::read_to_string(&file)
+            .map_err(|e| format!("cannot read {file}: {e}"))
+            .and_then(|text| Keyring::parse(&text, Cipher::ChaCha20Poly1305))
+            // unencrypted records are counted below rather than reported as corruption
+            .map(|keys| keys.migrating(true))
+    }) {
+        Some(Ok(keys)) => Some(keys),
+        Some(Err(e)) => {
+            eprintln!("{e}");
+            return ExitCode::from(2);
+        }
+        None => None,
+    };
+    let keys = keys.as_ref();
+    if !Path::new(&path).is_dir() {
+        // sealed records are bound to their segment, named after its id; a log from before
+        // segments became the first one
+        let id = Path::new(&path)
+            .file_stem()
+            .and_then(|stem| stem.to_str()?.parse().ok())
+            .unwrap_or(1);
+        return check_file(&path, id, fix, force, keys);
+    }
+    let manifest = match Manifest::read(Path::new(&path)) {
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => {
+            eprintln!("{path} has no manifest");
+            return ExitCode::from(2);
+        }
+        Err(e) => {
+            eprintln!("cannot read the manifest of {path}: {e}");
+            return ExitCode::from(2);
+        }
+    };
+    for &id in &manifest.segments {
+        let segment = segment_path(Path::new(&path), id).display().to_string();
+        let status = check_file(&segment, id, fix && id == manifest.active(), force, keys);
+        if status != ExitCode::SUCCESS {
+            return status;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+fn check_file(path: &str, id: u64, fix: bool, force: bool, keys: Option<&Keyring>) -> ExitCode {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("cannot read {path}: {e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let check = check_log(&data, id, keys);
+    if check.legacy_records > 0 {
+        println!(
+            "{} records use an older format, the server migrates them on its next start",
+            check.legacy_records
+        );
+    }
+    if check.stale_records > 0 {
+        println!(
+            "{} records are not encrypted with the first key, the server encrypts them again on its next start (with --migrate-encryption for unencrypted ones)",
+            check.stale_records
+        );
+    }
+    let Some(corruption) = check.corruption else {
+        println!("{path} is valid: {} records", check.records);
+        if let (Some((_, first)), Some((_, last))) =
+            (check.timestamps.first(), check.timestamps.last())
+        {
+            println!("written from {first} to {last} (unix ms)");
+        }
+        return ExitCode::SUCCESS;
+    };
+    println!(
+        "{path} is corrupted at offset {}: {}",
+        corruption.offset, corruption.reason
+    );
+    println!(
+        "{} valid records, {} of {} bytes would remain",
+        check.records,
+        check.valid_len,
+        data.len()
+    );
+    if !corruption.at_tail {
+        println!("valid records follow the corruption and would be lost by truncating");
+    }
+    if !fix {
+        return ExitCode::FAILURE;
+    }
+    if !corruption.at_tail && !force {
+        eprintln!("refusing to truncate valid records, pass --force to drop them anyway");
+        return ExitCode::FAILURE;
+    }
+    if check.valid_len == 0 && !data.is_empty() {
+        eprintln!("refusing to truncate the whole file");
+        return ExitCode::FAILURE;
+    }
+    let truncated = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(check.valid_len as u64));
+    match truncated {
+        Ok(()) => {
+            println!("truncated {path} to {} bytes", check.valid_len);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("cannot truncate {path}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -1,73 +1,154 @@
 use crate::app_server::parser::{extract_number, extract_string, skip_new_line, Command};
+use crate::app_server::reply::Reply;
+use crate::app_server::tls::{client_config, ClientTls};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConnection, StreamOwned};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 use std::{
     io::{Read, Write},
     net::TcpStream,
+    sync::Mutex,
 };
 
+#[derive(Debug)]
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    // rustls reads and writes through a mutable connection
+    Tls(Box<Mutex<StreamOwned<ClientConnection, TcpStream>>>),
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).read(buf),
+            Stream::Tls(stream) => stream.lock().unwrap().read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write(buf),
+            Stream::Tls(stream) => stream.lock().unwrap().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).flush(),
+            Stream::Tls(stream) => stream.lock().unwrap().flush(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Connector {
-    url: String,
-    stream: Option<TcpStream>,
+    stream: Stream,
 }
 
 impl Connector {
     pub fn with_port(port: &str) -> Self {
-        Connector {
-            url: format!("127.0.0.1:{}", port),
-            stream: None,
-        }
-        .connect()
+        Connector::with_url(&format!("127.0.0.1:{}", port))
     }
 
     pub fn with_url(url: &str) -> Self {
-        Connector {
-            url: url.to_string(),
-            stream: None,
-        }
-        .connect()
+        let stream = Stream::Tcp(TcpStream::connect(url).unwrap());
+        Connector::connect(stream, None)
     }
 
-    fn connect(mut self: Self) -> Self {
-        let mut stream = TcpStream::connect(self.url.clone()).unwrap();
-        stream
-            .write_all(Command::PING.to_string().as_bytes())
-            .unwrap();
-        let mut buffer: [u8; 512] = [0; 512];
-        let n = stream.read(&mut buffer).unwrap();
-        let res = String::from_utf8_lossy(&buffer[..n]).to_string();
-        assert_eq!(res, String::from("+PONG\r\n"));
-        self.stream = Some(stream);
-        self
+    // for a server with `requirepass` set
+    pub fn with_password(url: &str, password: &str) -> Self {
+        let stream = Stream::Tcp(TcpStream::connect(url).unwrap());
+        Connector::connect(stream, Some(password))
+    }
+
+    // the server's `unixsocket`, no password is needed for a client on the same host unless
+    // `requirepass` is set
+    #[cfg(unix)]
+    pub fn with_unix_socket(path: &str, password: Option<&str>) -> Self {
+        let stream = Stream::Unix(UnixStream::connect(path).unwrap());
+        Connector::connect(stream, password)
+    }
+
+    // the server's `tls_port`
+    pub fn with_tls(url: &str, tls: &ClientTls, password: Option<&str>) -> Self {
+        let config = client_config(tls).unwrap();
+        let name = match tls.server_name.as_str() {
+            "" => url.rsplit_once(':').map_or(url, |(host, _)| host),
+            name => name,
+        };
+        let name = ServerName::try_from(name.trim_matches(['[', ']']).to_string()).unwrap();
+        let connection = ClientConnection::new(config, name).unwrap();
+        let socket = TcpStream::connect(url).unwrap();
+        let stream = StreamOwned::new(connection, socket);
+        Connector::connect(Stream::Tls(Box::new(Mutex::new(stream))), password)
+    }
+
+    fn connect(stream: Stream, password: Option<&str>) -> Self {
+        let connector = Connector { stream };
+        if let Some(password) = password {
+            assert_eq!(connector.call(Command::cmd_auth(password)), Reply::ok());
+        }
+        assert_eq!(
+            connector.call(Command::PING),
+            Reply::Simple("PONG".to_string())
+        );
+        connector
     }
 
-    pub fn get(self: &Self, key: &str) -> Option<String> {
+    pub fn get(&self, key: &str) -> Option<String> {
         match self.call_server(Command::cmd_get(key)).as_str() {
             "$-1\r\n" => None,
             value => {
-                let mut chars = value.chars();
-                let len = extract_number('$', &mut chars)?;
-                skip_new_line(&mut chars);
-                Some(extract_string(len, &mut chars))
+                let mut bytes = value.as_bytes().iter();
+                let len = extract_number(b'$', &mut bytes)?;
+                skip_new_line(&mut bytes);
+                extract_string(len, &mut bytes)
             }
         }
     }
 
-    pub fn insert(self: &Self, key: &str, value: &str) {
+    pub fn insert(&self, key: &str, value: &str) {
         self.call_server(Command::cmd_set(key, value));
     }
 
-    pub fn keys(self: &Self, pt: &str) -> Vec<String> {
+    pub fn keys(&self, pt: &str) -> Vec<String> {
         Command::cmd_to_list(self.call_server(Command::cmd_keys(pt))).unwrap()
     }
 
-    pub fn call_server(self: &Self, cmd: Command) -> String {
-        self.stream
-            .as_ref()
-            .unwrap()
-            .write_all(cmd.to_string().as_bytes())
-            .unwrap();
+    pub fn call_server(&self, cmd: Command) -> String {
+        let mut stream = &self.stream;
+        stream.write_all(cmd.to_string().as_bytes()).unwrap();
         let mut buffer: [u8; 4096] = [0; 4096];
-        let n = self.stream.as_ref().unwrap().read(&mut buffer).unwrap();
+        let n = stream.read(&mut buffer).unwrap();
         String::from_utf8_lossy(&buffer[..n]).to_string()
     }
+
+    // reads until the whole reply has arrived, however long it is
+    pub fn call(&self, cmd: Command) -> Reply {
+        let mut stream = &self.stream;
+        stream.write_all(cmd.to_string().as_bytes()).unwrap();
+        let mut data = Vec::new();
+        let mut buffer: [u8; 4096] = [0; 4096];
+        loop {
+            if let Some((reply, _)) = Reply::decode(&data) {
+                return reply;
+            }
+            let n = stream.read(&mut buffer).unwrap();
+            if n == 0 {
+                return Reply::error("connection closed");
+            }
+            data.extend_from_slice(&buffer[..n]);
+        }
+    }
 }
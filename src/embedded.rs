@@ -0,0 +1,242 @@
+use crate::app_server::parser::Command;
+use crate::app_server::reply::Reply;
+use crate::services::persistence_service::read_log;
+use crate::services::session::Session;
+use crate::services::store::Store;
+use crate::Settings;
+
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// in-process access to the engine, replies skip the socket and RESP encoding
+// but go through the same persistence and expiry path as the server
+pub struct Db {
+    store: Arc<Store>,
+    // like a connection, each handle has its own selected database. Held for a whole
+    // command so concurrent calls on one handle see each other's SELECT and AUTH
+    session: tokio::sync::Mutex<Session>,
+    // the session as of the last finished command, for cloning without waiting
+    settled: Mutex<Session>,
+}
+
+// a clone starts on the database selected by this handle's last finished command
+impl Clone for Db {
+    fn clone(&self) -> Self {
+        let session = self.settled.lock().unwrap().clone();
+        Db {
+            store: self.store.clone(),
+            session: tokio::sync::Mutex::new(session.clone()),
+            settled: Mutex::new(session),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbError(pub String);
+
+impl Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl Db {
+    pub async fn open(settings: Settings) -> Self {
+        let db = Db::with_store(Store::new(settings));
+        db.store
+            .load()
+            .await
+            .expect("error loading persisted data!");
+        db
+    }
+
+    // the persisted data as `open` would load it, without changing any file: the log is
+    // replayed as it is or else the snapshot read. Writes only change the memory
+    pub async fn open_read_only(mut settings: Settings) -> Result<Self, DbError> {
+        let persist = std::mem::replace(&mut settings.persist, false);
+        settings.save.clear();
+        let db = Db::with_store(Store::new(settings));
+        let settings = db.store.settings();
+        let loaded = if persist {
+            read_log(
+                &db.store,
+                Path::new(&settings.wal_dir),
+                Path::new(&settings.db_file),
+            )
+            .await
+        } else {
+            match Path::new(&settings.snapshot_file) {
+                snapshot if snapshot.exists() => db.store.load_snapshot(snapshot),
+                _ => Ok(()),
+            }
+        };
+        loaded.map_err(DbError)?;
+        Ok(db)
+    }
+
+    pub fn with_store(store: Arc<Store>) -> Self {
+        Db {
+            store,
+            session: tokio::sync::Mutex::new(Session::default()),
+            settled: Mutex::new(Session::default()),
+        }
+    }
+
+    pub fn store(&self) -> &Arc<Store> {
+        &self.store
+    }
+
+    pub async fn execute(&self, cmd: Command) -> Reply {
+        let mut session = self.session.lock().await;
+        let reply = self
+            .store
+            .handle_on_memory_and_file(&mut session, cmd)
+            .await;
+        *self.settled.lock().unwrap() = session.clone();
+        reply
+    }
+
+    async fn call(&self, cmd: Command) -> Result<Reply, DbError> {
+        match self.execute(cmd).await {
+            Reply::Error(e) => Err(DbError(e)),
+            reply => Ok(reply),
+        }
+    }
+
+    async fn integer(&self, cmd: Command) -> Result<i64, DbError> {
+        match self.call(cmd).await? {
+            Reply::Integer(i) => Ok(i),
+            other => Err(DbError(format!("unexpected reply: {other}"))),
+        }
+    }
+
+    async fn bulk(&self, cmd: Command) -> Result<Option<String>, DbError> {
+        match self.call(cmd).await? {
+            Reply::Bulk(value) => Ok(value),
+            other => Err(DbError(format!("unexpected reply: {other}"))),
+        }
+    }
+
+    async fn strings(&self, cmd: Command) -> Result<Vec<String>, DbError> {
+        match self.call(cmd).await? {
+            Reply::Array(items) => Ok(items
+                .into_iter()
+                .filter_map(|item| match item {
+                    Reply::Bulk(value) => value,
+                    _ => None,
+                })
+                .collect()),
+            other => Err(DbError(format!("unexpected reply: {other}"))),
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>, DbError> {
+        self.bulk(Command::cmd_get(key)).await
+    }
+
+    pub async fn set(&self, key: &str, value: &str) -> Result<(), DbError> {
+        self.call(Command::cmd_set(key, value)).await.map(|_| ())
+    }
+
+    pub async fn set_ex(&self, key: &str, value: &str, sec: u64) -> Result<(), DbError> {
+        self.call(Command::cmd_setex(key, sec, value))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn del(&self, key: &str) -> Result<bool, DbError> {
+        Ok(self.integer(Command::cmd_del(key)).await? == 1)
+    }
+
+    pub async fn expire(&self, key: &str, sec: u64) -> Result<bool, DbError> {
+        Ok(self.integer(Command::cmd_expire(key, sec)).await? == 1)
+    }
+
+    // -2 when the key does not exist, -1 when it has no expiration
+    pub async fn ttl(&self, key: &str) -> Result<i64, DbError> {
+        self.integer(Command::cmd_ttl(key)).await
+    }
+
+    // like `ttl` in milliseconds
+    pub async fn pttl(&self, key: &str) -> Result<i64, DbError> {
+        self.integer(Command::cmd_pttl(key)).await
+    }
+
+    pub async fn keys(&self, pattern: &str) -> Result<Vec<String>, DbError> {
+        self.strings(Command::cmd_keys(pattern)).await
+    }
+
+    pub async fn incr(&self, key: &str) -> Result<i64, DbError> {
+        self.integer(Command::cmd_incr(key)).await
+    }
+
+    pub async fn decr(&self, key: &str) -> Result<i64, DbError> {
+        self.integer(Command::cmd_decr(key)).await
+    }
+
+    pub async fn lpush(&self, key: &str, values: &[&str]) -> Result<usize, DbError> {
+        Ok(self.integer(Command::cmd_lpush(key, values)).await? as usize)
+    }
+
+    pub async fn rpush(&self, key: &str, values: &[&str]) -> Result<usize, DbError> {
+        Ok(self.integer(Command::cmd_rpush(key, values)).await? as usize)
+    }
+
+    pub async fn lpop(&self, key: &str) -> Result<Option<String>, DbError> {
+        self.bulk(Command::cmd_lpop(key)).await
+    }
+
+    pub async fn rpop(&self, key: &str) -> Result<Option<String>, DbError> {
+        self.bulk(Command::cmd_rpop(key)).await
+    }
+
+    pub async fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<String>, DbError> {
+        self.strings(Command::cmd_lrange(key, start, stop)).await
+    }
+
+    pub async fn llen(&self, key: &str) -> Result<usize, DbError> {
+        Ok(self.integer(Command::cmd_llen(key)).await? as usize)
+    }
+
+    pub async fn flush_all(&self) -> Result<(), DbError> {
+        self.call(Command::FLUSHALL).await.map(|_| ())
+    }
+
+    pub async fn flush_db(&self) -> Result<(), DbError> {
+        self.call(Command::FLUSHDB).await.map(|_| ())
+    }
+
+    pub async fn select(&self, db: usize) -> Result<(), DbError> {
+        self.call(Command::cmd_select(db)).await.map(|_| ())
+    }
+
+    // true when the key was moved, false when it is missing here or already exists in `db`
+    pub async fn move_key(&self, key: &str, db: usize) -> Result<bool, DbError> {
+        Ok(self.integer(Command::cmd_move(key, db)).await? == 1)
+    }
+
+    pub async fn swap_db(&self, a: usize, b: usize) -> Result<(), DbError> {
+        self.call(Command::cmd_swapdb(a, b)).await.map(|_| ())
+    }
+
+    // an opaque serialization of the value, None when the key does not exist
+    pub async fn dump(&self, key: &str) -> Result<Option<String>, DbError> {
+        self.bulk(Command::cmd_dump(key)).await
+    }
+
+    // `ttl_ms` 0 for no expiration; fails when the key exists unless `replace`
+    pub async fn restore(
+        &self,
+        key: &str,
+        ttl_ms: u64,
+        payload: &str,
+        replace: bool,
+    ) -> Result<(), DbError> {
+        self.call(Command::cmd_restore(key, ttl_ms, payload, replace))
+            .await
+            .map(|_| ())
+    }
+}
@@ -1,15 +1,146 @@
 pub mod app_server;
 pub mod connector;
+pub mod embedded;
 pub mod services;
 
+use app_server::tls::TlsAuthClients;
 use config::{Config, ConfigError, Environment, File};
-use once_cell::sync::Lazy;
 use serde::Deserialize;
+use services::compression::Codec;
+use services::encryption::Cipher;
+use services::eviction::MaxmemoryPolicy;
+use services::persistence_service::AppendFsync;
+use services::storage::StorageKind;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
 pub struct Settings {
-    db_file: String,
+    // a single-file log from before segments, moved into `wal_dir` on first start
+    pub db_file: String,
+    pub persist: bool,
+    // directory of the numbered log segments and their manifest
+    pub wal_dir: String,
+    // a segment is closed once it reaches this many bytes or is this old, 0 disables either
+    pub wal_segment_size: u64,
+    pub wal_segment_seconds: u64,
+    // closed segments older than the latest rewrite kept around, e.g. for backups
+    pub wal_keep_segments: usize,
+    // rebuild the state as of "<unix ms>" or "<segment>:<offset>" at startup, empty for none,
+    // only set by `--recover-to`
+    #[serde(skip)]
+    pub recover_to: String,
+    // a Redis RDB file whose strings and lists are imported at startup, empty for none, only
+    // set by `--import-rdb` or the `import-rdb` subcommand
+    #[serde(skip)]
+    pub import_rdb: String,
+    pub snapshot_file: String,
+    // save points as "<seconds> <changes>" pairs, e.g. "900 1 300 10", empty disables them
+    pub save: String,
+    pub appendfsync: AppendFsync,
+    // rewrites start the log with a binary snapshot instead of commands
+    pub aof_use_rdb_preamble: bool,
+    // a torn last log record is cut off at startup instead of refusing to start
+    pub aof_load_truncated: bool,
+    // hex keys to encrypt the log and snapshots with, read from this file or else from the
+    // variable this names; the first key encrypts, the others only decrypt. Empty for none
+    pub encryption_key_file: String,
+    pub encryption_key_env: String,
+    pub encryption_cipher: Cipher,
+    // accept unencrypted data once and encrypt it, only set by `--migrate-encryption`
+    #[serde(skip)]
+    pub migrate_encryption: bool,
+    // codec of log records and snapshots, and of string values longer than
+    // `compress_values_above` bytes kept in memory (0 keeps them all as they are)
+    pub compression: Codec,
+    pub compress_values_above: usize,
+    // the log is rewritten once it grew by this percentage since the last rewrite, 0 disables it
+    pub auto_aof_rewrite_percentage: u64,
+    pub auto_aof_rewrite_min_size: u64,
+    pub shards: usize,
+    pub databases: usize,
+    pub storage: StorageKind,
+    // scratch space of the disk engine, rebuilt from the log or snapshot at startup
+    pub storage_dir: String,
+    // keys of a database the disk engine keeps in memory
+    pub hot_keys: usize,
+    // bytes of entries buffered in memory before the disk engine writes a table
+    pub lsm_memtable_size: usize,
+    // bytes, 0 disables the limit
+    pub maxmemory: usize,
+    pub maxmemory_policy: MaxmemoryPolicy,
+    pub maxmemory_samples: usize,
+    // bulk strings longer than this are a protocol error
+    pub proto_max_bulk_len: usize,
+    // bytes a client may send before its command is complete
+    pub client_query_buffer_limit: usize,
+    // addresses the server listens on, separated by spaces, e.g. "0.0.0.0 ::"
+    pub bind: String,
+    // without `requirepass` only loopback clients are accepted
+    pub protected_mode: bool,
+    // clients must AUTH with this before any other command, empty for none
+    pub requirepass: String,
+    // path of a unix socket to listen on as well, empty for none, and its mode in octal
+    pub unixsocket: String,
+    pub unixsocketperm: String,
+    // TLS is served on this port of every `bind` address as well, none by default
+    pub tls_port: Option<u16>,
+    // PEM files; SIGHUP reloads them without a restart
+    pub tls_cert_file: String,
+    pub tls_key_file: String,
+    // clients are verified against this CA, empty to not ask for client certificates
+    pub tls_ca_cert_file: String,
+    pub tls_auth_clients: TlsAuthClients,
 }
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            db_file: "log.db".to_string(),
+            persist: false,
+            wal_dir: "wal".to_string(),
+            wal_segment_size: 64 * 1024 * 1024,
+            wal_segment_seconds: 0,
+            wal_keep_segments: 0,
+            recover_to: String::new(),
+            import_rdb: String::new(),
+            snapshot_file: "dump.db".to_string(),
+            save: String::new(),
+            appendfsync: AppendFsync::Everysec,
+            aof_use_rdb_preamble: true,
+            aof_load_truncated: true,
+            encryption_key_file: String::new(),
+            encryption_key_env: String::new(),
+            encryption_cipher: Cipher::ChaCha20Poly1305,
+            migrate_encryption: false,
+            compression: Codec::None,
+            compress_values_above: 0,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            shards: 64,
+            databases: 16,
+            storage: StorageKind::Memory,
+            storage_dir: "data".to_string(),
+            hot_keys: 100_000,
+            lsm_memtable_size: 4 * 1024 * 1024,
+            maxmemory: 0,
+            maxmemory_policy: MaxmemoryPolicy::Noeviction,
+            maxmemory_samples: 5,
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            client_query_buffer_limit: 1024 * 1024 * 1024,
+            bind: "127.0.0.1".to_string(),
+            protected_mode: true,
+            requirepass: String::new(),
+            unixsocket: String::new(),
+            unixsocketperm: String::new(),
+            tls_port: None,
+            tls_cert_file: String::new(),
+            tls_key_file: String::new(),
+            tls_ca_cert_file: String::new(),
+            tls_auth_clients: TlsAuthClients::Yes,
+        }
+    }
+}
+
 // APP_ENV=dev cargo run
 // APP_ENV=test cargo test
 impl Settings {
@@ -22,5 +153,3 @@ impl Settings {
         builder.build()?.try_deserialize()
     }
 }
-
-static SETTING: Lazy<Settings> = Lazy::new(|| Settings::new().unwrap());
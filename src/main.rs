@@ -1,18 +1,143 @@
 use kvds::app_server::socket_server::AppServer;
+use kvds::connector::connector::Connector;
+use kvds::embedded::Db;
+use kvds::services::ndjson::{self, ImportOptions, TtlMode};
+use kvds::services::store::Store;
+use kvds::Settings;
 use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
 
 #[tokio::main]
 async fn main() -> tokio::io::Result<()> {
+    let mut settings = Settings::new().expect("error reading settings!");
     let mut port = String::from("6379");
-    let mut args = env::args().into_iter();
-    let mut persist = false;
+    let mut args = env::args().peekable();
+    args.next();
+    // `kvds export|import [--server <host:port> [--password <pw>]] ... [<file>]` moves keys
+    // as NDJSON
+    if let Some(tool) = args.next_if(|arg| arg == "export" || arg == "import") {
+        return run_ndjson(&tool, settings, args.collect()).await;
+    }
+    // `kvds import-rdb <file>` imports into the persisted data and exits
+    let import_only = args.next_if_eq("import-rdb").is_some();
+    if import_only {
+        settings.import_rdb = args.next().expect("missing RDB file!");
+    }
     while let Some(arg) = args.next() {
         if arg == "-p" {
             port = args.next().expect("wrong port number!");
         }
         if arg == "PERSIST" {
-            persist = true;
+            settings.persist = true;
+        }
+        if arg == "--recover-to" {
+            settings.recover_to = args.next().expect("missing recovery target!");
+        }
+        if arg == "--import-rdb" {
+            settings.import_rdb = args.next().expect("missing RDB file!");
+        }
+        if arg == "--migrate-encryption" {
+            settings.migrate_encryption = true;
         }
+        if arg == "--bind" {
+            settings.bind = args.next().expect("missing bind addresses!");
+        }
+        if arg == "--unixsocket" {
+            settings.unixsocket = args.next().expect("missing socket path!");
+        }
+        if arg == "--tls-port" {
+            let tls_port = args.next().and_then(|p| p.parse().ok());
+            settings.tls_port = Some(tls_port.expect("wrong TLS port number!"));
+        }
+    }
+    if import_only {
+        let store = Store::new(settings);
+        return store
+            .load()
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+    }
+    AppServer::new(port.as_str(), Store::new(settings))
+        .start()
+        .await
+}
+
+// without --server the data files are read or changed directly, so the server must be stopped
+async fn run_ndjson(tool: &str, settings: Settings, args: Vec<String>) -> io::Result<()> {
+    let mut server = None;
+    let mut password = None;
+    let mut path = None;
+    let mut options = ImportOptions::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => server = Some(args.next().expect("missing server address!")),
+            "--password" => password = Some(args.next().expect("missing password!")),
+            "--replace" => options.replace = true,
+            "--ttl" => {
+                options.ttl = match args.next().as_deref() {
+                    Some("keep") => TtlMode::Keep,
+                    Some("drop") => TtlMode::Drop,
+                    _ => panic!("--ttl takes keep or drop!"),
+                }
+            }
+            _ => path = Some(arg),
+        }
+    }
+    let result = match server {
+        Some(url) => {
+            let connector = match password {
+                Some(password) => Connector::with_password(&url, &password),
+                None => Connector::with_url(&url),
+            };
+            run_tool(tool, &connector, path, options).await
+        }
+        None if tool == "export" => match Db::open_read_only(settings).await {
+            Ok(db) => run_tool(tool, &db, path, options).await,
+            Err(e) => Err(e.0),
+        },
+        None => {
+            let persist = settings.persist;
+            let db = Db::open(settings).await;
+            let result = run_tool(tool, &db, path, options).await;
+            // the imported keys are on disk before exiting: like `import-rdb` in a fresh
+            // snapshot of the log, or else in the snapshot file
+            match result {
+                Ok(()) if persist => db.store().rewrite_log().await,
+                Ok(()) => db.store().save().await,
+                result => result,
+            }
+        }
+    };
+    result.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// reads or writes stdin/stdout when no file is given
+async fn run_tool<C: ndjson::Client>(
+    tool: &str,
+    client: &C,
+    path: Option<String>,
+    options: ImportOptions,
+) -> Result<(), String> {
+    if tool == "export" {
+        let out: Box<dyn Write> = match path {
+            Some(path) => Box::new(File::create(&path).map_err(|e| format!("{path}: {e}"))?),
+            None => Box::new(io::stdout().lock()),
+        };
+        let mut out = BufWriter::new(out);
+        let count = ndjson::export(client, &mut out).await?;
+        out.flush().map_err(|e| e.to_string())?;
+        eprintln!("exported {count} keys");
+    } else {
+        let stats = match path {
+            Some(path) => {
+                let file = File::open(&path).map_err(|e| format!("{path}: {e}"))?;
+                ndjson::import(client, BufReader::new(file), options).await?
+            }
+            None => ndjson::import(client, io::stdin().lock(), options).await?,
+        };
+        eprintln!("{stats}");
     }
-    AppServer::new(port.as_str(), persist).start().await
+    Ok(())
 }
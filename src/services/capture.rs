@@ -0,0 +1,246 @@
+use crate::app_server::parser::Command;
+use crate::services::keyspace::Keyspace;
+use crate::services::store::{Store, StoredData, Value};
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+
+// where a captured keyspace is written to, a snapshot file or the commands of a log rewrite
+pub(crate) trait SnapshotSink {
+    fn select_db(&mut self, db: usize) -> io::Result<()>;
+    // `expires_at` in unix milliseconds
+    fn entry(&mut self, key: &str, value: &Value, expires_at: Option<u64>) -> io::Result<()>;
+}
+
+// an entry as it was when the capture started
+struct Kept {
+    value: Value,
+    expires_at: Option<u64>,
+}
+
+#[derive(Default)]
+struct CapturedShard {
+    written: AtomicBool,
+    // keys changed since the capture started, None for those that did not exist then
+    kept: Mutex<HashMap<String, Option<Kept>>>,
+}
+
+// the keyspace as of the moment a background save or rewrite started, written out one shard
+// at a time while clients keep writing: a change to a shard not written out yet first keeps
+// the old entries of its keys here, so only what changes meanwhile is copied
+pub(crate) struct Capture {
+    // the keyspaces by database index at the start, SWAPDB moves them around meanwhile
+    dbs: Vec<Arc<Keyspace>>,
+    now: Instant,
+    unix_now: u64,
+    shards: Vec<Vec<CapturedShard>>,
+    finished: AtomicBool,
+    done: tokio::sync::Notify,
+}
+
+impl Capture {
+    pub(crate) fn new(dbs: Vec<Arc<Keyspace>>, now: Instant, unix_now: u64) -> Self {
+        let shards = dbs
+            .iter()
+            .map(|db| {
+                db.shards()
+                    .iter()
+                    .map(|_| CapturedShard::default())
+                    .collect()
+            })
+            .collect();
+        Capture {
+            dbs,
+            now,
+            unix_now,
+            shards,
+            finished: AtomicBool::new(false),
+            done: tokio::sync::Notify::new(),
+        }
+    }
+
+    // the unix deadline of an entry, None when it has none or already expired at the start
+    fn expires_at(&self, stored: &StoredData) -> Option<Option<u64>> {
+        match stored.ttl {
+            Some(ttl) if ttl <= self.now => None,
+            Some(ttl) => Some(Some(
+                self.unix_now + ttl.duration_since(self.now).as_millis() as u64,
+            )),
+            None => Some(None),
+        }
+    }
+
+    // called before `key` of `db` changes
+    pub(crate) fn keep(&self, db: &Arc<Keyspace>, key: &str) -> io::Result<()> {
+        let Some(index) = self.dbs.iter().position(|d| Arc::ptr_eq(d, db)) else {
+            return Ok(());
+        };
+        let shard = db.index(key);
+        let captured = &self.shards[index][shard];
+        let mut map = db.shards()[shard].write().unwrap();
+        if captured.written.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        map.load(key)?;
+        let old = map.get(key).and_then(|stored| {
+            self.expires_at(stored).map(|expires_at| Kept {
+                value: stored.value.clone(),
+                expires_at,
+            })
+        });
+        // a change to the key that kept it first came after this read
+        captured
+            .kept
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_insert(old);
+        Ok(())
+    }
+
+    // writes every database, skipping empty ones, holding one shard at a time
+    pub(crate) fn write(&self, sink: &mut dyn SnapshotSink) -> io::Result<()> {
+        for (index, db) in self.dbs.iter().enumerate() {
+            let mut selected = false;
+            let mut select = |sink: &mut dyn SnapshotSink| {
+                if !std::mem::replace(&mut selected, true) {
+                    return sink.select_db(index);
+                }
+                Ok(())
+            };
+            for (shard, captured) in db.shards().iter().zip(&self.shards[index]) {
+                // keys are only kept before they change, which waits for this lock
+                let shard = shard.read().unwrap();
+                captured.written.store(true, Ordering::SeqCst);
+                let kept = std::mem::take(&mut *captured.kept.lock().unwrap());
+                let mut result = Ok(());
+                shard.scan(&mut |key, stored| {
+                    if result.is_err() || kept.contains_key(key) {
+                        return;
+                    }
+                    if let Some(expires_at) = self.expires_at(stored) {
+                        result =
+                            select(sink).and_then(|_| sink.entry(key, &stored.value, expires_at));
+                    }
+                })?;
+                result?;
+                for (key, kept) in kept
+                    .iter()
+                    .filter_map(|(key, kept)| Some((key, kept.as_ref()?)))
+                {
+                    select(sink)?;
+                    sink.entry(key, &kept.value, kept.expires_at)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(&self) {
+        self.finished.store(true, Ordering::SeqCst);
+        self.done.notify_waiters();
+    }
+
+    pub(crate) async fn finished(&self) {
+        let done = self.done.notified();
+        if !self.finished.load(Ordering::SeqCst) {
+            done.await;
+        }
+    }
+}
+
+// a capture being written out, it stops being kept up when dropped, written or not
+pub(crate) struct Captured {
+    store: Weak<Store>,
+    capture: Arc<Capture>,
+}
+
+impl Captured {
+    pub(crate) fn write(&self, sink: &mut dyn SnapshotSink) -> io::Result<()> {
+        self.capture.write(sink)
+    }
+}
+
+impl Drop for Captured {
+    fn drop(&mut self) {
+        if let Some(store) = self.store.upgrade() {
+            let mut captures = store.captures.lock().unwrap();
+            captures.retain(|capture| !Arc::ptr_eq(capture, &self.capture));
+        }
+        self.capture.finish();
+    }
+}
+
+impl Store {
+    // the caller holds the barrier exclusively so no write is half applied
+    pub(crate) fn begin_capture(&self) -> Captured {
+        let capture = Arc::new(Capture::new(
+            self.all_dbs(),
+            self.clock.now(),
+            self.unix_ms(),
+        ));
+        self.captures.lock().unwrap().push(capture.clone());
+        Captured {
+            store: self.me.clone(),
+            capture,
+        }
+    }
+
+    // loads the keys `cmd` is about to change, so one on disk that cannot be read fails the
+    // command before it is logged, and keeps them for the captures being written out
+    pub(crate) async fn prepare_write(&self, db: usize, cmd: &Command) -> io::Result<()> {
+        let captures = self.captures.lock().unwrap().clone();
+        let Some(keyspace) = self.db(db) else {
+            return Ok(());
+        };
+        let mut keys = Vec::new();
+        match cmd {
+            // whole databases are not copied, these wait for the captures instead
+            Command::FLUSHDB | Command::FLUSHALL => {
+                for capture in &captures {
+                    capture.finished().await;
+                }
+            }
+            Command::MSET { pairs } => {
+                keys.extend(pairs.iter().map(|(key, _)| (keyspace.clone(), key)));
+            }
+            Command::MOVE { key, db } => {
+                keys.push((keyspace.clone(), key));
+                keys.extend(self.db(*db).map(|target| (target, key)));
+            }
+            Command::SET { key, .. }
+            | Command::SETEX { key, .. }
+            | Command::SETPXAT { key, .. }
+            | Command::DEL { key }
+            | Command::EXPIRE { key, .. }
+            | Command::PEXPIREAT { key, .. }
+            | Command::INCR { key }
+            | Command::DECR { key }
+            | Command::LPUSH { key, .. }
+            | Command::RPUSH { key, .. }
+            | Command::LPOP { key }
+            | Command::RPOP { key }
+            | Command::RESTORE { key, .. } => keys.push((keyspace.clone(), key)),
+            _ => {}
+        }
+        for (keyspace, key) in keys {
+            keyspace.load(key)?;
+            for capture in &captures {
+                capture.keep(&keyspace, key)?;
+            }
+        }
+        Ok(())
+    }
+
+    // before eviction removes a key
+    pub(crate) fn keep_evicted(&self, keyspace: &Arc<Keyspace>, key: &str) -> io::Result<()> {
+        let captures = self.captures.lock().unwrap().clone();
+        for capture in &captures {
+            capture.keep(keyspace, key)?;
+        }
+        Ok(())
+    }
+}
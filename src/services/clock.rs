@@ -0,0 +1,97 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+// deadlines are kept as monotonic `Instant`s, wall time is only derived from them
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn system_time(&self) -> SystemTime;
+
+    // `listener` is called whenever the clock jumps ahead, until it returns false once what
+    // it serves is gone; only a manual clock ever jumps
+    fn on_advance(&self, _listener: Listener) {}
+}
+
+pub type Listener = Arc<dyn Fn() -> bool + Send + Sync>;
+
+pub struct MonotonicClock {
+    origin: Instant,
+    origin_wall: SystemTime,
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        MonotonicClock {
+            origin: Instant::now(),
+            origin_wall: SystemTime::now(),
+        }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.origin_wall + self.origin.elapsed()
+    }
+}
+
+pub struct ManualClock {
+    origin: Instant,
+    origin_wall: SystemTime,
+    elapsed: Mutex<Duration>,
+    listeners: Mutex<Vec<Listener>>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            origin: Instant::now(),
+            origin_wall: SystemTime::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+            listeners: Mutex::new(Vec::new()),
+        }
+    }
+
+    // schedulers on this clock run what became due before this returns
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+        let listeners = self.listeners.lock().unwrap().clone();
+        let gone: Vec<Listener> = listeners
+            .into_iter()
+            .filter(|listener| !listener())
+            .collect();
+        self.listeners
+            .lock()
+            .unwrap()
+            .retain(|listener| !gone.iter().any(|g| Arc::ptr_eq(g, listener)));
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.origin + *self.elapsed.lock().unwrap()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.origin_wall + *self.elapsed.lock().unwrap()
+    }
+
+    fn on_advance(&self, listener: Listener) {
+        self.listeners.lock().unwrap().push(listener);
+    }
+}
@@ -1,163 +1,586 @@
 use crate::app_server::parser::Command;
-use crate::services::persistence_service::{clear_log_file, persist_log};
-use crate::services::timer_service::do_after_delay;
+use crate::app_server::reply::Reply;
+use crate::services::keyspace::Keyspace;
+use crate::services::session::Session;
+use crate::services::snapshot::{dump_value, is_write, restore_value};
+use crate::services::store::{Store, StoredData, Value};
 
 use globset::{Glob, GlobMatcher};
-use once_cell::sync::Lazy;
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicIsize, Ordering};
-use std::sync::RwLock;
-use std::time::{Duration, SystemTime};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub static PERSIST: Lazy<RwLock<bool>> = Lazy::new(|| RwLock::new(false));
-
-static GLOBAL_STORE: Lazy<RwLock<HashMap<String, StoredData>>> =
-    Lazy::new(|| RwLock::new(HashMap::new()));
+// a write that could not be logged is refused rather than applied in memory only
+pub(crate) fn misconf(e: &str) -> Reply {
+    Reply::Error(format!("MISCONF Errors writing to the log file: {e}"))
+}
 
-static GLOBAL_NUMBERS: Lazy<RwLock<HashMap<String, AtomicIsize>>> =
-    Lazy::new(|| RwLock::new(HashMap::new()));
+// a key on disk could not be read, nothing was changed
+pub(crate) fn storage_error(e: &io::Error) -> Reply {
+    Reply::error(&format!("storage engine: {e}"))
+}
 
-struct StoredData {
-    value: String,
-    ttl: Option<SystemTime>,
+// takes as long wherever the passwords differ; ring marks the function deprecated for
+// outside callers but still ships it
+#[allow(deprecated)]
+fn password_matches(given: &str, required: &str) -> bool {
+    ring::constant_time::verify_slices_are_equal(given.as_bytes(), required.as_bytes()).is_ok()
 }
-impl StoredData {
-    fn new(value: String) -> Self {
-        StoredData {
-            value: value,
-            ttl: None,
+
+impl Store {
+    // expires the key if due, otherwise records the access for LRU/LFU eviction
+    fn access_key(&self, db: &Keyspace, key: &str) -> io::Result<()> {
+        db.remove_if_expired(key, self.clock.now())?;
+        if let Some(stored) = db.shard(key).read().unwrap().get(key) {
+            stored.touch(self.clock_ms());
         }
+        Ok(())
     }
-}
 
-pub async fn handle_on_memory(cmd: Command) -> String {
-    let already_in_map = |key| match GLOBAL_STORE.write().unwrap().remove(key) {
-        Some(stored) => match str::parse::<isize>(&stored.value) {
-            Ok(i) => Ok(i),
-            Err(_) => Err(()),
-        },
-        None => Ok(0),
-    };
-    match cmd {
-        Command::PING => "+PONG".to_string(),
-        Command::GET { key } => match GLOBAL_STORE.read().unwrap().get(&key) {
-            Some(stored) => format!("${}\r\n{}", stored.value.len(), stored.value),
-            None => match GLOBAL_NUMBERS.read().unwrap().get(&key) {
-                Some(i) => {
-                    let x = i.load(Ordering::SeqCst);
-                    format!("${}\r\n{}", x.to_string().len(), x)
-                }
-                None => "$-1".to_string(),
+    fn expire_after(&self, db: &Arc<Keyspace>, stored: &mut StoredData, key: String, sec: u64) {
+        if let Some(deadline) = self.clock.now().checked_add(Duration::from_secs(sec)) {
+            self.expire_at(db, stored, key, deadline);
+        }
+    }
+
+    // expiry tasks only hold a weak reference so they never keep a dropped keyspace alive
+    pub(crate) fn expire_at(
+        &self,
+        db: &Arc<Keyspace>,
+        stored: &mut StoredData,
+        key: String,
+        deadline: Instant,
+    ) {
+        if let Some(expiry) = stored.expiry.take() {
+            expiry.cancel();
+        }
+        let name = format!("expire:{key}");
+        let keyspace = Arc::downgrade(db);
+        let clock = self.clock.clone();
+        stored.expiry = Some(self.scheduler.schedule_once(
+            &name,
+            deadline.saturating_duration_since(self.clock.now()),
+            move || {
+                if let Some(keyspace) = keyspace.upgrade() {
+                    if let Err(e) = keyspace.remove_if_expired(&key, clock.now()) {
+                        eprintln!("error expiring {key}: {e}");
+                    }
+                }
             },
-        },
-        Command::DEL { key } => match GLOBAL_STORE.write().unwrap().remove(&key) {
-            Some(_) => ":1".to_string(),
-            None => ":0".to_string(),
-        },
-        Command::SET { key, value } => {
-            GLOBAL_STORE
-                .write()
-                .unwrap()
-                .insert(key, StoredData::new(value));
-            "+OK".to_string()
+        ));
+        stored.ttl = Some(deadline);
+    }
+
+    fn deadline_entry(&self, key: &str, sec: u64) -> Command {
+        let at = self.unix_ms().saturating_add(sec.saturating_mul(1000));
+        Command::cmd_pexpireat(key, at)
+    }
+
+    fn db_index(&self, index: usize) -> Result<usize, Reply> {
+        if index < self.databases() {
+            Ok(index)
+        } else {
+            Err(Reply::error("DB index is out of range"))
+        }
+    }
+
+    // the key keeps its value and deadline, only the expiry task is re-created for the target
+    fn move_key(&self, from: &Arc<Keyspace>, key: String, to: usize) -> io::Result<Reply> {
+        let Some(to) = self.db(to) else {
+            return Ok(Reply::error("DB index is out of range"));
+        };
+        if Arc::ptr_eq(from, &to) {
+            return Ok(Reply::error("source and destination objects are the same"));
         }
-        Command::KEYS { pattern } => {
-            let glob: Glob = Glob::new(&pattern).expect("Invalid glob pattern");
-            let matcher: GlobMatcher = glob.compile_matcher();
-
-            let keys = GLOBAL_STORE
-                .read()
-                .unwrap()
-                .keys()
-                .filter(|&k| matcher.is_match(k))
-                .map(|k| k.clone())
-                .collect::<Vec<String>>();
-
-            let ln = keys.len();
-
-            keys.into_iter()
-                .fold(String::from(format!("*{}", ln)), |mut acc, k| {
-                    acc.push_str(format!("\r\n${}\r\n{}", k.len(), k).as_str());
-                    acc
-                })
+        to.remove_if_expired(&key, self.clock.now())?;
+        // both shards are locked in address order so two opposite moves can't deadlock
+        let (source, target) = (from.shard(&key), to.shard(&key));
+        let (mut source, mut target) = if Arc::as_ptr(from) < Arc::as_ptr(&to) {
+            let source = source.write().unwrap();
+            (source, target.write().unwrap())
+        } else {
+            let target = target.write().unwrap();
+            (source.write().unwrap(), target)
+        };
+        if target.get(&key).is_some() {
+            return Ok(Reply::Integer(0));
         }
-        Command::EXPIRE { key, sec } => match GLOBAL_STORE.write().unwrap().get_mut(&key) {
-            Some(stored) => {
-                do_after_delay(
-                    move || {
-                        GLOBAL_STORE.write().unwrap().remove(&key);
-                    },
-                    Duration::from_secs(sec),
-                );
-                stored.ttl = SystemTime::now().checked_add(Duration::from_secs(sec));
-                ":1".to_string()
-            }
-            None => ":0".to_string(),
-        },
-        Command::FLUSHALL => {
-            GLOBAL_STORE.write().unwrap().clear();
-            GLOBAL_NUMBERS.write().unwrap().clear();
-            "+OK".to_string()
+        let Some(mut stored) = source.remove(&key)? else {
+            return Ok(Reply::Integer(0));
+        };
+        if let Some(deadline) = stored.ttl {
+            self.expire_at(&to, &mut stored, key.clone(), deadline);
         }
-        Command::TTL { key } => match GLOBAL_STORE.read().unwrap().get(&key) {
-            Some(stored) => match stored.ttl {
-                Some(ttl) => {
-                    let sec = ttl
-                        .duration_since(SystemTime::now())
-                        .unwrap_or_default()
-                        .as_secs()
-                        .to_string();
-
-                    format!(":{}", sec)
-                }
-                None => ":-1".to_string(),
+        target.insert(key, stored)?;
+        Ok(Reply::Integer(1))
+    }
+
+    fn push(
+        &self,
+        db: &Keyspace,
+        key: String,
+        values: Vec<String>,
+        left: bool,
+    ) -> io::Result<Reply> {
+        let mut map = db.shard(&key).write().unwrap();
+        let stored =
+            map.get_or_insert_with(&key, || self.new_data(Value::List(VecDeque::new())))?;
+        let reply = match &mut stored.value {
+            Value::List(list) => {
+                for v in values {
+                    if left {
+                        list.push_front(v);
+                    } else {
+                        list.push_back(v);
+                    }
+                }
+                Reply::Integer(list.len() as i64)
+            }
+            Value::Str(_) | Value::Packed(_) => return Ok(Reply::wrong_type()),
+        };
+        map.resize(&key);
+        Ok(reply)
+    }
+
+    fn pop(&self, db: &Keyspace, key: &str, left: bool) -> io::Result<Reply> {
+        let mut map = db.shard(key).write().unwrap();
+        let Some(stored) = map.get_mut(key)? else {
+            return Ok(Reply::nil());
+        };
+        let reply = match &mut stored.value {
+            Value::List(list) => {
+                let popped = if left {
+                    list.pop_front()
+                } else {
+                    list.pop_back()
+                };
+                Reply::Bulk(popped)
+            }
+            Value::Str(_) | Value::Packed(_) => return Ok(Reply::wrong_type()),
+        };
+        if matches!(&stored.value, Value::List(list) if list.is_empty()) {
+            map.remove(key)?;
+        } else {
+            map.resize(key);
+        }
+        Ok(reply)
+    }
+
+    // the value is parsed and rewritten in place under the shard lock, keeping its TTL
+    fn add_to_integer(&self, db: &Keyspace, key: String, by: i64) -> io::Result<Reply> {
+        let mut map = db.shard(&key).write().unwrap();
+        let stored = map.get_or_insert_with(&key, || self.new_data(Value::Str("0".to_string())))?;
+        stored.value.unpack();
+        let reply = match &mut stored.value {
+            Value::Str(value) => match str::parse::<i64>(value)
+                .ok()
+                .and_then(|i| i.checked_add(by))
+            {
+                Some(new_value) => {
+                    *value = new_value.to_string();
+                    Reply::Integer(new_value)
+                }
+                None => return Ok(Reply::not_integer()),
             },
-            None => ":-2".to_string(),
-        },
-        Command::INCR { key } => match already_in_map(&key) {
-            Ok(i) => {
-                let mut map = GLOBAL_NUMBERS.write().unwrap();
-                let counter = map.entry(key).or_insert_with(|| AtomicIsize::new(i));
-                let new_value = counter.fetch_add(1, Ordering::SeqCst) + 1;
-                format!(":{new_value}")
-            }
-            Err(_) => "-ERR value is not an integer or out of range".to_string(),
-        },
-        Command::DECR { key } => match already_in_map(&key) {
-            Ok(i) => {
-                let mut map = GLOBAL_NUMBERS.write().unwrap();
-                let counter = map.entry(key).or_insert_with(|| AtomicIsize::new(i));
-                let new_value = counter.fetch_sub(1, Ordering::SeqCst) - 1;
-                format!(":{new_value}")
-            }
-            Err(_) => "-ERR value is not an integer or out of range".to_string(),
-        },
+            Value::Packed(_) | Value::List(_) => return Ok(Reply::wrong_type()),
+        };
+        map.resize(&key);
+        Ok(reply)
+    }
+
+    pub async fn handle_on_memory(&self, session: &mut Session, cmd: Command) -> Reply {
+        self.apply(session, cmd)
+            .await
+            .unwrap_or_else(|e| storage_error(&e))
     }
-}
 
-pub async fn handle_on_memory_and_file(cmd: Command) -> String {
-    if *PERSIST.read().unwrap() {
+    // fails only when a key on disk could not be read
+    pub(crate) async fn apply(&self, session: &mut Session, cmd: Command) -> io::Result<Reply> {
+        let Some(db) = self.db(session.db) else {
+            return Ok(Reply::error("DB index is out of range"));
+        };
         match &cmd {
-            Command::PING {}
-            | Command::GET { key: _ }
-            | Command::KEYS { pattern: _ }
-            | Command::TTL { key: _ } => {}
+            Command::GET { key }
+            | Command::DEL { key }
+            | Command::EXPIRE { key, sec: _ }
+            | Command::PEXPIREAT { key, at: _ }
+            | Command::TTL { key }
+            | Command::PTTL { key }
+            | Command::INCR { key }
+            | Command::DECR { key }
+            | Command::LPUSH { key, values: _ }
+            | Command::RPUSH { key, values: _ }
+            | Command::LPOP { key }
+            | Command::RPOP { key }
+            | Command::LRANGE { key, .. }
+            | Command::LLEN { key }
+            | Command::MOVE { key, db: _ }
+            | Command::DUMP { key }
+            | Command::RESTORE { key, .. } => self.access_key(&db, key)?,
+            _ => {}
+        }
+        Ok(match cmd {
+            Command::PING => Reply::Simple("PONG".to_string()),
+            Command::GET { key } => match db.shard(&key).read().unwrap().get(&key) {
+                Some(stored) => match &stored.value {
+                    Value::Str(value) => Reply::bulk(value),
+                    Value::Packed(packed) => Reply::bulk(&packed.unpack()),
+                    Value::List(_) => Reply::wrong_type(),
+                },
+                None => Reply::nil(),
+            },
+            Command::DEL { key } => match db.shard(&key).write().unwrap().remove(&key)? {
+                Some(_) => Reply::Integer(1),
+                None => Reply::Integer(0),
+            },
+            Command::SET { key, value } => {
+                db.shard(&key)
+                    .write()
+                    .unwrap()
+                    .insert(key, self.new_data(Value::Str(value)))?;
+                Reply::ok()
+            }
+            Command::SETEX { key, sec, value } => {
+                let mut map = db.shard(&key).write().unwrap();
+                let mut stored = self.new_data(Value::Str(value));
+                self.expire_after(&db, &mut stored, key.clone(), sec);
+                map.insert(key, stored)?;
+                Reply::ok()
+            }
+            Command::SETPXAT { key, value, at } => {
+                let mut map = db.shard(&key).write().unwrap();
+                // like RESTORE, a deadline already passed only deletes the key
+                let Some(deadline) = self.deadline_from_unix_ms(at) else {
+                    map.remove(&key)?;
+                    return Ok(Reply::ok());
+                };
+                let mut stored = self.new_data(Value::Str(value));
+                self.expire_at(&db, &mut stored, key.clone(), deadline);
+                map.insert(key, stored)?;
+                Reply::ok()
+            }
+            Command::KEYS { pattern } => {
+                let glob: Glob = match Glob::new(&pattern) {
+                    Ok(glob) => glob,
+                    Err(_) => return Ok(Reply::error("invalid glob pattern")),
+                };
+                let matcher: GlobMatcher = glob.compile_matcher();
+                let now = self.clock.now();
 
-            Command::INCR { key: _ }
-            | Command::DECR { key: _ }
-            | Command::DEL { key: _ }
-            | Command::SET { key: _, value: _ } => {
-                persist_log(&cmd).await;
+                let mut keys = Vec::new();
+                for shard in db.read_all().iter() {
+                    shard.scan(&mut |k, stored| {
+                        if matcher.is_match(k) && !stored.is_expired(now) {
+                            keys.push(k.to_string());
+                        }
+                    })?;
+                }
+
+                Reply::strings(keys)
+            }
+            Command::EXPIRE { key, sec } => match db.shard(&key).write().unwrap().get_mut(&key)? {
+                Some(stored) => {
+                    self.expire_after(&db, stored, key, sec);
+                    Reply::Integer(1)
+                }
+                None => Reply::Integer(0),
+            },
+            Command::DUMP { key } => match db.shard(&key).read().unwrap().get(&key) {
+                Some(stored) => Reply::bulk(&hex::encode(dump_value(&stored.value))),
+                None => Reply::nil(),
+            },
+            Command::RESTORE {
+                key,
+                ttl,
+                payload,
+                replace,
+                absttl,
+            } => {
+                let Some(value) = hex::decode(&payload).ok().and_then(|p| restore_value(&p)) else {
+                    return Ok(Reply::error("DUMP payload version or checksum are wrong"));
+                };
+                let mut map = db.shard(&key).write().unwrap();
+                if !replace && map.get(&key).is_some() {
+                    return Ok(Reply::Error(
+                        "BUSYKEY Target key name already exists.".to_string(),
+                    ));
+                }
+                let mut stored = self.new_data(value);
+                if ttl > 0 {
+                    let at = if absttl {
+                        ttl
+                    } else {
+                        self.unix_ms().saturating_add(ttl)
+                    };
+                    // like Redis, a deadline already passed only deletes the key
+                    let Some(deadline) = self.deadline_from_unix_ms(at) else {
+                        map.remove(&key)?;
+                        return Ok(Reply::ok());
+                    };
+                    self.expire_at(&db, &mut stored, key.clone(), deadline);
+                }
+                map.insert(key, stored)?;
+                Reply::ok()
+            }
+            Command::PEXPIREAT { key, at } => {
+                let mut map = db.shard(&key).write().unwrap();
+                if map.get(&key).is_none() {
+                    return Ok(Reply::Integer(0));
+                }
+                match self.deadline_from_unix_ms(at) {
+                    Some(deadline) => {
+                        let stored = map.get_mut(&key)?.unwrap();
+                        self.expire_at(&db, stored, key, deadline);
+                    }
+                    None => {
+                        map.remove(&key)?;
+                    }
+                }
+                Reply::Integer(1)
+            }
+            Command::FLUSHALL => {
+                for db in self.all_dbs() {
+                    db.write_all().iter_mut().for_each(|shard| shard.clear());
+                }
+                Reply::ok()
             }
+            Command::FLUSHDB => {
+                db.write_all().iter_mut().for_each(|shard| shard.clear());
+                Reply::ok()
+            }
+            Command::AUTH { password } => {
+                let required = &self.settings().requirepass;
+                if required.is_empty() {
+                    Reply::error("AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?")
+                } else if password_matches(&password, required) {
+                    session.authenticated = true;
+                    Reply::ok()
+                } else {
+                    Reply::Error(
+                        "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+                    )
+                }
+            }
+            Command::SELECT { db } => match self.db_index(db) {
+                Ok(db) => {
+                    session.db = db;
+                    Reply::ok()
+                }
+                Err(e) => e,
+            },
+            Command::MOVE { key, db: to } => self.move_key(&db, key, to)?,
+            Command::BGREWRITEAOF => self.bg_rewrite(),
+            Command::SAVE => match self.save().await {
+                Ok(()) => Reply::ok(),
+                Err(e) => Reply::error(&e),
+            },
+            Command::BGSAVE => self.bg_save(),
+            Command::LASTSAVE => Reply::Integer(self.last_save() as i64),
+            Command::SWAPDB { a, b } => match (self.db_index(a), self.db_index(b)) {
+                (Ok(a), Ok(b)) => {
+                    self.dbs.write().unwrap().swap(a, b);
+                    Reply::ok()
+                }
+                (Err(e), _) | (_, Err(e)) => e,
+            },
+            Command::TTL { key } => match db.shard(&key).read().unwrap().get(&key) {
+                Some(stored) => match stored.ttl {
+                    Some(ttl) => Reply::Integer(
+                        ttl.saturating_duration_since(self.clock.now()).as_secs() as i64,
+                    ),
+                    None => Reply::Integer(-1),
+                },
+                None => Reply::Integer(-2),
+            },
+            Command::PTTL { key } => match db.shard(&key).read().unwrap().get(&key) {
+                Some(stored) => match stored.ttl {
+                    Some(ttl) => Reply::Integer(
+                        ttl.saturating_duration_since(self.clock.now()).as_millis() as i64,
+                    ),
+                    None => Reply::Integer(-1),
+                },
+                None => Reply::Integer(-2),
+            },
+            Command::INCR { key } => self.add_to_integer(&db, key, 1)?,
+            Command::DECR { key } => self.add_to_integer(&db, key, -1)?,
+            Command::LPUSH { key, values } => self.push(&db, key, values, true)?,
+            Command::RPUSH { key, values } => self.push(&db, key, values, false)?,
+            Command::LPOP { key } => self.pop(&db, &key, true)?,
+            Command::RPOP { key } => self.pop(&db, &key, false)?,
+            Command::LRANGE { key, start, stop } => {
+                match db.shard(&key).read().unwrap().get(&key) {
+                    Some(stored) => match &stored.value {
+                        Value::List(list) => {
+                            let len = list.len() as i64;
+                            let start = if start < 0 {
+                                (len + start).max(0)
+                            } else {
+                                start
+                            };
+                            let stop = if stop < 0 {
+                                len + stop
+                            } else {
+                                stop.min(len - 1)
+                            };
+                            if start > stop {
+                                return Ok(Reply::Array(vec![]));
+                            }
+                            Reply::strings(
+                                list.iter()
+                                    .skip(start as usize)
+                                    .take((stop - start + 1) as usize)
+                                    .cloned(),
+                            )
+                        }
+                        Value::Str(_) | Value::Packed(_) => Reply::wrong_type(),
+                    },
+                    None => Reply::Array(vec![]),
+                }
+            }
+            Command::LLEN { key } => match db.shard(&key).read().unwrap().get(&key) {
+                Some(stored) => match &stored.value {
+                    Value::List(list) => Reply::Integer(list.len() as i64),
+                    Value::Str(_) | Value::Packed(_) => Reply::wrong_type(),
+                },
+                None => Reply::Integer(0),
+            },
+            Command::MSET { pairs } => {
+                let mut shards = db.lock_keys(pairs.iter().map(|(k, _)| k.as_str()));
+                for (key, value) in pairs {
+                    shards
+                        .shard(&key)
+                        .insert(key, self.new_data(Value::Str(value)))?;
+                }
+                Reply::ok()
+            }
+            Command::MGET { keys } => {
+                let now = self.clock.now();
+                let mut shards = db.lock_keys(keys.iter().map(|k| k.as_str()));
+                for key in &keys {
+                    shards.shard(key).load(key)?;
+                }
+                Reply::Array(
+                    keys.iter()
+                        .map(|key| match shards.shard(key).get(key) {
+                            Some(stored) if !stored.is_expired(now) => match &stored.value {
+                                Value::Str(value) => Reply::bulk(value),
+                                Value::Packed(packed) => Reply::bulk(&packed.unpack()),
+                                Value::List(_) => Reply::nil(),
+                            },
+                            _ => Reply::nil(),
+                        })
+                        .collect(),
+                )
+            }
+        })
+    }
 
-            Command::EXPIRE { key, sec: _ } => {
-                persist_log(&Command::DEL { key: key.clone() }).await;
+    pub async fn handle_on_memory_and_file(&self, session: &mut Session, cmd: Command) -> Reply {
+        // SAVE takes the barrier exclusively itself
+        if cmd == Command::SAVE {
+            return self.handle_on_memory(session, cmd).await;
+        }
+        let _barrier = self.barrier.read().await;
+        let write = is_write(&cmd);
+        match &cmd {
+            Command::SET { .. }
+            | Command::SETEX { .. }
+            | Command::SETPXAT { .. }
+            | Command::INCR { .. }
+            | Command::DECR { .. }
+            | Command::LPUSH { .. }
+            | Command::RPUSH { .. }
+            | Command::MSET { .. }
+            | Command::RESTORE { .. } => {
+                if let Err(oom) = self.free_memory().await {
+                    return oom;
+                }
             }
+            _ => {}
+        }
+        if let Err(e) = self.prepare_write(session.db, &cmd).await {
+            return storage_error(&e);
+        }
+        if let Some(persistence) = &self.persistence {
+            let logged = match &cmd {
+                Command::PING
+                | Command::GET { key: _ }
+                | Command::KEYS { pattern: _ }
+                | Command::TTL { key: _ }
+                | Command::PTTL { key: _ }
+                | Command::LRANGE { .. }
+                | Command::LLEN { key: _ }
+                | Command::MGET { keys: _ }
+                | Command::SELECT { db: _ }
+                | Command::BGREWRITEAOF
+                | Command::SAVE
+                | Command::BGSAVE
+                | Command::LASTSAVE
+                | Command::DUMP { key: _ }
+                | Command::AUTH { password: _ } => Ok(()),
 
-            Command::FLUSHALL => {
-                clear_log_file().await;
+                Command::INCR { key: _ }
+                | Command::DECR { key: _ }
+                | Command::DEL { key: _ }
+                | Command::SET { key: _, value: _ }
+                | Command::LPUSH { key: _, values: _ }
+                | Command::RPUSH { key: _, values: _ }
+                | Command::LPOP { key: _ }
+                | Command::RPOP { key: _ }
+                | Command::MSET { pairs: _ }
+                | Command::MOVE { .. }
+                | Command::SWAPDB { .. }
+                | Command::FLUSHDB
+                | Command::FLUSHALL
+                | Command::SETPXAT { .. }
+                | Command::PEXPIREAT { .. } => persistence.persist_log(session.db, &cmd).await,
+
+                // relative expirations are logged as absolute deadlines so a restart
+                // doesn't extend them
+                Command::EXPIRE { key, sec } => {
+                    persistence
+                        .persist_log(session.db, &self.deadline_entry(key, *sec))
+                        .await
+                }
+                Command::RESTORE {
+                    key,
+                    ttl,
+                    payload,
+                    replace,
+                    absttl,
+                } => {
+                    let ttl = match (*ttl, *absttl) {
+                        (0, _) | (_, true) => *ttl,
+                        (ttl, false) => self.unix_ms().saturating_add(ttl),
+                    };
+                    let cmd = Command::RESTORE {
+                        key: key.clone(),
+                        ttl,
+                        payload: payload.clone(),
+                        replace: *replace,
+                        absttl: ttl > 0,
+                    };
+                    persistence.persist_log(session.db, &cmd).await
+                }
+                // one record, so a crash can't leave the value without its deadline
+                Command::SETEX { key, sec, value } => {
+                    let cmd = Command::SETPXAT {
+                        key: key.clone(),
+                        value: value.clone(),
+                        at: self.unix_ms().saturating_add(sec.saturating_mul(1000)),
+                    };
+                    persistence.persist_log(session.db, &cmd).await
+                }
+            };
+            if let Err(e) = logged {
+                return misconf(&e);
             }
         }
+        let reply = self.handle_on_memory(session, cmd).await;
+        if write && !matches!(reply, Reply::Error(_)) {
+            self.mark_dirty();
+        }
+        reply
     }
-    handle_on_memory(cmd).await
 }
@@ -0,0 +1,70 @@
+use serde::Deserialize;
+
+// shorter data rarely shrinks enough to pay for the header
+const MIN_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Codec {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Result<Codec, String> {
+        match id {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Lz4),
+            2 => Ok(Codec::Zstd),
+            _ => Err(format!("unknown compression codec {id}")),
+        }
+    }
+
+    // None when compressing does not make the data smaller
+    pub(crate) fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        let compressed = match self {
+            Codec::None => return None,
+            _ if data.len() < MIN_LEN => return None,
+            Codec::Lz4 => lz4_flex::compress_prepend_size(data),
+            Codec::Zstd => zstd::encode_all(data, 0).ok()?,
+        };
+        (compressed.len() < data.len()).then_some(compressed)
+    }
+
+    pub(crate) fn decompress(self, data: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Lz4 => lz4_flex::decompress_size_prepended(data).map_err(|e| e.to_string()),
+            Codec::Zstd => zstd::decode_all(data).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// a string value kept compressed in memory, unpacked whenever it is read
+#[derive(Clone)]
+pub(crate) struct Packed {
+    pub(crate) codec: Codec,
+    pub(crate) data: Vec<u8>,
+}
+
+impl Packed {
+    pub(crate) fn new(codec: Codec, value: &str) -> Option<Packed> {
+        let data = codec.compress(value.as_bytes())?;
+        Some(Packed { codec, data })
+    }
+
+    // packed by this process, so it is trusted to unpack
+    pub(crate) fn unpack(&self) -> String {
+        let bytes = self.codec.decompress(&self.data).unwrap_or_default();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
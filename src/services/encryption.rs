@@ -0,0 +1,204 @@
+use crate::Settings;
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use ring::hmac;
+use serde::Deserialize;
+use std::sync::{Arc, RwLock};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+// cipher, key id (u32 LE) and nonce
+const HEADER_LEN: usize = 1 + 4 + NONCE_LEN;
+// set in the cipher byte of data sealed with associated data and an HMAC key id; data
+// from before that is only opened while migrating
+const BOUND: u8 = 0x80;
+const KEY_ID_LABEL: &[u8] = b"kvds encryption key id";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Cipher {
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+}
+
+impl Cipher {
+    fn id(self) -> u8 {
+        match self {
+            Cipher::ChaCha20Poly1305 => 1,
+            Cipher::Aes256Gcm => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Cipher> {
+        match id {
+            1 => Some(Cipher::ChaCha20Poly1305),
+            2 => Some(Cipher::Aes256Gcm),
+            _ => None,
+        }
+    }
+}
+
+// the cipher and the id of the key some data was sealed with
+pub type SealedWith = (Cipher, u32);
+
+struct SealKey {
+    // an HMAC of a fixed label under the key, tells which key sealed some data without
+    // revealing anything about it
+    id: u32,
+    chacha: ChaCha20Poly1305,
+    aes: Aes256Gcm,
+}
+
+// the keys data at rest is sealed with: the first one seals, all of them open, so data
+// sealed with a retired key stays readable until a rewrite seals it again
+pub struct Keyring {
+    cipher: Cipher,
+    keys: Vec<SealKey>,
+    // unencrypted data and data sealed before records were bound to their position are
+    // accepted, for a one-time start that encrypts them
+    migrating: bool,
+}
+
+impl Keyring {
+    // 32 byte keys in hex separated by whitespace or commas, the current one first
+    pub fn parse(text: &str, cipher: Cipher) -> Result<Keyring, String> {
+        let keys = text
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|key| !key.is_empty())
+            .map(|key| {
+                let key = hex::decode(key).map_err(|_| "encryption key is not hex".to_string())?;
+                if key.len() != KEY_LEN {
+                    return Err(format!("encryption key must be {KEY_LEN} bytes"));
+                }
+                let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key), KEY_ID_LABEL);
+                Ok(SealKey {
+                    id: u32::from_le_bytes(tag.as_ref()[..4].try_into().unwrap()),
+                    chacha: ChaCha20Poly1305::new_from_slice(&key).unwrap(),
+                    aes: Aes256Gcm::new_from_slice(&key).unwrap(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if keys.is_empty() {
+            return Err("no encryption key given".to_string());
+        }
+        Ok(Keyring {
+            cipher,
+            keys,
+            migrating: false,
+        })
+    }
+
+    pub fn migrating(mut self, migrating: bool) -> Self {
+        self.migrating = migrating;
+        self
+    }
+
+    pub fn is_migrating(&self) -> bool {
+        self.migrating
+    }
+
+    // from `encryption_key_file`, else the variable named by `encryption_key_env`,
+    // None when encryption is off
+    pub fn load(settings: &Settings) -> Result<Option<Keyring>, String> {
+        let text = if !settings.encryption_key_file.is_empty() {
+            std::fs::read_to_string(&settings.encryption_key_file).map_err(|e| {
+                format!(
+                    "cannot read the encryption key file {}: {e}",
+                    settings.encryption_key_file
+                )
+            })?
+        } else if !settings.encryption_key_env.is_empty() {
+            std::env::var(&settings.encryption_key_env)
+                .map_err(|_| format!("{} is not set", settings.encryption_key_env))?
+        } else {
+            return Ok(None);
+        };
+        Keyring::parse(&text, settings.encryption_cipher)
+            .map(|keyring| Some(keyring.migrating(settings.migrate_encryption)))
+    }
+
+    // how new data is sealed
+    pub fn current(&self) -> SealedWith {
+        (self.cipher, self.keys[0].id)
+    }
+
+    // `aad` is authenticated along with the data, so it only opens with the same
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let key = &self.keys[0];
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        let ciphertext = match self.cipher {
+            Cipher::ChaCha20Poly1305 => key.chacha.encrypt(&nonce, payload),
+            Cipher::Aes256Gcm => key.aes.encrypt(&nonce, payload),
+        }
+        .expect("encryption failed");
+        let mut sealed = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        sealed.push(self.cipher.id() | BOUND);
+        sealed.extend_from_slice(&key.id.to_le_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    // the plaintext and how it was sealed
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<(Vec<u8>, SealedWith), String> {
+        if sealed.len() < HEADER_LEN {
+            return Err("sealed data is too short".to_string());
+        }
+        let bound = sealed[0] & BOUND != 0;
+        let cipher = Cipher::from_id(sealed[0] & !BOUND).ok_or("unknown cipher".to_string())?;
+        let id = u32::from_le_bytes(sealed[1..5].try_into().unwrap());
+        let nonce = sealed[5..HEADER_LEN].into();
+        let ciphertext = &sealed[HEADER_LEN..];
+        let open = |key: &SealKey, aad: &[u8]| {
+            let payload = Payload {
+                msg: ciphertext,
+                aad,
+            };
+            match cipher {
+                Cipher::ChaCha20Poly1305 => key.chacha.decrypt(nonce, payload),
+                Cipher::Aes256Gcm => key.aes.decrypt(nonce, payload),
+            }
+            .ok()
+        };
+        let plaintext = if bound {
+            let Some(key) = self.keys.iter().find(|key| key.id == id) else {
+                return Err(format!("sealed with unknown key {id:08x}"));
+            };
+            open(key, aad)
+        } else if self.migrating {
+            // the ids of older data do not match any more, the cipher tells the right key
+            self.keys.iter().find_map(|key| open(key, &[]))
+        } else {
+            return Err(
+                "sealed in an older format, start once with --migrate-encryption".to_string(),
+            );
+        };
+        plaintext
+            .map(|plaintext| (plaintext, (cipher, id)))
+            .ok_or("decryption failed".to_string())
+    }
+}
+
+// the keyring in use, a log rewrite reloads it to pick up a rotated key
+#[derive(Default)]
+pub struct Keys(RwLock<Option<Arc<Keyring>>>);
+
+impl Keys {
+    pub fn new(keyring: Option<Keyring>) -> Self {
+        Keys(RwLock::new(keyring.map(Arc::new)))
+    }
+
+    pub fn get(&self) -> Option<Arc<Keyring>> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub(crate) fn set(&self, keyring: Keyring) {
+        *self.0.write().unwrap() = Some(Arc::new(keyring));
+    }
+}
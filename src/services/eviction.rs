@@ -0,0 +1,125 @@
+use crate::app_server::parser::Command;
+use crate::app_server::reply::Reply;
+use crate::services::command_handler::{misconf, storage_error};
+use crate::services::store::Store;
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MaxmemoryPolicy {
+    Noeviction,
+    AllkeysLru,
+    VolatileLru,
+    AllkeysLfu,
+    VolatileLfu,
+    AllkeysRandom,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    fn volatile_only(self) -> bool {
+        matches!(
+            self,
+            Self::VolatileLru | Self::VolatileLfu | Self::VolatileRandom | Self::VolatileTtl
+        )
+    }
+}
+
+fn oom() -> Reply {
+    Reply::Error("OOM command not allowed when used memory > 'maxmemory'.".to_string())
+}
+
+impl Store {
+    // evicts keys until usage is back under `maxmemory`, or refuses the write
+    pub(crate) async fn free_memory(&self) -> Result<(), Reply> {
+        let max = self.settings.maxmemory;
+        if max == 0 {
+            return Ok(());
+        }
+        while self.used_memory() > max {
+            if self.settings.maxmemory_policy == MaxmemoryPolicy::Noeviction {
+                return Err(oom());
+            }
+            let Some((index, key)) = self.eviction_candidate() else {
+                return Err(oom());
+            };
+            let Some(db) = self.db(index) else {
+                continue;
+            };
+            self.keep_evicted(&db, &key)
+                .map_err(|e| storage_error(&e))?;
+            // logged first like any command, so a key the log can't drop stays in memory
+            if let Some(persistence) = &self.persistence {
+                persistence
+                    .persist_log(index, &Command::cmd_del(&key))
+                    .await
+                    .map_err(|e| misconf(&e))?;
+            }
+            db.shard(&key)
+                .write()
+                .unwrap()
+                .remove(&key)
+                .map_err(|e| storage_error(&e))?;
+        }
+        Ok(())
+    }
+
+    // approximated like Redis: the best key out of a few random samples across all databases
+    fn eviction_candidate(&self) -> Option<(usize, String)> {
+        let policy = self.settings.maxmemory_policy;
+        let samples = match policy {
+            MaxmemoryPolicy::AllkeysRandom | MaxmemoryPolicy::VolatileRandom => 1,
+            _ => self.settings.maxmemory_samples.max(1),
+        };
+        let now = self.clock.now();
+        let now_ms = self.clock_ms();
+        // only databases holding keys are sampled, empty ones would mostly miss
+        let dbs: Vec<_> = self
+            .all_dbs()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, db)| db.used_memory() > 0)
+            .collect();
+        if dbs.is_empty() {
+            return None;
+        }
+        let mut best: Option<(u128, usize, String)> = None;
+        let mut taken = 0;
+        // empty shards and non-volatile keys don't count as samples, but are bounded
+        for _ in 0..samples * 16 {
+            if taken == samples {
+                break;
+            }
+            let (index, db) = &dbs[fastrand::usize(..dbs.len())];
+            let index = *index;
+            let shards = db.shards();
+            let shard = shards[fastrand::usize(..shards.len())].read().unwrap();
+            let Some((key, stored)) = shard.sample() else {
+                continue;
+            };
+            if policy.volatile_only() && stored.ttl.is_none() {
+                continue;
+            }
+            taken += 1;
+            // lower scores are evicted first
+            let score = match policy {
+                MaxmemoryPolicy::AllkeysLru | MaxmemoryPolicy::VolatileLru => {
+                    stored.last_access() as u128
+                }
+                MaxmemoryPolicy::AllkeysLfu | MaxmemoryPolicy::VolatileLfu => {
+                    stored.lfu(now_ms) as u128
+                }
+                MaxmemoryPolicy::VolatileTtl => stored.ttl.map_or(u128::MAX, |ttl| {
+                    ttl.saturating_duration_since(now).as_millis()
+                }),
+                _ => 0,
+            };
+            if best.as_ref().is_none_or(|(s, _, _)| score < *s) {
+                best = Some((score, index, key.clone()));
+            }
+        }
+        best.map(|(_, index, key)| (index, key))
+    }
+}
@@ -0,0 +1,227 @@
+use crate::services::storage::StorageEngine;
+use crate::services::store::StoredData;
+
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Instant;
+
+// the keys of a storage engine, whose inserts and removals keep the keyspace's memory
+// counter up to date. Keys on disk are loaded before they are changed, which can fail,
+// and don't count as used memory
+pub(crate) struct Shard {
+    engine: Box<dyn StorageEngine>,
+    // of every key, in memory or on disk
+    size: usize,
+    // what this shard added to the counter, the size of the keys in memory
+    counted: usize,
+    used_memory: Arc<AtomicUsize>,
+}
+
+impl Shard {
+    pub(crate) fn get(&self, key: &str) -> Option<&StoredData> {
+        self.engine.get(key)
+    }
+
+    // in-place changes of the value must be followed by `resize`
+    pub(crate) fn get_mut(&mut self, key: &str) -> io::Result<Option<&mut StoredData>> {
+        self.load(key)?;
+        Ok(self.engine.get_mut(key))
+    }
+
+    pub(crate) fn get_or_insert_with<F>(&mut self, key: &str, f: F) -> io::Result<&mut StoredData>
+    where
+        F: FnOnce() -> StoredData,
+    {
+        self.load(key)?;
+        if self.engine.get(key).is_none() {
+            self.insert(key.to_string(), f())?;
+        }
+        Ok(self.engine.get_mut(key).unwrap())
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        key: String,
+        mut data: StoredData,
+    ) -> io::Result<Option<StoredData>> {
+        data.size = data.estimate_size(&key);
+        let size = data.size;
+        let old = self.engine.insert(key, data)?;
+        self.grow(size);
+        if let Some(old) = &old {
+            self.shrink(old.size);
+        }
+        Ok(old)
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> io::Result<Option<StoredData>> {
+        let old = self.engine.remove(key)?;
+        if let Some(old) = &old {
+            self.shrink(old.size);
+        }
+        Ok(old)
+    }
+
+    pub(crate) fn resize(&mut self, key: &str) {
+        if let Some(data) = self.engine.get_mut(key) {
+            let (old, size) = (data.size, data.estimate_size(key));
+            data.size = size;
+            self.grow(size);
+            self.shrink(old);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.engine.clear();
+        self.shrink(self.size);
+    }
+
+    // brings a key kept on disk into memory so `get` sees it
+    pub(crate) fn load(&mut self, key: &str) -> io::Result<()> {
+        let loaded = self.engine.load(key);
+        self.count();
+        loaded
+    }
+
+    // a random key in memory, cold keys are never sampled
+    pub(crate) fn sample(&self) -> Option<(&String, &StoredData)> {
+        self.engine.sample()
+    }
+
+    pub(crate) fn scan(&self, f: &mut dyn FnMut(&str, &StoredData)) -> io::Result<()> {
+        self.engine.scan(f)
+    }
+
+    fn grow(&mut self, by: usize) {
+        self.size += by;
+        self.count();
+    }
+
+    fn shrink(&mut self, by: usize) {
+        self.size -= by;
+        self.count();
+    }
+
+    // keys the engine moved to disk or back since are taken off or put back on the counter
+    fn count(&mut self) {
+        let resident = self.size.saturating_sub(self.engine.cold_size());
+        if resident >= self.counted {
+            self.used_memory
+                .fetch_add(resident - self.counted, Ordering::Relaxed);
+        } else {
+            self.used_memory
+                .fetch_sub(self.counted - resident, Ordering::Relaxed);
+        }
+        self.counted = resident;
+    }
+}
+
+// the keyspace is split into lock-striped shards so writers on different keys don't contend
+pub(crate) struct Keyspace {
+    shards: Vec<RwLock<Shard>>,
+    hasher: RandomState,
+    used_memory: Arc<AtomicUsize>,
+}
+
+impl Keyspace {
+    pub(crate) fn new(engines: Vec<Box<dyn StorageEngine>>) -> Self {
+        let used_memory = Arc::new(AtomicUsize::new(0));
+        Keyspace {
+            shards: engines
+                .into_iter()
+                .map(|engine| {
+                    RwLock::new(Shard {
+                        engine,
+                        size: 0,
+                        counted: 0,
+                        used_memory: used_memory.clone(),
+                    })
+                })
+                .collect(),
+            hasher: RandomState::new(),
+            used_memory,
+        }
+    }
+
+    pub(crate) fn index(&self, key: &str) -> usize {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    pub(crate) fn shard(&self, key: &str) -> &RwLock<Shard> {
+        &self.shards[self.index(key)]
+    }
+
+    pub(crate) fn shards(&self) -> &[RwLock<Shard>] {
+        &self.shards
+    }
+
+    pub(crate) fn used_memory(&self) -> usize {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn load(&self, key: &str) -> io::Result<()> {
+        let shard = self.shard(key);
+        if shard.read().unwrap().engine.is_cold(key)? {
+            shard.write().unwrap().load(key)?;
+        }
+        Ok(())
+    }
+
+    // keys are also expired lazily so a key is never visible past its deadline,
+    // even before its scheduled removal has run
+    pub(crate) fn remove_if_expired(&self, key: &str, now: Instant) -> io::Result<()> {
+        self.load(key)?;
+        let shard = self.shard(key);
+        let expired = |map: &Shard| map.get(key).is_some_and(|s| s.is_expired(now));
+        if expired(&shard.read().unwrap()) {
+            let mut map = shard.write().unwrap();
+            if expired(&map) {
+                map.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    // every shard, always locked in ascending order
+    pub(crate) fn read_all(&self) -> Vec<RwLockReadGuard<'_, Shard>> {
+        self.shards.iter().map(|s| s.read().unwrap()).collect()
+    }
+
+    pub(crate) fn write_all(&self) -> Vec<RwLockWriteGuard<'_, Shard>> {
+        self.shards.iter().map(|s| s.write().unwrap()).collect()
+    }
+
+    // locks the shards owning `keys` in ascending shard order so multi-key commands can't deadlock
+    pub(crate) fn lock_keys<'a, I>(&self, keys: I) -> LockedShards<'_>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut indexes = keys.into_iter().map(|k| self.index(k)).collect::<Vec<_>>();
+        indexes.sort_unstable();
+        indexes.dedup();
+        LockedShards {
+            keyspace: self,
+            guards: indexes
+                .into_iter()
+                .map(|i| (i, self.shards[i].write().unwrap()))
+                .collect(),
+        }
+    }
+}
+
+pub(crate) struct LockedShards<'a> {
+    keyspace: &'a Keyspace,
+    guards: BTreeMap<usize, RwLockWriteGuard<'a, Shard>>,
+}
+
+impl LockedShards<'_> {
+    // panics if `key` was not part of the locked set
+    pub(crate) fn shard(&mut self, key: &str) -> &mut Shard {
+        let index = self.keyspace.index(key);
+        self.guards.get_mut(&index).expect("key was not locked!")
+    }
+}
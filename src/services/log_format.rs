@@ -0,0 +1,374 @@
+use crate::app_server::parser::{parse_command, Command};
+use crate::services::compression::Codec;
+use crate::services::encryption::{Keyring, SealedWith};
+use crate::services::snapshot::{is_snapshot, read_snapshot};
+
+const RECORD_MARKER: u8 = 0xFA;
+const TIMESTAMP_MARKER: u8 = 0xFB;
+const SEALED_MARKER: u8 = 0xFC;
+const COMPRESSED_MARKER: u8 = 0xFD;
+const RECORD_HEADER_LEN: usize = 9;
+const TIMESTAMP_LEN: usize = 13;
+
+// one logged command: the marker, the payload length and its crc32 (both u32 LE),
+// then the command in RESP as is
+pub(crate) fn encode_record(cmd: &Command) -> Vec<u8> {
+    frame(RECORD_MARKER, &cmd.to_string().into_bytes())
+}
+
+// where a record starts in the log, sealed into it so it cannot be moved elsewhere
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub segment: u64,
+    pub offset: u64,
+}
+
+impl Position {
+    // the associated data of a record sealed here
+    pub(crate) fn aad(&self) -> [u8; 16] {
+        let mut aad = [0; 16];
+        aad[..8].copy_from_slice(&self.segment.to_le_bytes());
+        aad[8..].copy_from_slice(&self.offset.to_le_bytes());
+        aad
+    }
+}
+
+// any other record wrapped in more records framed the same way: first compressed, the codec
+// and the compressed record as the payload, when that makes it smaller, then with encryption
+// on sealed as the payload, bound to where it is written
+pub(crate) fn pack_record(
+    record: Vec<u8>,
+    codec: Codec,
+    keys: Option<&Keyring>,
+    at: Position,
+) -> Vec<u8> {
+    let record = match codec.compress(&record) {
+        Some(compressed) => frame(
+            COMPRESSED_MARKER,
+            &[&[codec.id()], &compressed[..]].concat(),
+        ),
+        None => record,
+    };
+    match keys {
+        Some(keys) => frame(SEALED_MARKER, &keys.seal(&record, &at.aad())),
+        None => record,
+    }
+}
+
+fn frame(marker: u8, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.push(marker);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+// when the records following it were written: the marker, the unix time in ms (u64 LE)
+// and its crc32 (u32 LE)
+pub(crate) fn encode_timestamp(unix_ms: u64) -> Vec<u8> {
+    let time = unix_ms.to_le_bytes();
+    let mut record = Vec::with_capacity(TIMESTAMP_LEN);
+    record.push(TIMESTAMP_MARKER);
+    record.extend_from_slice(&time);
+    record.extend_from_slice(&crc32fast::hash(&time).to_le_bytes());
+    record
+}
+
+enum Record {
+    Command(Command),
+    Timestamp(u64),
+    Sealed(Vec<u8>),
+    Compressed(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Corruption {
+    // byte offset of the first bad record
+    pub offset: usize,
+    pub reason: String,
+    // nothing valid follows the bad record, as after a crash during a write
+    pub at_tail: bool,
+}
+
+pub struct LogCheck {
+    // the length of the snapshot preamble, 0 without one
+    pub preamble: usize,
+    pub commands: Vec<Command>,
+    // the byte offset where the record of each command ends
+    pub ends: Vec<usize>,
+    // (number of commands before it, unix ms) for every timestamp record
+    pub timestamps: Vec<(usize, u64)>,
+    // command records, without timestamps
+    pub records: usize,
+    // records in one of the older text formats, loading rewrites the file when there are any
+    pub legacy_records: usize,
+    // with a keyring, records and preamble not sealed with its current key and cipher,
+    // loading rewrites the log when there are any
+    pub stale_records: usize,
+    // the file can be truncated to this length to drop the corruption
+    pub valid_len: usize,
+    pub corruption: Option<Corruption>,
+}
+
+// reads the snapshot preamble if any and the records up to the first bad one of the
+// `segment`, sealed ones need `keys`. With `keys` unencrypted records are bad unless
+// migrating
+pub fn check_log(data: &[u8], segment: u64, keys: Option<&Keyring>) -> LogCheck {
+    let mut check = LogCheck {
+        preamble: 0,
+        commands: Vec::new(),
+        ends: Vec::new(),
+        timestamps: Vec::new(),
+        records: 0,
+        legacy_records: 0,
+        stale_records: 0,
+        valid_len: 0,
+        corruption: None,
+    };
+    if is_snapshot(data) {
+        let at = Position { segment, offset: 0 };
+        // only checked here, replaying reads it again as it restores it
+        let mut input = data;
+        match read_snapshot(&mut input, keys, &at.aad(), &mut |_, _| Ok(())) {
+            Ok(sealed_with) => {
+                check.preamble = data.len() - input.len();
+                check.valid_len = check.preamble;
+                check.stale_records += is_stale(keys, sealed_with) as usize;
+            }
+            Err(reason) => {
+                check.corruption = Some(Corruption {
+                    offset: 0,
+                    reason: format!("snapshot preamble: {reason}"),
+                    at_tail: false,
+                });
+                return check;
+            }
+        }
+    }
+    while check.valid_len < data.len() {
+        let offset = check.valid_len;
+        let at = Position {
+            segment,
+            offset: offset as u64,
+        };
+        let read = read_record(&data[offset..]).map(|(record, len)| {
+            let opened = open_record(record, keys, None, &at.aad()).and_then(|opened| match keys {
+                Some(keys) if opened.1.is_none() && !keys.is_migrating() => {
+                    Err("unencrypted record but an encryption key is configured".to_string())
+                }
+                _ => Ok(opened),
+            });
+            (opened, len)
+        });
+        match read {
+            Ok((Err(reason), _)) => {
+                // intact but unreadable, so never cut off as a torn write
+                check.corruption = Some(Corruption {
+                    offset,
+                    reason,
+                    at_tail: false,
+                });
+                break;
+            }
+            Ok((Ok((Record::Timestamp(unix_ms), sealed_with)), len)) => {
+                check.stale_records += is_stale(keys, sealed_with) as usize;
+                check.timestamps.push((check.commands.len(), unix_ms));
+                check.valid_len += len;
+            }
+            Ok((Ok((Record::Command(cmd), sealed_with)), len)) => {
+                check.stale_records += is_stale(keys, sealed_with) as usize;
+                if !matches!(
+                    data[offset],
+                    RECORD_MARKER | SEALED_MARKER | COMPRESSED_MARKER
+                ) {
+                    check.legacy_records += 1;
+                }
+                check.commands.push(cmd);
+                check.records += 1;
+                check.valid_len += len;
+                check.ends.push(check.valid_len);
+            }
+            Ok((Ok(_), _)) => unreachable!("records are opened down to a command or timestamp"),
+            Err((reason, end)) => {
+                // without a known end, a record is torn when no other line follows it. One
+                // running past the end may have a corrupted length instead, so it is only
+                // torn when no valid record follows it either
+                let (reason, at_tail) = match end {
+                    Some(end) if offset + end >= data.len() => {
+                        if valid_record_after(data, offset + 1) {
+                            ("invalid record length".to_string(), false)
+                        } else {
+                            (reason, true)
+                        }
+                    }
+                    Some(_) => (reason, false),
+                    None => (reason, !data[offset..].contains(&b'\n')),
+                };
+                check.corruption = Some(Corruption {
+                    offset,
+                    reason,
+                    at_tail,
+                });
+                break;
+            }
+        }
+    }
+    check
+}
+
+// whether a whole framed record with a matching checksum starts anywhere from `from` on
+fn valid_record_after(data: &[u8], from: usize) -> bool {
+    (from..data.len()).any(|at| {
+        matches!(
+            data[at],
+            RECORD_MARKER | TIMESTAMP_MARKER | SEALED_MARKER | COMPRESSED_MARKER
+        ) && read_record(&data[at..]).is_ok()
+    })
+}
+
+impl LogCheck {
+    // the last timestamp written before the command at `index`
+    pub fn written_at(&self, index: usize) -> Option<u64> {
+        let stamps = self.timestamps.partition_point(|(at, _)| *at <= index);
+        stamps.checked_sub(1).map(|i| self.timestamps[i].1)
+    }
+}
+
+fn is_stale(keys: Option<&Keyring>, sealed_with: Option<SealedWith>) -> bool {
+    keys.is_some_and(|keys| sealed_with != Some(keys.current()))
+}
+
+// peels the encryption and compression off a record down to the command or timestamp inside,
+// returns it with how the outermost layer was sealed
+fn open_record(
+    record: Record,
+    keys: Option<&Keyring>,
+    sealed_with: Option<SealedWith>,
+    aad: &[u8],
+) -> Result<(Record, Option<SealedWith>), String> {
+    let (inner, sealed_with) = match record {
+        Record::Sealed(sealed) => {
+            let keys =
+                keys.ok_or("encrypted record but no encryption key is configured".to_string())?;
+            let (plain, with) = keys.open(&sealed, aad)?;
+            (plain, sealed_with.or(Some(with)))
+        }
+        Record::Compressed(compressed) => {
+            let (codec, compressed) = compressed.split_first().ok_or("empty compressed record")?;
+            (Codec::from_id(*codec)?.decompress(compressed)?, sealed_with)
+        }
+        record => return Ok((record, sealed_with)),
+    };
+    // only framed records are ever wrapped
+    let framed = matches!(
+        inner.first(),
+        Some(&(RECORD_MARKER | TIMESTAMP_MARKER | SEALED_MARKER | COMPRESSED_MARKER))
+    );
+    match read_record(&inner) {
+        Ok((record, len)) if framed && len == inner.len() => {
+            open_record(record, keys, sealed_with, aad)
+        }
+        _ => Err("invalid wrapped record".to_string()),
+    }
+}
+
+// why a record is invalid and where it ends, if that is known
+type RecordError = (String, Option<usize>);
+
+// a record and its length
+fn read_record(data: &[u8]) -> Result<(Record, usize), RecordError> {
+    if data.first() == Some(&SEALED_MARKER) {
+        return read_frame(data).map(|(payload, end)| (Record::Sealed(payload.to_vec()), end));
+    }
+    if data.first() == Some(&COMPRESSED_MARKER) {
+        return read_frame(data).map(|(payload, end)| (Record::Compressed(payload.to_vec()), end));
+    }
+    if data.first() == Some(&TIMESTAMP_MARKER) {
+        let record = data
+            .get(1..TIMESTAMP_LEN)
+            .ok_or(("incomplete record".to_string(), Some(data.len())))?;
+        let checksum = u32::from_le_bytes(record[8..].try_into().unwrap());
+        if crc32fast::hash(&record[..8]) != checksum {
+            return Err(("checksum mismatch".to_string(), Some(TIMESTAMP_LEN)));
+        }
+        let unix_ms = u64::from_le_bytes(record[..8].try_into().unwrap());
+        return Ok((Record::Timestamp(unix_ms), TIMESTAMP_LEN));
+    }
+    read_command(data).map(|(cmd, len)| (Record::Command(cmd), len))
+}
+
+fn read_command(data: &[u8]) -> Result<(Command, usize), RecordError> {
+    if data.first() == Some(&RECORD_MARKER) {
+        let (payload, end) = read_frame(data)?;
+        let cmd = std::str::from_utf8(payload)
+            .map_err(|e| e.to_string())
+            .and_then(|payload| {
+                parse_command(payload.to_string()).map_err(|_| "invalid command".to_string())
+            });
+        return cmd.map(|cmd| (cmd, end)).map_err(|e| (e, Some(end)));
+    }
+    // lines written before records were framed hold just the escaped command
+    if data.first() == Some(&b'*') {
+        let Some(end) = data.iter().position(|b| *b == b'\n') else {
+            return Err(("incomplete record".to_string(), None));
+        };
+        let line = &data[..end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        return parse_payload(line)
+            .map(|cmd| (cmd, end + 1))
+            .map_err(|e| (e, Some(end + 1)));
+    }
+    // text records: "<payload length> <crc32 in hex> <escaped command>\r\n"
+    let header = |from: usize| -> Result<(&[u8], usize), RecordError> {
+        let Some(space) = data[from..].iter().position(|b| *b == b' ') else {
+            return Err(("incomplete record".to_string(), None));
+        };
+        Ok((&data[from..from + space], from + space + 1))
+    };
+    let (len, next) = header(0)?;
+    let len = parse_number(len, 10).ok_or(("invalid record length".to_string(), None))?;
+    let (checksum, start) = header(next)?;
+    let checksum = parse_number(checksum, 16).ok_or(("invalid checksum".to_string(), None))?;
+    let end = usize::try_from(len)
+        .ok()
+        .and_then(|len| start.checked_add(len)?.checked_add(2))
+        .ok_or(("invalid record length".to_string(), None))?;
+    if data.len() < end {
+        return Err(("incomplete record".to_string(), None));
+    }
+    if &data[end - 2..end] != b"\r\n" {
+        return Err(("record is not terminated".to_string(), Some(end)));
+    }
+    let payload = &data[start..end - 2];
+    if crc32fast::hash(payload) as u64 != checksum {
+        return Err(("checksum mismatch".to_string(), Some(end)));
+    }
+    parse_payload(payload)
+        .map(|cmd| (cmd, end))
+        .map_err(|e| (e, Some(end)))
+}
+
+// the payload of a framed record and where the record ends
+fn read_frame(data: &[u8]) -> Result<(&[u8], usize), RecordError> {
+    // an incomplete record runs to the end of the data
+    let torn = || ("incomplete record".to_string(), Some(data.len()));
+    let header = data.get(1..RECORD_HEADER_LEN).ok_or_else(torn)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let end = RECORD_HEADER_LEN.saturating_add(len);
+    let payload = data.get(RECORD_HEADER_LEN..end).ok_or_else(torn)?;
+    if crc32fast::hash(payload) != checksum {
+        return Err(("checksum mismatch".to_string(), Some(end)));
+    }
+    Ok((payload, end))
+}
+
+fn parse_number(digits: &[u8], radix: u32) -> Option<u64> {
+    u64::from_str_radix(std::str::from_utf8(digits).ok()?, radix).ok()
+}
+
+fn parse_payload(payload: &[u8]) -> Result<Command, String> {
+    let payload = std::str::from_utf8(payload).map_err(|e| e.to_string())?;
+    parse_command(payload.replace("\\r\\n", "\r\n")).map_err(|_| "invalid command".to_string())
+}
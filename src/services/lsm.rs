@@ -0,0 +1,572 @@
+use crate::services::compression::{Codec, Packed};
+use crate::services::store::{StoredData, Value};
+
+use indexmap::IndexMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// every so many records of a table have their key and offset indexed in memory
+const INDEX_INTERVAL: usize = 16;
+// more tables than this are merged into one
+const MAX_TABLES: usize = 4;
+// about 1% false positives
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u64 = 7;
+
+// a key and its encoded entry, None for a deletion that hides older tables
+type Record = (String, Option<Vec<u8>>);
+
+// the most recently used keys stay in memory as they are, the others are encoded into a
+// sorted in-memory table that is written out as an immutable file once it is full.
+// A key lives either in memory or on disk, never in both
+pub(crate) struct LsmEngine {
+    dir: PathBuf,
+    hot: IndexMap<String, StoredData>,
+    hot_capacity: usize,
+    memtable: BTreeMap<String, Option<Vec<u8>>>,
+    memtable_bytes: usize,
+    memtable_size: usize,
+    // the memtable is written out once it holds this many bytes, later after a failed write
+    next_flush: usize,
+    // oldest first
+    tables: Vec<Table>,
+    next_table: u64,
+    // live keys in the memtable and the tables, and their size in memory
+    cold: usize,
+    cold_size: usize,
+    // deadlines are stored relative to it
+    epoch: Instant,
+    // keys loaded back into memory count as accessed at the newest access seen
+    latest_access: u64,
+}
+
+impl LsmEngine {
+    // the directory is scratch space, anything left in it is removed
+    pub(crate) fn open(
+        dir: PathBuf,
+        hot_capacity: usize,
+        memtable_size: usize,
+    ) -> io::Result<Self> {
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        Ok(LsmEngine {
+            dir,
+            hot: IndexMap::new(),
+            hot_capacity: hot_capacity.max(1),
+            memtable: BTreeMap::new(),
+            memtable_bytes: 0,
+            memtable_size,
+            next_flush: memtable_size,
+            tables: Vec::new(),
+            next_table: 1,
+            cold: 0,
+            cold_size: 0,
+            epoch: Instant::now(),
+            latest_access: 0,
+        })
+    }
+
+    // only tables whose bloom filter may hold the key are read
+    fn cold_get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        if self.cold == 0 {
+            return Ok(None);
+        }
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(value.clone());
+        }
+        let hash = key_hash(key);
+        for table in self.tables.iter().rev() {
+            if !table.bloom.may_contain(hash) {
+                continue;
+            }
+            if let Some(value) = table.get(key)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    fn take_cold(&mut self, key: &str) -> io::Result<Option<StoredData>> {
+        let Some(bytes) = self.cold_get(key)? else {
+            return Ok(None);
+        };
+        self.put(key.to_string(), None);
+        let data = decode(key, &bytes, self.epoch);
+        self.cold -= 1;
+        self.cold_size = self.cold_size.saturating_sub(data.size);
+        Ok(Some(data))
+    }
+
+    // a memtable that could not be written out stays in memory and is tried again later
+    fn put(&mut self, key: String, value: Option<Vec<u8>>) {
+        self.memtable_bytes += key.len() + value.as_ref().map_or(0, |v| v.len()) + 9;
+        self.memtable.insert(key, value);
+        if self.memtable_bytes < self.next_flush {
+            return;
+        }
+        match self.flush() {
+            Ok(()) => self.next_flush = self.memtable_size,
+            Err(e) => {
+                eprintln!("error writing the storage engine: {e}");
+                self.next_flush = self.memtable_bytes + self.memtable_size;
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let path = self.table_path();
+        let records = self
+            .memtable
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), value.clone())));
+        let table = Table::write(&path, records)?;
+        self.memtable.clear();
+        self.memtable_bytes = 0;
+        self.tables.extend(table);
+        if self.tables.len() > MAX_TABLES {
+            // the tables stay as they are until a later flush merges them
+            if let Err(e) = self.compact() {
+                eprintln!("error merging the storage engine tables: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    // merges every table into one, deletions have nothing left to hide afterwards
+    fn compact(&mut self) -> io::Result<()> {
+        let sources = self
+            .tables
+            .iter()
+            .rev()
+            .map(|table| {
+                table
+                    .iter()
+                    .map(|iter| Box::new(iter) as Box<dyn Iterator<Item = io::Result<Record>>>)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let path = self.table_path();
+        let merged = Table::write(
+            &path,
+            Merge::new(sources).filter(|record| !matches!(record, Ok((_, None)))),
+        )?;
+        for table in self.tables.drain(..) {
+            let _ = std::fs::remove_file(&table.path);
+        }
+        self.tables.extend(merged);
+        Ok(())
+    }
+
+    fn table_path(&mut self) -> PathBuf {
+        self.next_table += 1;
+        self.dir.join(format!("{:08}.sst", self.next_table - 1))
+    }
+
+    // writes the least recently used keys but `keep` to disk once memory holds too many
+    fn demote(&mut self, keep: &str) {
+        if self.hot.len() <= self.hot_capacity {
+            return;
+        }
+        let target = self.hot_capacity - self.hot_capacity / 4;
+        let mut candidates = self
+            .hot
+            .iter()
+            .filter(|(key, _)| key.as_str() != keep)
+            .map(|(key, data)| (data.last_access(), key.clone()))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+        for (_, key) in candidates.into_iter().take(self.hot.len() - target) {
+            let mut data = self.hot.swap_remove(&key).unwrap();
+            // the expiry task stays scheduled, it loads the key back when it is due
+            data.expiry.take();
+            let bytes = encode(&data, self.epoch);
+            self.put(key, Some(bytes));
+            self.cold += 1;
+            self.cold_size += data.size;
+        }
+    }
+}
+
+impl super::storage::StorageEngine for LsmEngine {
+    fn get(&self, key: &str) -> Option<&StoredData> {
+        self.hot.get(key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut StoredData> {
+        self.hot.get_mut(key)
+    }
+
+    fn insert(&mut self, key: String, data: StoredData) -> io::Result<Option<StoredData>> {
+        let old = if self.hot.contains_key(&key) {
+            None
+        } else {
+            self.take_cold(&key)?
+        };
+        self.latest_access = self.latest_access.max(data.last_access());
+        if let Some(old) = self.hot.insert(key.clone(), data) {
+            return Ok(Some(old));
+        }
+        self.demote(&key);
+        Ok(old)
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<Option<StoredData>> {
+        match self.hot.swap_remove(key) {
+            Some(old) => Ok(Some(old)),
+            None => self.take_cold(key),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.hot.clear();
+        self.memtable.clear();
+        self.memtable_bytes = 0;
+        for table in self.tables.drain(..) {
+            let _ = std::fs::remove_file(&table.path);
+        }
+        self.cold = 0;
+        self.cold_size = 0;
+    }
+
+    fn sample(&self) -> Option<(&String, &StoredData)> {
+        super::storage::sample(&self.hot)
+    }
+
+    // cold keys are read from the tables one at a time, never all at once
+    fn scan(&self, f: &mut dyn FnMut(&str, &StoredData)) -> io::Result<()> {
+        self.hot.iter().for_each(|(key, data)| f(key, data));
+        let mut sources: Vec<Box<dyn Iterator<Item = io::Result<Record>>>> = vec![Box::new(
+            self.memtable
+                .iter()
+                .map(|(key, value)| Ok((key.clone(), value.clone()))),
+        )];
+        for table in self.tables.iter().rev() {
+            sources.push(Box::new(table.iter()?));
+        }
+        for record in Merge::new(sources) {
+            if let (key, Some(bytes)) = record? {
+                f(&key, &decode(&key, &bytes, self.epoch));
+            }
+        }
+        Ok(())
+    }
+
+    fn cold_size(&self) -> usize {
+        self.cold_size
+    }
+
+    fn is_cold(&self, key: &str) -> io::Result<bool> {
+        Ok(!self.hot.contains_key(key) && self.cold_get(key)?.is_some())
+    }
+
+    fn load(&mut self, key: &str) -> io::Result<()> {
+        if self.hot.contains_key(key) {
+            return Ok(());
+        }
+        if let Some(data) = self.take_cold(key)? {
+            self.hot
+                .insert(key.to_string(), data.created_at(self.latest_access));
+            self.demote(key);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LsmEngine {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// a sorted, immutable file of records
+struct Table {
+    path: PathBuf,
+    file: Mutex<File>,
+    // the key and offset of every INDEX_INTERVAL-th record
+    index: Vec<(String, u64)>,
+    bloom: Bloom,
+    len: u64,
+}
+
+impl Table {
+    // None when there was nothing to write, the file is removed when writing it fails
+    fn write(
+        path: &Path,
+        records: impl Iterator<Item = io::Result<Record>>,
+    ) -> io::Result<Option<Table>> {
+        let mut index = Vec::new();
+        let mut hashes = Vec::new();
+        let mut len = 0;
+        let write = || -> io::Result<()> {
+            let mut out = BufWriter::new(File::create(path)?);
+            for (i, record) in records.enumerate() {
+                let (key, value) = record?;
+                if i % INDEX_INTERVAL == 0 {
+                    index.push((key.clone(), len));
+                }
+                hashes.push(key_hash(&key));
+                len += write_record(&mut out, &key, value.as_deref())?;
+            }
+            out.into_inner()?.sync_data()
+        };
+        if let Err(e) = write() {
+            let _ = std::fs::remove_file(path);
+            return Err(e);
+        }
+        if index.is_empty() {
+            std::fs::remove_file(path)?;
+            return Ok(None);
+        }
+        Ok(Some(Table {
+            path: path.to_path_buf(),
+            file: Mutex::new(File::open(path)?),
+            index,
+            bloom: Bloom::new(&hashes),
+            len,
+        }))
+    }
+
+    // Some(None) when the table holds a deletion of the key
+    fn get(&self, key: &str) -> io::Result<Option<Option<Vec<u8>>>> {
+        let block = self
+            .index
+            .partition_point(|(first, _)| first.as_str() <= key);
+        let Some(block) = block.checked_sub(1) else {
+            return Ok(None);
+        };
+        let start = self.index[block].1;
+        let end = self
+            .index
+            .get(block + 1)
+            .map_or(self.len, |(_, offset)| *offset);
+        let mut bytes = vec![0; (end - start) as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut bytes)?;
+        }
+        let mut reader = bytes.as_slice();
+        while let Some((found, value)) = read_record(&mut reader)? {
+            if found == key {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    // ends after the first error
+    fn iter(&self) -> io::Result<impl Iterator<Item = io::Result<Record>>> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut failed = false;
+        Ok(std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+            let record = read_record(&mut reader).transpose();
+            failed = matches!(record, Some(Err(_)));
+            record
+        }))
+    }
+}
+
+// which keys a table may hold, so looking up keys it doesn't hold skips reading it
+struct Bloom {
+    bits: Vec<u64>,
+}
+
+impl Bloom {
+    fn new(hashes: &[u64]) -> Self {
+        let mut bits = vec![0u64; (hashes.len() * BLOOM_BITS_PER_KEY).div_ceil(64).max(1)];
+        for hash in hashes {
+            for bit in positions(bits.len(), *hash) {
+                bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        Bloom { bits }
+    }
+
+    fn may_contain(&self, hash: u64) -> bool {
+        positions(self.bits.len(), hash).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+// the bits of a key in a filter of `words` u64s, by double hashing over the halves of its hash
+fn positions(words: usize, hash: u64) -> impl Iterator<Item = usize> {
+    let len = words as u64 * 64;
+    let (low, high) = (hash & 0xFFFF_FFFF, (hash >> 32) | 1);
+    (0..BLOOM_HASHES).map(move |i| (low.wrapping_add(i.wrapping_mul(high)) % len) as usize)
+}
+
+fn key_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// each key once, from the first source holding it; sources are sorted and newest first.
+// A read error is passed on as soon as a source runs into it
+struct Merge<'a> {
+    sources: Vec<Peekable<Box<dyn Iterator<Item = io::Result<Record>> + 'a>>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(sources: Vec<Box<dyn Iterator<Item = io::Result<Record>> + 'a>>) -> Self {
+        Merge {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        let mut key: Option<String> = None;
+        for source in &mut self.sources {
+            match source.peek() {
+                Some(Err(_)) => return source.next(),
+                Some(Ok((next, _))) if key.as_ref().is_none_or(|key| next < key) => {
+                    key = Some(next.clone())
+                }
+                _ => {}
+            }
+        }
+        let key = key?;
+        let mut newest = None;
+        for source in &mut self.sources {
+            if matches!(source.peek(), Some(Ok((next, _))) if *next == key) {
+                let record = source.next();
+                newest = newest.or(record);
+            }
+        }
+        newest
+    }
+}
+
+// the key length (u32 LE) and key, then 0 for a deletion or 1 and the length-prefixed entry
+fn write_record(out: &mut impl Write, key: &str, value: Option<&[u8]>) -> io::Result<u64> {
+    out.write_all(&(key.len() as u32).to_le_bytes())?;
+    out.write_all(key.as_bytes())?;
+    let Some(value) = value else {
+        out.write_all(&[0])?;
+        return Ok(4 + key.len() as u64 + 1);
+    };
+    out.write_all(&[1])?;
+    out.write_all(&(value.len() as u32).to_le_bytes())?;
+    out.write_all(value)?;
+    Ok(4 + key.len() as u64 + 5 + value.len() as u64)
+}
+
+// None at the end of the input
+fn read_record(input: &mut impl Read) -> io::Result<Option<Record>> {
+    let mut len = [0; 4];
+    match input.read_exact(&mut len) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let key = read_bytes(input, u32::from_le_bytes(len) as usize)?;
+    let key = String::from_utf8(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut kind = [0; 1];
+    input.read_exact(&mut kind)?;
+    if kind[0] == 0 {
+        return Ok(Some((key, None)));
+    }
+    input.read_exact(&mut len)?;
+    let value = read_bytes(input, u32::from_le_bytes(len) as usize)?;
+    Ok(Some((key, Some(value))))
+}
+
+fn read_bytes(input: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+// the deadline as nanoseconds from the epoch with a sign byte, then the value
+fn encode(data: &StoredData, epoch: Instant) -> Vec<u8> {
+    let mut out = Vec::new();
+    match data.ttl {
+        None => out.push(0),
+        Some(ttl) if ttl >= epoch => {
+            out.push(1);
+            out.extend_from_slice(&(ttl.duration_since(epoch).as_nanos() as u64).to_le_bytes());
+        }
+        Some(ttl) => {
+            out.push(2);
+            out.extend_from_slice(&(epoch.duration_since(ttl).as_nanos() as u64).to_le_bytes());
+        }
+    }
+    let put = |out: &mut Vec<u8>, s: &str| {
+        out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+    };
+    match &data.value {
+        Value::Str(value) => {
+            out.push(0);
+            put(&mut out, value);
+        }
+        Value::Packed(packed) => {
+            out.push(2);
+            out.push(packed.codec.id());
+            out.extend_from_slice(&(packed.data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&packed.data);
+        }
+        Value::List(list) => {
+            out.push(1);
+            out.extend_from_slice(&(list.len() as u32).to_le_bytes());
+            list.iter().for_each(|item| put(&mut out, item));
+        }
+    }
+    out
+}
+
+// entries were encoded by this process, so they are trusted
+fn decode(key: &str, mut bytes: &[u8], epoch: Instant) -> StoredData {
+    let mut take = |n: usize| {
+        let (head, rest) = bytes.split_at(n);
+        bytes = rest;
+        head
+    };
+    let u32_at = |b: &[u8]| u32::from_le_bytes(b.try_into().unwrap()) as usize;
+    let ttl = match take(1)[0] {
+        0 => None,
+        sign => {
+            let nanos = Duration::from_nanos(u64::from_le_bytes(take(8).try_into().unwrap()));
+            Some(if sign == 1 {
+                epoch + nanos
+            } else {
+                epoch - nanos
+            })
+        }
+    };
+    let kind = take(1)[0];
+    let value = if kind == 0 {
+        let len = u32_at(take(4));
+        Value::Str(String::from_utf8_lossy(take(len)).into_owned())
+    } else if kind == 2 {
+        let codec = Codec::from_id(take(1)[0]).unwrap_or(Codec::None);
+        let len = u32_at(take(4));
+        Value::Packed(Packed {
+            codec,
+            data: take(len).to_vec(),
+        })
+    } else {
+        let count = u32_at(take(4));
+        let mut list = VecDeque::with_capacity(count);
+        for _ in 0..count {
+            let len = u32_at(take(4));
+            list.push_back(String::from_utf8_lossy(take(len)).into_owned());
+        }
+        Value::List(list)
+    };
+    let mut data = StoredData::new(value);
+    data.ttl = ttl;
+    data.size = data.estimate_size(key);
+    data
+}
@@ -1,3 +1,20 @@
+pub mod capture;
+pub mod clock;
 pub mod command_handler;
+pub mod compression;
+pub mod encryption;
+pub mod eviction;
+pub mod keyspace;
+pub mod log_format;
+pub mod lsm;
+pub mod ndjson;
 pub mod persistence_service;
+pub mod rdb;
+pub mod recovery;
+pub mod rewrite;
+pub mod session;
+pub mod snapshot;
+pub mod storage;
+pub mod store;
 pub mod timer_service;
+pub mod wal;
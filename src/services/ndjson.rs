@@ -0,0 +1,207 @@
+use crate::app_server::parser::Command;
+use crate::app_server::reply::Reply;
+use crate::connector::connector::Connector;
+use crate::embedded::Db;
+use crate::services::snapshot::{dump_value, restore_value};
+use crate::services::store::Value;
+
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::future::Future;
+use std::io::{BufRead, Write};
+
+// one key per line, e.g.
+// {"db":0,"key":"name","type":"string","value":"kvds","pttl":120000}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    #[serde(default)]
+    pub db: usize,
+    pub key: String,
+    #[serde(flatten)]
+    pub value: EntryValue,
+    // milliseconds left when exported, absent for keys without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pttl: Option<u64>,
+    // the same in seconds, still read from files written before `pttl`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum EntryValue {
+    String(String),
+    List(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtlMode {
+    // the exported TTL counts again from the import
+    Keep,
+    // every imported key is persistent
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ImportOptions {
+    // overwrite keys that already exist instead of skipping them
+    pub replace: bool,
+    pub ttl: TtlMode,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions {
+            replace: false,
+            ttl: TtlMode::Keep,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportStats {
+    pub imported: usize,
+    // keys that already existed and were kept
+    pub skipped: usize,
+}
+
+impl Display for ImportStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "imported {} keys", self.imported)?;
+        if self.skipped > 0 {
+            write!(f, ", skipped {} existing keys", self.skipped)?;
+        }
+        Ok(())
+    }
+}
+
+// where keys are exported from and imported into: the data files through an embedded
+// handle, or a running server
+pub trait Client {
+    fn call(&self, cmd: Command) -> impl Future<Output = Reply>;
+}
+
+impl Client for Db {
+    fn call(&self, cmd: Command) -> impl Future<Output = Reply> {
+        self.execute(cmd)
+    }
+}
+
+impl Client for Connector {
+    async fn call(&self, cmd: Command) -> Reply {
+        Connector::call(self, cmd)
+    }
+}
+
+// every key of every database, returns how many were written. Like `import` this leaves
+// database 0 selected
+pub async fn export<C: Client>(client: &C, out: &mut impl Write) -> Result<usize, String> {
+    let mut count = 0;
+    // selecting past the last database fails, which ends the export
+    for db in 0.. {
+        if let Reply::Error(_) = client.call(Command::cmd_select(db)).await {
+            break;
+        }
+        let keys = match client.call(Command::cmd_keys("*")).await {
+            Reply::Array(keys) => keys,
+            other => return Err(format!("unexpected reply to KEYS: {other}")),
+        };
+        for key in keys {
+            let Reply::Bulk(Some(key)) = key else {
+                continue;
+            };
+            if let Some(entry) = export_key(client, db, key).await? {
+                serde_json::to_writer(&mut *out, &entry).map_err(|e| e.to_string())?;
+                writeln!(out).map_err(|e| e.to_string())?;
+                count += 1;
+            }
+        }
+    }
+    client.call(Command::cmd_select(0)).await;
+    Ok(count)
+}
+
+// None when the key expired since it was listed
+async fn export_key<C: Client>(
+    client: &C,
+    db: usize,
+    key: String,
+) -> Result<Option<Entry>, String> {
+    let payload = match client.call(Command::cmd_dump(&key)).await {
+        Reply::Bulk(Some(payload)) => payload,
+        Reply::Bulk(None) => return Ok(None),
+        other => return Err(format!("unexpected reply to DUMP {key}: {other}")),
+    };
+    let value = hex::decode(&payload)
+        .ok()
+        .and_then(|payload| restore_value(&payload))
+        .ok_or(format!("cannot read the DUMP payload of {key}"))?;
+    let value = match value {
+        Value::Str(value) => EntryValue::String(value),
+        Value::Packed(packed) => EntryValue::String(packed.unpack()),
+        Value::List(items) => EntryValue::List(items.into()),
+    };
+    let pttl = match client.call(Command::cmd_pttl(&key)).await {
+        Reply::Integer(-2) => return Ok(None),
+        Reply::Integer(pttl) => u64::try_from(pttl).ok(),
+        other => return Err(format!("unexpected reply to PTTL {key}: {other}")),
+    };
+    Ok(Some(Entry {
+        db,
+        key,
+        value,
+        pttl,
+        ttl: None,
+    }))
+}
+
+pub async fn import<C: Client>(
+    client: &C,
+    input: impl BufRead,
+    options: ImportOptions,
+) -> Result<ImportStats, String> {
+    let mut stats = ImportStats::default();
+    let mut selected = None;
+    for (n, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry =
+            serde_json::from_str(&line).map_err(|e| format!("line {}: {e}", n + 1))?;
+        if selected != Some(entry.db) {
+            if let Reply::Error(e) = client.call(Command::cmd_select(entry.db)).await {
+                return Err(format!("line {}: {e}", n + 1));
+            }
+            selected = Some(entry.db);
+        }
+        let value = match entry.value {
+            EntryValue::String(value) => Value::Str(value),
+            EntryValue::List(items) => Value::List(items.into()),
+        };
+        let pttl = match entry.pttl {
+            Some(pttl) => Some(pttl),
+            None => entry
+                .ttl
+                .map(|ttl| {
+                    ttl.checked_mul(1000)
+                        .ok_or(format!("line {}: TTL out of range", n + 1))
+                })
+                .transpose()?,
+        };
+        // RESTORE takes 0 for no TTL, so a key that was about to expire gets 1 ms
+        let ttl = match (options.ttl, pttl) {
+            (TtlMode::Keep, Some(pttl)) => pttl.max(1),
+            _ => 0,
+        };
+        let payload = hex::encode(dump_value(&value));
+        let restore = Command::cmd_restore(&entry.key, ttl, &payload, options.replace);
+        match client.call(restore).await {
+            Reply::Error(e) if e.starts_with("BUSYKEY") => stats.skipped += 1,
+            Reply::Error(e) => return Err(format!("line {}: {e}", n + 1)),
+            _ => stats.imported += 1,
+        }
+    }
+    client.call(Command::cmd_select(0)).await;
+    Ok(stats)
+}
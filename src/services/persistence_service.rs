@@ -1,62 +1,626 @@
-use crate::app_server::parser::{parse_command, Command};
-use crate::services::command_handler::handle_on_memory;
-use crate::SETTING;
-use once_cell::sync::Lazy;
-use std::io::{Read, Write};
-use std::{
-    fs::{File, OpenOptions},
-    sync::RwLock,
+use crate::app_server::parser::Command;
+use crate::services::clock::Clock;
+use crate::services::compression::Codec;
+use crate::services::encryption::Keys;
+use crate::services::log_format::{
+    check_log, encode_record, encode_timestamp, pack_record, Corruption, LogCheck, Position,
 };
-use tokio::sync::mpsc::{self, Sender};
-
-static DB_FILE: Lazy<RwLock<File>> = Lazy::new(|| {
-    let file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .read(true)
-        .open(SETTING.db_file.clone())
-        .expect("error in read or create DB file!");
-    RwLock::new(file)
-});
-
-static QUEUE: Lazy<Sender<String>> = Lazy::new(|| {
-    let (tx, mut rx) = mpsc::channel::<String>(10_000);
-    tokio::spawn(async move {
-        while let Some(mut message) = rx.recv().await {
-            message.push_str("\r\n");
-            let _ = DB_FILE.write().unwrap().write(message.as_bytes());
-        }
-    });
-    tx
-});
-
-pub async fn persist_log(cmd: &Command) {
-    QUEUE
-        .send(cmd.to_string().replace("\r\n", "\\r\\n"))
+use crate::services::recovery::RecoveryTarget;
+use crate::services::session::Session;
+use crate::services::snapshot::read_snapshot;
+use crate::services::store::Store;
+use crate::services::wal::{open_segment, segment_path, Manifest, SnapshotPoint};
+use crate::Settings;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AppendFsync {
+    // the reply waits until the entry is on disk
+    Always,
+    Everysec,
+    // flushing is left to the operating system
+    No,
+}
+
+enum LogOp {
+    Command { db: usize, line: Vec<u8> },
+    // sets a segment id aside for the rewritten log and moves on to a new segment
+    StartRewrite,
+    // moves the rewritten file into the set aside segment and replays from there
+    FinishRewrite { path: PathBuf },
+    AbortRewrite,
+}
+
+struct LogEntry {
+    op: LogOp,
+    done: Option<oneshot::Sender<Result<(), String>>>,
+}
+
+// the segments and the one being appended to
+struct Wal {
+    dir: PathBuf,
+    manifest: Manifest,
+    active: File,
+    opened: Instant,
+    // the id set aside for the segment a running rewrite produces, and when it started
+    reserved: Option<SnapshotPoint>,
+    // after a recovery, the first segment logged past its target
+    set_aside: Option<u64>,
+}
+
+impl Wal {
+    // a single log file from before segments becomes the first segment
+    fn open(dir: &Path, legacy_file: &str) -> io::Result<Wal> {
+        std::fs::create_dir_all(dir)?;
+        let manifest = match Manifest::read(dir)? {
+            Some(manifest) => manifest,
+            None => {
+                let manifest = Manifest::new();
+                let legacy = Path::new(legacy_file);
+                let migrate = legacy.metadata().is_ok_and(|m| m.is_file() && m.len() > 0);
+                if migrate {
+                    std::fs::copy(legacy, segment_path(dir, 1))?;
+                }
+                manifest.write(dir)?;
+                if migrate {
+                    std::fs::remove_file(legacy)?;
+                }
+                manifest
+            }
+        };
+        Ok(Wal {
+            dir: dir.to_path_buf(),
+            active: open_segment(dir, manifest.active())?,
+            manifest,
+            opened: Instant::now(),
+            reserved: None,
+            set_aside: None,
+        })
+    }
+
+    fn next_id(&self) -> u64 {
+        let reserved = self.reserved.map_or(0, |r| r.segment);
+        self.manifest.active().max(reserved) + 1
+    }
+
+    // closes the active segment, it is never written again
+    fn rotate(&mut self) -> io::Result<()> {
+        self.active.sync_data()?;
+        let id = self.next_id();
+        let file = open_segment(&self.dir, id)?;
+        self.manifest.segments.push(id);
+        self.manifest.write(&self.dir)?;
+        self.active = file;
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    // replay starts from `snapshot` from now on, older segments past `keep` are deleted. After
+    // a recovery the segments past its target leave the log for a directory of their own
+    fn rebase(&mut self, snapshot: SnapshotPoint, keep: usize) -> io::Result<()> {
+        self.manifest.base = snapshot.segment;
+        self.manifest.snapshots.push(snapshot);
+        let aside = match self.set_aside.take() {
+            Some(from) => self.manifest.remove(from..snapshot.segment),
+            None => Vec::new(),
+        };
+        let dropped = self.manifest.retain(keep);
+        self.manifest.write(&self.dir)?;
+        if !aside.is_empty() {
+            let to = self.dir.join(format!("past-recovery-{}", snapshot.unix_ms));
+            std::fs::create_dir_all(&to)?;
+            for id in aside {
+                let from = segment_path(&self.dir, id);
+                std::fs::rename(&from, to.join(from.file_name().unwrap()))?;
+            }
+        }
+        for id in dropped {
+            let _ = std::fs::remove_file(segment_path(&self.dir, id));
+        }
+        Ok(())
+    }
+
+    fn live_size(&self) -> u64 {
+        self.manifest
+            .live()
+            .filter_map(|id| std::fs::metadata(segment_path(&self.dir, id)).ok())
+            .map(|m| m.len())
+            .sum()
+    }
+}
+
+// shared between the handle and the writer thread
+struct LogState {
+    dir: PathBuf,
+    // timestamps the log so an earlier state can be recovered
+    clock: Arc<dyn Clock>,
+    // records are compressed, then sealed with the current key as they are written
+    codec: Codec,
+    keys: Arc<Keys>,
+    wal: Mutex<Wal>,
+    fsync: AppendFsync,
+    // 0 disables rotation by size or age
+    segment_size: u64,
+    segment_age: Duration,
+    keep_segments: usize,
+    // the last write or fsync error, cleared by the next successful one
+    error: Mutex<Option<String>>,
+    // of the segments replayed at startup
+    size: AtomicU64,
+    // the size right after the last rewrite, or at startup
+    base_size: AtomicU64,
+    rewriting: AtomicBool,
+}
+
+pub struct Persistence {
+    state: Arc<LogState>,
+    queue: OnceCell<Sender<LogEntry>>,
+}
+
+impl Persistence {
+    pub fn open(settings: &Settings, clock: Arc<dyn Clock>, keys: Arc<Keys>) -> Self {
+        let dir = PathBuf::from(&settings.wal_dir);
+        let wal = Wal::open(&dir, &settings.db_file).expect("error in read or create the log!");
+        let size = wal.live_size();
+        Persistence {
+            state: Arc::new(LogState {
+                dir,
+                clock,
+                codec: settings.compression,
+                keys,
+                wal: Mutex::new(wal),
+                fsync: settings.appendfsync,
+                segment_size: settings.wal_segment_size,
+                segment_age: Duration::from_secs(settings.wal_segment_seconds),
+                keep_segments: settings.wal_keep_segments,
+                error: Mutex::new(None),
+                size: AtomicU64::new(size),
+                base_size: AtomicU64::new(size),
+                rewriting: AtomicBool::new(false),
+            }),
+            queue: OnceCell::new(),
+        }
+    }
+
+    // the writer is started on first use. Its writes and fsyncs block, so it runs on a thread
+    // of its own instead of holding up a worker of the runtime serving clients
+    fn queue(&self) -> &Sender<LogEntry> {
+        self.queue.get_or_init(|| {
+            let (tx, rx) = mpsc::channel::<LogEntry>(10_000);
+            let state = self.state.clone();
+            std::thread::Builder::new()
+                .name("log-writer".to_string())
+                .spawn(move || {
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_time()
+                        .build()
+                        .expect("error in start the log writer!")
+                        .block_on(write_log(state, rx))
+                })
+                .expect("error in start the log writer!");
+            tx
+        })
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.state.error.lock().unwrap().clone()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.state.dir
+    }
+
+    pub fn manifest(&self) -> Manifest {
+        self.state.wal.lock().unwrap().manifest.clone()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.state.size.load(Ordering::Relaxed)
+    }
+
+    pub fn base_size(&self) -> u64 {
+        self.state.base_size.load(Ordering::Relaxed)
+    }
+
+    pub fn rewrite_in_progress(&self) -> bool {
+        self.state.rewriting.load(Ordering::SeqCst)
+    }
+
+    // with `always` this returns once the entry is fsynced, otherwise once it is queued;
+    // either way a failing log file refuses the write instead of losing it silently
+    async fn send(&self, op: LogOp) -> Result<(), String> {
+        if self.state.fsync != AppendFsync::Always {
+            if let Some(e) = self.last_error() {
+                return Err(e);
+            }
+            return self
+                .queue()
+                .send(LogEntry { op, done: None })
+                .await
+                .map_err(|_| "log writer stopped".to_string());
+        }
+        self.request(op).await
+    }
+
+    async fn request(&self, op: LogOp) -> Result<(), String> {
+        let (done, result) = oneshot::channel();
+        self.queue()
+            .send(LogEntry {
+                op,
+                done: Some(done),
+            })
+            .await
+            .map_err(|_| "log writer stopped".to_string())?;
+        result
+            .await
+            .unwrap_or_else(|_| Err("log writer stopped".to_string()))
+    }
+
+    pub async fn persist_log(&self, db: usize, cmd: &Command) -> Result<(), String> {
+        self.send(LogOp::Command {
+            db,
+            line: encode_record(cmd),
+        })
         .await
-        .expect("error sending log to queue!");
+    }
+
+    // replays the segments from the base on, which starts with a snapshot preamble after a
+    // rewrite. A torn last record of the active segment, as left by a crash during a write,
+    // is cut off unless the `aof_load_truncated` setting is off; corruption anywhere else
+    // always fails. With `recover_to` set replay starts from the newest snapshot before the
+    // target instead, stops at it, and the recovered state becomes the new base
+    pub async fn load_data(&self, store: &Store) -> Result<(), String> {
+        let target = match store.settings().recover_to.as_str() {
+            "" => None,
+            target => Some(target.parse::<RecoveryTarget>()?),
+        };
+        let manifest = self.manifest();
+        let start = match &target {
+            Some(target) => target.start(&manifest)?,
+            None => manifest.base,
+        };
+        let active = manifest.active();
+        let mut session = Session::default();
+        let keys = self.state.keys.get();
+        let mut legacy_records = 0;
+        let mut stale_records = 0;
+        let mut stopped = None;
+        for id in manifest.replayed_from(start) {
+            let path = segment_path(&self.state.dir, id);
+            let stored_data = std::fs::read(&path).map_err(|e| e.to_string())?;
+            let check = check_log(&stored_data, id, keys.as_deref());
+            if let Some(corruption) = &check.corruption {
+                let reason = bad_record(&path, corruption);
+                if id != active || !corruption.at_tail || !store.settings().aof_load_truncated {
+                    return Err(reason);
+                }
+                eprintln!("{reason}, truncating it to {} bytes", check.valid_len);
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|file| file.set_len(check.valid_len as u64))
+                    .map_err(|e| e.to_string())?;
+            }
+            legacy_records += check.legacy_records;
+            stale_records += check.stale_records;
+            if !replay(store, &mut session, id, &stored_data, check, target).await? {
+                stopped = Some(id);
+                break;
+            }
+        }
+        let size = self.state.wal.lock().unwrap().live_size();
+        self.state.size.store(size, Ordering::Relaxed);
+        self.state.base_size.store(size, Ordering::Relaxed);
+
+        if let Some(target) = target {
+            println!("recovered the state as of {target}");
+            // what was logged past the target is moved aside by the rewrite, never deleted
+            self.state.wal.lock().unwrap().set_aside = stopped;
+            store.rewrite_log().await?;
+        } else if legacy_records > 0 {
+            // files from before the binary record format are migrated by rewriting them
+            println!("migrating {legacy_records} log records to the current format");
+            store.rewrite_log().await?;
+        } else if stale_records > 0 {
+            // records sealed with a retired key, or when migrating unencrypted ones or ones
+            // sealed in an older format, are sealed again
+            println!("encrypting {stale_records} log records with the current key");
+            store.rewrite_log().await?;
+        }
+        Ok(())
+    }
+
+    // false when a rewrite is already running
+    pub(crate) fn try_begin_rewrite(&self) -> bool {
+        !self.state.rewriting.swap(true, Ordering::SeqCst)
+    }
+
+    pub(crate) fn rewrite_path(&self) -> PathBuf {
+        self.state.dir.join("rewrite.tmp")
+    }
+
+    // the segment the rewritten log becomes
+    pub(crate) async fn start_rewrite(&self) -> Result<u64, String> {
+        self.request(LogOp::StartRewrite).await?;
+        let wal = self.state.wal.lock().unwrap();
+        wal.reserved
+            .map(|reserved| reserved.segment)
+            .ok_or("no rewrite in progress".to_string())
+    }
+
+    pub(crate) async fn finish_rewrite(&self, written: Result<(), String>) -> Result<(), String> {
+        let result = match written {
+            Ok(()) => {
+                self.request(LogOp::FinishRewrite {
+                    path: self.rewrite_path(),
+                })
+                .await
+            }
+            Err(e) => {
+                let _ = self.request(LogOp::AbortRewrite).await;
+                Err(e)
+            }
+        };
+        if result.is_err() {
+            let _ = std::fs::remove_file(self.rewrite_path());
+        }
+        self.state.rewriting.store(false, Ordering::SeqCst);
+        result
+    }
+}
+
+// replays the log like `load_data` without changing any file, for reading the data of a
+// stopped server: a log from before segments is read where it is, and a torn tail is
+// skipped instead of cut off
+pub(crate) async fn read_log(store: &Store, dir: &Path, legacy_file: &Path) -> Result<(), String> {
+    let (segments, active) = match Manifest::read(dir).map_err(|e| e.to_string())? {
+        Some(manifest) => (
+            manifest
+                .replayed_from(manifest.base)
+                .map(|id| (id, segment_path(dir, id)))
+                .collect(),
+            manifest.active(),
+        ),
+        None if legacy_file.is_file() => (vec![(1, legacy_file.to_path_buf())], 1),
+        None => (Vec::new(), 1),
+    };
+    let keys = store.keys.get();
+    let mut session = Session::default();
+    for (id, path) in segments {
+        let data = std::fs::read(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let check = check_log(&data, id, keys.as_deref());
+        if let Some(corruption) = &check.corruption {
+            let reason = bad_record(&path, corruption);
+            if id != active || !corruption.at_tail || !store.settings().aof_load_truncated {
+                return Err(reason);
+            }
+            eprintln!("{reason}, skipping it");
+        }
+        replay(store, &mut session, id, &data, check, None).await?;
+    }
+    Ok(())
 }
 
-pub async fn load_data() {
-    let mut stored_data = String::new();
-    DB_FILE
-        .write()
-        .expect("error opening db file!")
-        .read_to_string(&mut stored_data)
-        .expect("error opening db file!");
+// restores the snapshot preamble of the segment `data` as it is read and applies its
+// commands, false once one is past `target`
+async fn replay(
+    store: &Store,
+    session: &mut Session,
+    id: u64,
+    data: &[u8],
+    mut check: LogCheck,
+    target: Option<RecoveryTarget>,
+) -> Result<bool, String> {
+    if check.preamble > 0 {
+        let at = Position {
+            segment: id,
+            offset: 0,
+        };
+        let keys = store.keys.get();
+        let mut preamble = &data[..check.preamble];
+        read_snapshot(
+            &mut preamble,
+            keys.as_deref(),
+            &at.aad(),
+            &mut store.restorer(),
+        )?;
+    }
+    let commands = std::mem::take(&mut check.commands);
+    for (index, cmd) in commands.into_iter().enumerate() {
+        if target.is_some_and(|target| !target.includes(id, &check, index)) {
+            return Ok(false);
+        }
+        store
+            .apply(session, cmd)
+            .await
+            .map_err(|e| format!("storage engine: {e}"))?;
+    }
+    Ok(true)
+}
+
+fn bad_record(path: &Path, corruption: &Corruption) -> String {
+    format!(
+        "bad log record at offset {} of {}: {}",
+        corruption.offset,
+        path.display(),
+        corruption.reason
+    )
+}
+
+fn unix_ms(state: &LogState) -> u64 {
+    state
+        .clock
+        .system_time()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
 
-    for row in stored_data.lines() {
-        let cmd = parse_command(row.replace("\\r\\n", "\r\n")).expect("error reading db rows!");
-        handle_on_memory(cmd).await;
+// everything already queued is written and fsynced together (group commit).
+// A SELECT record is written whenever the next command targets another database
+// than the last one, and at the start of every segment so each one replays on its own,
+// a timestamp record whenever the time moved on since the last one
+async fn write_log(state: Arc<LogState>, mut rx: Receiver<LogEntry>) {
+    let mut last_db = None;
+    let mut last_stamp = None;
+    let mut dirty = false;
+    let mut every_second = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        let first = tokio::select! {
+            entry = rx.recv() => match entry {
+                Some(entry) => entry,
+                None => return,
+            },
+            _ = every_second.tick() => {
+                let mut wal = state.wal.lock().unwrap();
+                // under `no` nothing else clears an error that refuses writes, so the log is
+                // probed again every second
+                let failed = state.error.lock().unwrap().is_some();
+                if failed && state.fsync == AppendFsync::No {
+                    let result = wal.active.sync_data().map_err(|e| e.to_string());
+                    record(&state.error, &result);
+                }
+                if dirty && state.fsync == AppendFsync::Everysec {
+                    dirty = false;
+                    let result = wal.active.sync_data().map_err(|e| e.to_string());
+                    record(&state.error, &result);
+                }
+                let written = wal.active.metadata().map_or(0, |m| m.len()) > 0;
+                if !state.segment_age.is_zero() && written && wal.opened.elapsed() >= state.segment_age {
+                    let result = wal.rotate().map_err(|e| e.to_string());
+                    record(&state.error, &result);
+                    last_db = None;
+                    last_stamp = None;
+                }
+                continue;
+            }
+        };
+        let mut batch = vec![first];
+        while let Ok(entry) = rx.try_recv() {
+            batch.push(entry);
+        }
+
+        let keys = state.keys.get();
+        let mut wal = state.wal.lock().unwrap();
+        let mut records = Vec::new();
+        let mut waiters = Vec::new();
+        let mut result = Ok(());
+        for entry in batch {
+            match entry.op {
+                LogOp::Command { db, line } => {
+                    let now = unix_ms(&state);
+                    if last_stamp != Some(now) {
+                        records.push(encode_timestamp(now));
+                        last_stamp = Some(now);
+                    }
+                    if last_db != Some(db) {
+                        records.push(encode_record(&Command::SELECT { db }));
+                        last_db = Some(db);
+                    }
+                    records.push(line);
+                }
+                LogOp::StartRewrite => {
+                    // pending entries go to the new segment, after the rewritten one
+                    wal.reserved = Some(SnapshotPoint {
+                        segment: wal.next_id(),
+                        unix_ms: unix_ms(&state),
+                    });
+                    result = wal.rotate();
+                    last_db = None;
+                    last_stamp = None;
+                }
+                LogOp::FinishRewrite { path } => {
+                    let finished = finish_rewrite(&state, &mut wal, &path);
+                    if let Some(done) = entry.done {
+                        let _ = done.send(finished.map_err(|e| e.to_string()));
+                    }
+                    continue;
+                }
+                LogOp::AbortRewrite => wal.reserved = None,
+            }
+            waiters.extend(entry.done);
+        }
+        // records are packed where they land, the active segment after any rotation above
+        let mut buf = Vec::new();
+        let mut result = result
+            .and_then(|_| wal.active.metadata())
+            .and_then(|metadata| {
+                let segment = wal.manifest.active();
+                for record in records {
+                    let at = Position {
+                        segment,
+                        offset: metadata.len() + buf.len() as u64,
+                    };
+                    buf.extend_from_slice(&pack_record(record, state.codec, keys.as_deref(), at));
+                }
+                // a write failing partway is cut back off, so the next batch lands where its
+                // positions say; the segment is opened for appending, so that is its end
+                wal.active.write_all(&buf).inspect_err(|_| {
+                    let _ = wal.active.set_len(metadata.len());
+                })
+            })
+            .map_err(|e| e.to_string());
+        if result.is_ok() {
+            state.size.fetch_add(buf.len() as u64, Ordering::Relaxed);
+        } else {
+            // the SELECT and timestamp records of the batch may be lost with it
+            last_db = None;
+            last_stamp = None;
+        }
+        dirty = true;
+        if result.is_ok() && state.fsync == AppendFsync::Always {
+            result = wal.active.sync_data().map_err(|e| e.to_string());
+            dirty = false;
+        }
+        let full = state.segment_size > 0
+            && wal.active.metadata().map_or(0, |m| m.len()) >= state.segment_size;
+        if result.is_ok() && full {
+            result = wal.rotate().map_err(|e| e.to_string());
+            last_db = None;
+            last_stamp = None;
+            dirty = false;
+        }
+        record(&state.error, &result);
+        for waiter in waiters {
+            let _ = waiter.send(result.clone());
+        }
     }
 }
 
-pub async fn clear_log_file() {
-    OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(SETTING.db_file.clone())
-        .expect("error in read or create DB file!");
+fn finish_rewrite(state: &LogState, wal: &mut Wal, path: &Path) -> io::Result<()> {
+    let Some(snapshot) = wal.reserved.take() else {
+        return Err(io::Error::other("no rewrite in progress"));
+    };
+    let id = snapshot.segment;
+    std::fs::rename(path, segment_path(&wal.dir, id))?;
+    let at = wal.manifest.segments.partition_point(|s| *s < id);
+    wal.manifest.segments.insert(at, id);
+    wal.rebase(snapshot, state.keep_segments)?;
+    let size = wal.live_size();
+    state.size.store(size, Ordering::Relaxed);
+    state.base_size.store(size, Ordering::Relaxed);
+    Ok(())
+}
 
-    let _ = DB_FILE.write().unwrap().sync_data();
+fn record(error: &Mutex<Option<String>>, result: &Result<(), String>) {
+    let mut error = error.lock().unwrap();
+    match result {
+        Ok(()) => *error = None,
+        Err(e) => {
+            if error.is_none() {
+                eprintln!("error writing the log file: {e}");
+            }
+            *error = Some(e.clone());
+        }
+    }
 }
@@ -0,0 +1,589 @@
+use crate::services::snapshot::{Entry, Snapshot};
+use crate::services::store::{Store, Value};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+
+const MAX_VERSION: u32 = 12;
+
+const OP_SLOT_INFO: u8 = 0xF4;
+const OP_FUNCTION2: u8 = 0xF5;
+const OP_FUNCTION_PRE_GA: u8 = 0xF6;
+const OP_MODULE_AUX: u8 = 0xF7;
+const OP_IDLE: u8 = 0xF8;
+const OP_FREQ: u8 = 0xF9;
+const OP_AUX: u8 = 0xFA;
+const OP_RESIZEDB: u8 = 0xFB;
+const OP_EXPIRETIME_MS: u8 = 0xFC;
+const OP_EXPIRETIME: u8 = 0xFD;
+const OP_SELECTDB: u8 = 0xFE;
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+// hashes with field expiries, from RDB 12
+const TYPE_HASH_METADATA_PRE_GA: u8 = 22;
+const TYPE_HASH_LISTPACK_EX_PRE_GA: u8 = 23;
+const TYPE_HASH_METADATA: u8 = 24;
+const TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+// the most a back reference expands to, 264 bytes out of 3
+const LZF_MAX_RATIO: usize = 88;
+
+// quicklist 2 nodes
+const NODE_PLAIN: u64 = 1;
+const NODE_PACKED: u64 = 2;
+
+// what an RDB import brought in and what kvds has no type for
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RdbImport {
+    pub keys: usize,
+    // keys skipped by their Redis type, or "binary" for keys or values that are not UTF-8
+    pub skipped: BTreeMap<&'static str, usize>,
+}
+
+impl std::fmt::Display for RdbImport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "imported {} keys", self.keys)?;
+        for (kind, count) in &self.skipped {
+            write!(f, ", skipped {count} {kind}")?;
+        }
+        Ok(())
+    }
+}
+
+enum RdbValue {
+    Str(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    // parsed but not imported
+    Unsupported(&'static str),
+}
+
+// keys with a deadline in the past are left to `restore` to drop. kvds has only strings and
+// lists, so sets, sorted sets, hashes and streams are read past in every encoding and counted as skipped
+pub(crate) fn read_rdb(data: &[u8]) -> Result<(Snapshot, RdbImport), String> {
+    let mut reader = Reader { data, pos: 0 };
+    if reader.take(5)? != b"REDIS" {
+        return Err("not an RDB file".to_string());
+    }
+    let version = std::str::from_utf8(reader.take(4)?)
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or("invalid RDB version")?;
+    if version > MAX_VERSION {
+        return Err(format!("unsupported RDB version {version}"));
+    }
+    let mut snapshot: Snapshot = vec![(0, Vec::new())];
+    let mut import = RdbImport::default();
+    let mut expires_at = None;
+    loop {
+        match reader.byte()? {
+            OP_EOF => break,
+            OP_SELECTDB => {
+                let db = reader.length()? as usize;
+                snapshot.push((db, Vec::new()));
+            }
+            OP_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            }
+            OP_SLOT_INFO => {
+                (0..3).try_for_each(|_| reader.length().map(|_| ()))?;
+            }
+            OP_AUX => {
+                reader.string()?;
+                reader.string()?;
+            }
+            OP_FUNCTION2 => {
+                reader.string()?;
+            }
+            OP_FREQ => {
+                reader.byte()?;
+            }
+            OP_IDLE => {
+                reader.length()?;
+            }
+            OP_EXPIRETIME => {
+                expires_at = Some(u32::from_le_bytes(reader.array()?) as u64 * 1000);
+            }
+            OP_EXPIRETIME_MS => expires_at = Some(u64::from_le_bytes(reader.array()?)),
+            OP_MODULE_AUX | OP_FUNCTION_PRE_GA => {
+                return Err("RDB files with modules or functions are not supported".to_string())
+            }
+            kind => {
+                let key = reader.string()?;
+                let value = read_value(&mut reader, kind)
+                    .map_err(|e| format!("{e} at key {:?}", String::from_utf8_lossy(&key)))?;
+                let expires_at = expires_at.take();
+                let value = match value {
+                    RdbValue::Unsupported(kind) => {
+                        *import.skipped.entry(kind).or_default() += 1;
+                        continue;
+                    }
+                    RdbValue::Str(value) => utf8(value).map(Value::Str),
+                    RdbValue::List(items) => items
+                        .into_iter()
+                        .map(utf8)
+                        .collect::<Option<VecDeque<_>>>()
+                        .map(Value::List),
+                };
+                let (Some(key), Some(value)) = (utf8(key), value) else {
+                    *import.skipped.entry("binary").or_default() += 1;
+                    continue;
+                };
+                import.keys += 1;
+                let (_, entries) = snapshot.last_mut().unwrap();
+                entries.push(Entry {
+                    key,
+                    value,
+                    expires_at,
+                });
+            }
+        }
+    }
+    // a CRC-64 of everything before it since version 5, 0 when it was disabled
+    let end = reader.pos;
+    if version >= 5 {
+        let checksum = u64::from_le_bytes(reader.array()?);
+        if checksum != 0 && checksum != crc64(&data[..end]) {
+            return Err("RDB checksum mismatch".to_string());
+        }
+    }
+    snapshot.retain(|(_, entries)| !entries.is_empty());
+    Ok((snapshot, import))
+}
+
+fn utf8(bytes: Vec<u8>) -> Option<String> {
+    String::from_utf8(bytes).ok()
+}
+
+fn read_value(reader: &mut Reader, kind: u8) -> Result<RdbValue, String> {
+    let value = match kind {
+        TYPE_STRING => RdbValue::Str(reader.string()?),
+        TYPE_LIST => {
+            let len = reader.length()?;
+            RdbValue::List(
+                (0..len)
+                    .map(|_| reader.string())
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+        TYPE_SET | TYPE_HASH | TYPE_ZSET | TYPE_ZSET_2 => {
+            let len = reader.length()?;
+            for _ in 0..len {
+                reader.string()?;
+                match kind {
+                    TYPE_HASH => {
+                        reader.string()?;
+                    }
+                    TYPE_ZSET => {
+                        // scores as text after their length, 253 to 255 stand for NaN and infinities
+                        let len = reader.byte()?;
+                        if len < 253 {
+                            reader.take(len as usize)?;
+                        }
+                    }
+                    TYPE_ZSET_2 => {
+                        reader.take(8)?;
+                    }
+                    _ => {}
+                }
+            }
+            RdbValue::Unsupported(type_name(kind))
+        }
+        TYPE_LIST_ZIPLIST => RdbValue::List(ziplist(&reader.string()?)?),
+        TYPE_LIST_QUICKLIST => {
+            let nodes = reader.length()?;
+            let mut items = Vec::new();
+            for _ in 0..nodes {
+                items.extend(ziplist(&reader.string()?)?);
+            }
+            RdbValue::List(items)
+        }
+        TYPE_LIST_QUICKLIST_2 => {
+            let nodes = reader.length()?;
+            let mut items = Vec::new();
+            for _ in 0..nodes {
+                match reader.length()? {
+                    NODE_PLAIN => items.push(reader.string()?),
+                    NODE_PACKED => items.extend(listpack(&reader.string()?)?),
+                    other => return Err(format!("unknown quicklist node container {other}")),
+                }
+            }
+            RdbValue::List(items)
+        }
+        TYPE_SET_INTSET => {
+            intset(&reader.string()?)?;
+            RdbValue::Unsupported("set")
+        }
+        TYPE_HASH_ZIPMAP => {
+            reader.string()?;
+            RdbValue::Unsupported("hash")
+        }
+        TYPE_ZSET_ZIPLIST | TYPE_HASH_ZIPLIST => {
+            ziplist(&reader.string()?)?;
+            RdbValue::Unsupported(type_name(kind))
+        }
+        TYPE_HASH_LISTPACK | TYPE_ZSET_LISTPACK | TYPE_SET_LISTPACK => {
+            listpack(&reader.string()?)?;
+            RdbValue::Unsupported(type_name(kind))
+        }
+        TYPE_HASH_METADATA | TYPE_HASH_METADATA_PRE_GA => {
+            // the earliest field expiry, the others are stored relative to it
+            if kind == TYPE_HASH_METADATA {
+                reader.take(8)?;
+            }
+            let len = reader.length()?;
+            for _ in 0..len {
+                reader.length()?;
+                reader.string()?;
+                reader.string()?;
+            }
+            RdbValue::Unsupported("hash")
+        }
+        TYPE_HASH_LISTPACK_EX | TYPE_HASH_LISTPACK_EX_PRE_GA => {
+            if kind == TYPE_HASH_LISTPACK_EX {
+                reader.take(8)?;
+            }
+            listpack(&reader.string()?)?;
+            RdbValue::Unsupported("hash")
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            stream(reader, kind)?;
+            RdbValue::Unsupported("stream")
+        }
+        other => return Err(format!("unsupported RDB value type {other}")),
+    };
+    Ok(value)
+}
+
+fn type_name(kind: u8) -> &'static str {
+    match kind {
+        TYPE_SET | TYPE_SET_INTSET | TYPE_SET_LISTPACK => "set",
+        TYPE_HASH | TYPE_HASH_ZIPMAP | TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => "hash",
+        _ => "zset",
+    }
+}
+
+// listpacks keyed by their master ID, the stream metadata, then the consumer groups with
+// their pending entries and consumers
+fn stream(reader: &mut Reader, kind: u8) -> Result<(), String> {
+    let nodes = reader.length()?;
+    for _ in 0..nodes {
+        reader.string()?;
+        listpack(&reader.string()?)?;
+    }
+    // the length and the last ID, then the first ID, the max deleted ID and the entries added
+    let fields = if kind == TYPE_STREAM_LISTPACKS { 3 } else { 8 };
+    (0..fields).try_for_each(|_| reader.length().map(|_| ()))?;
+    let groups = reader.length()?;
+    for _ in 0..groups {
+        reader.string()?;
+        let fields = if kind == TYPE_STREAM_LISTPACKS { 2 } else { 3 };
+        (0..fields).try_for_each(|_| reader.length().map(|_| ()))?;
+        // a raw 16 byte ID, the delivery time in milliseconds and the delivery count
+        let pending = reader.length()?;
+        for _ in 0..pending {
+            reader.take(24)?;
+            reader.length()?;
+        }
+        let consumers = reader.length()?;
+        for _ in 0..consumers {
+            reader.string()?;
+            // the seen time, and the active time since the third version
+            reader.take(if kind == TYPE_STREAM_LISTPACKS_3 {
+                16
+            } else {
+                8
+            })?;
+            let pending = reader.length()?;
+            for _ in 0..pending {
+                reader.take(16)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// entries after a 10 byte header, each after the length of the previous one, up to 0xFF;
+// small integers are stored as such
+fn ziplist(blob: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut reader = Reader {
+        data: blob,
+        pos: 10,
+    };
+    let mut items = Vec::new();
+    while reader.peek()? != 0xFF {
+        if reader.byte()? == 254 {
+            reader.take(4)?;
+        }
+        let encoding = reader.byte()?;
+        let item = match encoding >> 6 {
+            0 => reader.take((encoding & 0x3F) as usize)?.to_vec(),
+            1 => {
+                let len = ((encoding as usize & 0x3F) << 8) | reader.byte()? as usize;
+                reader.take(len)?.to_vec()
+            }
+            2 => {
+                let len = u32::from_be_bytes(reader.array()?) as usize;
+                reader.take(len)?.to_vec()
+            }
+            _ => {
+                let n = match encoding {
+                    0xC0 => i16::from_le_bytes(reader.array()?) as i64,
+                    0xD0 => i32::from_le_bytes(reader.array()?) as i64,
+                    0xE0 => i64::from_le_bytes(reader.array()?),
+                    0xF0 => {
+                        let [a, b, c] = reader.array()?;
+                        i32::from_le_bytes([0, a, b, c]) as i64 >> 8
+                    }
+                    0xFE => reader.byte()? as i8 as i64,
+                    0xF1..=0xFD => (encoding & 0x0F) as i64 - 1,
+                    other => return Err(format!("invalid ziplist encoding {other:#04x}")),
+                };
+                n.to_string().into_bytes()
+            }
+        };
+        items.push(item);
+    }
+    Ok(items)
+}
+
+// entries after a 6 byte header up to 0xFF, each followed by its own length for reading
+// backwards
+fn listpack(blob: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut reader = Reader { data: blob, pos: 6 };
+    let mut items = Vec::new();
+    while reader.peek()? != 0xFF {
+        let start = reader.pos;
+        let encoding = reader.byte()?;
+        let int = |n: i64| Ok::<_, String>(n.to_string().into_bytes());
+        let item = match encoding {
+            0x00..=0x7F => int(encoding as i64)?,
+            0x80..=0xBF => reader.take((encoding & 0x3F) as usize)?.to_vec(),
+            0xC0..=0xDF => {
+                let n = ((encoding as i64 & 0x1F) << 8) | reader.byte()? as i64;
+                int(if n >= 1 << 12 { n - (1 << 13) } else { n })?
+            }
+            0xE0..=0xEF => {
+                let len = ((encoding as usize & 0x0F) << 8) | reader.byte()? as usize;
+                reader.take(len)?.to_vec()
+            }
+            0xF0 => {
+                let len = u32::from_le_bytes(reader.array()?) as usize;
+                reader.take(len)?.to_vec()
+            }
+            0xF1 => int(i16::from_le_bytes(reader.array()?) as i64)?,
+            0xF2 => {
+                let [a, b, c] = reader.array()?;
+                int(i32::from_le_bytes([0, a, b, c]) as i64 >> 8)?
+            }
+            0xF3 => int(i32::from_le_bytes(reader.array()?) as i64)?,
+            0xF4 => int(i64::from_le_bytes(reader.array()?))?,
+            other => return Err(format!("invalid listpack encoding {other:#04x}")),
+        };
+        let len = reader.pos - start;
+        // the boundaries Redis' lpEncodeBacklen uses
+        let backlen = match len {
+            0..=127 => 1,
+            128..16_383 => 2,
+            16_383..2_097_151 => 3,
+            2_097_151..268_435_455 => 4,
+            _ => 5,
+        };
+        reader.take(backlen)?;
+        items.push(item);
+    }
+    Ok(items)
+}
+
+// the integer width, the count (both u32 LE) and the sorted integers
+fn intset(blob: &[u8]) -> Result<Vec<i64>, String> {
+    let mut reader = Reader { data: blob, pos: 0 };
+    let width = u32::from_le_bytes(reader.array()?) as usize;
+    let len = u32::from_le_bytes(reader.array()?);
+    (0..len)
+        .map(|_| {
+            let bytes = reader.take(width)?;
+            Ok(match width {
+                2 => i16::from_le_bytes(bytes.try_into().unwrap()) as i64,
+                4 => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+                8 => i64::from_le_bytes(bytes.try_into().unwrap()),
+                _ => return Err(format!("invalid intset width {width}")),
+            })
+        })
+        .collect()
+}
+
+// runs of literals (control byte < 32 holds the count - 1) and back references into the output
+fn lzf(input: &[u8], len: usize) -> Result<Vec<u8>, String> {
+    let bad = || "invalid LZF data".to_string();
+    // the declared length is not trusted for allocating more than the input can expand to
+    if len > input.len().saturating_mul(LZF_MAX_RATIO) {
+        return Err(bad());
+    }
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let literal = input.get(i..i + ctrl + 1).ok_or_else(bad)?;
+            out.extend_from_slice(literal);
+            i += ctrl + 1;
+            continue;
+        }
+        let mut count = ctrl >> 5;
+        if count == 7 {
+            count += *input.get(i).ok_or_else(bad)? as usize;
+            i += 1;
+        }
+        let low = *input.get(i).ok_or_else(bad)? as usize;
+        i += 1;
+        let distance = ((ctrl & 0x1F) << 8) + low + 1;
+        let start = out.len().checked_sub(distance).ok_or_else(bad)?;
+        for k in 0..count + 2 {
+            out.push(out[start + k]);
+        }
+    }
+    if out.len() != len {
+        return Err(bad());
+    }
+    Ok(out)
+}
+
+// CRC-64/Jones as Redis computes it: reflected, no initial or final xor
+pub fn crc64(data: &[u8]) -> u64 {
+    const POLY: u64 = 0x95AC_9329_AC4B_C9B5;
+    let mut crc = 0u64;
+    for byte in data {
+        crc ^= *byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+enum Length {
+    Len(u64),
+    // a string stored as an integer or LZF compressed
+    Encoded(u8),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.data.len());
+        let Some(end) = end else {
+            return Err("unexpected end of RDB data".to_string());
+        };
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn peek(&self) -> Result<u8, String> {
+        self.data
+            .get(self.pos)
+            .copied()
+            .ok_or("unexpected end of RDB data".to_string())
+    }
+
+    // the top two bits of the first byte tell how the rest is stored
+    fn length_or_encoding(&mut self) -> Result<Length, String> {
+        let first = self.byte()?;
+        Ok(match first >> 6 {
+            0 => Length::Len((first & 0x3F) as u64),
+            1 => Length::Len(((first as u64 & 0x3F) << 8) | self.byte()? as u64),
+            2 if first == 0x80 => Length::Len(u32::from_be_bytes(self.array()?) as u64),
+            2 if first == 0x81 => Length::Len(u64::from_be_bytes(self.array()?)),
+            2 => return Err(format!("invalid RDB length {first:#04x}")),
+            _ => Length::Encoded(first & 0x3F),
+        })
+    }
+
+    fn length(&mut self) -> Result<u64, String> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err("expected a length".to_string()),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, String> {
+        let len = match self.length_or_encoding()? {
+            Length::Len(len) => len,
+            Length::Encoded(0) => return Ok((self.byte()? as i8).to_string().into_bytes()),
+            Length::Encoded(1) => {
+                return Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes())
+            }
+            Length::Encoded(2) => {
+                return Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes())
+            }
+            Length::Encoded(3) => {
+                let compressed = self.length()? as usize;
+                let len = self.length()? as usize;
+                return lzf(self.take(compressed)?, len);
+            }
+            Length::Encoded(other) => return Err(format!("invalid string encoding {other}")),
+        };
+        Ok(self
+            .take(usize::try_from(len).unwrap_or(usize::MAX))?
+            .to_vec())
+    }
+}
+
+impl Store {
+    // the keys of the file overwrite keys of the same name, and are persisted by rewriting
+    // the log so they are imported only once
+    pub async fn import_rdb(&self, path: &Path) -> Result<RdbImport, String> {
+        let data =
+            std::fs::read(path).map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+        let (snapshot, import) = read_rdb(&data)?;
+        {
+            let _barrier = self.barrier.write().await;
+            self.restore(snapshot)?;
+        }
+        (0..import.keys).for_each(|_| self.mark_dirty());
+        if self.persistence.is_some() {
+            self.rewrite_log().await?;
+        }
+        Ok(import)
+    }
+}
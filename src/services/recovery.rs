@@ -0,0 +1,72 @@
+use crate::services::log_format::LogCheck;
+use crate::services::wal::Manifest;
+use std::fmt;
+use std::str::FromStr;
+
+// an earlier state rebuilt at startup from the snapshots and segments still kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryTarget {
+    // everything logged up to this unix time in ms
+    Time(u64),
+    // everything logged before this byte offset of a segment
+    Offset { segment: u64, offset: usize },
+}
+
+impl FromStr for RecoveryTarget {
+    type Err = String;
+
+    // "<unix ms>" or "<segment>:<offset>"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid recovery target {s:?}");
+        match s.split_once(':') {
+            Some((segment, offset)) => Ok(RecoveryTarget::Offset {
+                segment: segment.parse().map_err(|_| invalid())?,
+                offset: offset.parse().map_err(|_| invalid())?,
+            }),
+            None => s.parse().map(RecoveryTarget::Time).map_err(|_| invalid()),
+        }
+    }
+}
+
+impl fmt::Display for RecoveryTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecoveryTarget::Time(unix_ms) => write!(f, "{unix_ms}"),
+            RecoveryTarget::Offset { segment, offset } => write!(f, "{segment}:{offset}"),
+        }
+    }
+}
+
+impl RecoveryTarget {
+    // the newest segment holding the keyspace from before the target
+    pub(crate) fn start(&self, manifest: &Manifest) -> Result<u64, String> {
+        if let RecoveryTarget::Offset { segment, .. } = self {
+            if !manifest.segments.contains(segment) {
+                return Err(format!("log segment {segment} is not kept"));
+            }
+        }
+        manifest
+            .snapshots
+            .iter()
+            .filter(|snapshot| match self {
+                RecoveryTarget::Time(unix_ms) => snapshot.unix_ms <= *unix_ms,
+                RecoveryTarget::Offset { segment, .. } => snapshot.segment <= *segment,
+            })
+            .map(|snapshot| snapshot.segment)
+            .max()
+            .ok_or_else(|| format!("no snapshot from before {self} is kept, see wal_keep_segments"))
+    }
+
+    // whether the command at `index` of the segment was logged before the target
+    pub(crate) fn includes(&self, segment: u64, check: &LogCheck, index: usize) -> bool {
+        match self {
+            RecoveryTarget::Time(unix_ms) => {
+                check.written_at(index).is_none_or(|at| at <= *unix_ms)
+            }
+            RecoveryTarget::Offset {
+                segment: last,
+                offset,
+            } => segment < *last || (segment == *last && check.ends[index] <= *offset),
+        }
+    }
+}
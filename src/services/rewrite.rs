@@ -0,0 +1,214 @@
+use crate::app_server::parser::Command;
+use crate::app_server::reply::Reply;
+use crate::services::capture::{Captured, SnapshotSink};
+use crate::services::compression::Codec;
+use crate::services::encryption::Keyring;
+use crate::services::log_format::{encode_record, pack_record, Position};
+use crate::services::snapshot::SnapshotWriter;
+use crate::services::store::{Store, Value};
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+impl Store {
+    // rewrites the log as the minimal commands rebuilding the current keyspace into a new
+    // base segment, writes logged meanwhile go to the segment after it
+    pub async fn rewrite_log(&self) -> Result<(), String> {
+        let Some(persistence) = &self.persistence else {
+            return Err("persistence is disabled".to_string());
+        };
+        if !persistence.try_begin_rewrite() {
+            return Err("Background append only file rewriting already in progress".to_string());
+        }
+        self.run_rewrite().await
+    }
+
+    // the caller has marked the rewrite as started
+    async fn run_rewrite(&self) -> Result<(), String> {
+        let Some(persistence) = &self.persistence else {
+            return Err("persistence is disabled".to_string());
+        };
+        // writes go on meanwhile, keeping for the capture what they change
+        let captured = {
+            let _barrier = self.barrier.write().await;
+            self.reload_keys();
+            persistence
+                .start_rewrite()
+                .await
+                .map(|segment| (segment, self.begin_capture()))
+        };
+        let written = match captured {
+            Ok((segment, captured)) => {
+                let path = persistence.rewrite_path();
+                let preamble = self.settings.aof_use_rdb_preamble;
+                let codec = self.settings.compression;
+                let keys = self.keys.get();
+                tokio::task::spawn_blocking(move || {
+                    write_snapshot(&path, segment, &captured, preamble, codec, keys)
+                })
+                .await
+                .unwrap_or_else(|e| Err(e.to_string()))
+            }
+            Err(e) => Err(e),
+        };
+        let result = persistence.finish_rewrite(written).await;
+        if let Err(e) = &result {
+            eprintln!("log rewrite failed: {e}");
+        }
+        result
+    }
+
+    // a rewrite seals everything with the key currently configured, so rotating the key
+    // takes replacing it in its source and rewriting the log
+    fn reload_keys(&self) {
+        let Some(current) = self.keys.get() else {
+            return;
+        };
+        match Keyring::load(&self.settings) {
+            Ok(Some(keyring)) => {
+                if keyring.current() != current.current() {
+                    println!("the log is rewritten with a new encryption key");
+                }
+                self.keys.set(keyring);
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("{e}, keeping the current encryption key"),
+        }
+    }
+
+    pub(crate) fn bg_rewrite(&self) -> Reply {
+        match &self.persistence {
+            None => Reply::error("persistence is disabled"),
+            Some(_) => match self.spawn_rewrite() {
+                Ok(()) => {
+                    Reply::Simple("Background append only file rewriting started".to_string())
+                }
+                Err(e) => Reply::error(&e),
+            },
+        }
+    }
+
+    fn spawn_rewrite(&self) -> Result<(), String> {
+        let Some(persistence) = &self.persistence else {
+            return Err("persistence is disabled".to_string());
+        };
+        let store = self.me.upgrade().ok_or("the store is shutting down")?;
+        let runtime = tokio::runtime::Handle::try_current()
+            .map_err(|_| "no runtime to rewrite the log in the background".to_string())?;
+        if !persistence.try_begin_rewrite() {
+            return Err("Background append only file rewriting already in progress".to_string());
+        }
+        runtime.spawn(async move {
+            let _ = store.run_rewrite().await;
+        });
+        Ok(())
+    }
+
+    // like Redis, the log is rewritten once it doubled (by default) and is past a minimum size
+    fn rewrite_due(&self) -> bool {
+        let Some(persistence) = &self.persistence else {
+            return false;
+        };
+        let percentage = self.settings.auto_aof_rewrite_percentage;
+        let size = persistence.size();
+        let base = persistence.base_size().max(1);
+        percentage > 0
+            && !persistence.rewrite_in_progress()
+            && size >= self.settings.auto_aof_rewrite_min_size
+            && size.saturating_sub(base) * 100 / base >= percentage
+    }
+
+    pub(crate) fn schedule_rewrite_check(&self) {
+        let store = self.me.clone();
+        self.scheduler
+            .schedule_every("log-rewrite-check", Duration::from_secs(1), move || {
+                if let Some(store) = store.upgrade() {
+                    if store.rewrite_due() {
+                        let _ = store.spawn_rewrite();
+                    }
+                }
+            });
+    }
+}
+
+// with the preamble the keyspace is written in the binary snapshot format,
+// otherwise as commands rebuilding it
+fn write_snapshot(
+    path: &Path,
+    segment: u64,
+    captured: &Captured,
+    preamble: bool,
+    codec: Codec,
+    keys: Option<Arc<Keyring>>,
+) -> Result<(), String> {
+    let write = || -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        if preamble {
+            let at = Position { segment, offset: 0 };
+            let mut writer = SnapshotWriter::new(&mut out, codec, keys, &at.aad())?;
+            captured.write(&mut writer)?;
+            writer.finish()?;
+        } else {
+            captured.write(&mut CommandSink {
+                out: &mut out,
+                segment,
+                offset: 0,
+                codec,
+                keys: keys.as_deref(),
+            })?;
+        }
+        out.into_inner()?.sync_data()
+    };
+    write().map_err(|e| e.to_string())
+}
+
+struct CommandSink<'a> {
+    out: &'a mut BufWriter<File>,
+    segment: u64,
+    offset: u64,
+    codec: Codec,
+    keys: Option<&'a Keyring>,
+}
+
+impl CommandSink<'_> {
+    fn record(&mut self, cmd: &Command) -> io::Result<()> {
+        let at = Position {
+            segment: self.segment,
+            offset: self.offset,
+        };
+        let record = pack_record(encode_record(cmd), self.codec, self.keys, at);
+        self.offset += record.len() as u64;
+        self.out.write_all(&record)
+    }
+}
+
+impl SnapshotSink for CommandSink<'_> {
+    fn select_db(&mut self, db: usize) -> io::Result<()> {
+        self.record(&Command::SELECT { db })
+    }
+
+    fn entry(&mut self, key: &str, value: &Value, expires_at: Option<u64>) -> io::Result<()> {
+        let key = key.to_string();
+        self.record(&match value {
+            Value::Str(value) => Command::SET {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            Value::Packed(packed) => Command::SET {
+                key: key.clone(),
+                value: packed.unpack(),
+            },
+            Value::List(list) => Command::RPUSH {
+                key: key.clone(),
+                values: list.iter().cloned().collect(),
+            },
+        })?;
+        if let Some(at) = expires_at {
+            self.record(&Command::PEXPIREAT { key, at })?;
+        }
+        Ok(())
+    }
+}
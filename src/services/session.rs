@@ -0,0 +1,7 @@
+// per-connection state, each client starts on database 0
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub db: usize,
+    // set by AUTH, only checked by the server when `requirepass` is set
+    pub authenticated: bool,
+}
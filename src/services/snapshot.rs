@@ -0,0 +1,682 @@
+use crate::app_server::parser::Command;
+use crate::app_server::reply::Reply;
+use crate::services::capture::SnapshotSink;
+use crate::services::compression::Codec;
+use crate::services::encryption::{Keyring, SealedWith};
+use crate::services::store::{Store, Value};
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+const MAGIC: &[u8; 4] = b"KVDS";
+const SEALED_MAGIC: &[u8; 4] = b"KVDE";
+const VERSION: u16 = 1;
+// a version 1 snapshot compressed whole, or cut into chunks
+const COMPRESSED_VERSION: u16 = 2;
+const CHUNKED_VERSION: u16 = 3;
+const CHUNK_SIZE: usize = 1 << 20;
+
+const OP_SELECT_DB: u8 = 0xFE;
+const OP_EXPIRE_MS: u8 = 0xFC;
+const OP_EOF: u8 = 0xFF;
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+
+pub(crate) struct Entry {
+    pub(crate) key: String,
+    pub(crate) value: Value,
+    // unix time in milliseconds
+    pub(crate) expires_at: Option<u64>,
+}
+
+// (database index, its keys), empty databases are left out
+pub(crate) type Snapshot = Vec<(usize, Vec<Entry>)>;
+
+#[derive(Default)]
+pub(crate) struct SnapshotState {
+    // unix seconds of the last successful save
+    last_save: AtomicU64,
+    // writes since the last save
+    dirty: AtomicU64,
+    saving: AtomicBool,
+}
+
+// one "<seconds> <changes>" pair of the `save` setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavePoint {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+pub fn parse_save_points(rules: &str) -> Result<Vec<SavePoint>, String> {
+    let numbers = rules
+        .split_whitespace()
+        .map(|n| {
+            n.parse::<u64>()
+                .map_err(|_| format!("invalid save rule: {rules}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if numbers.len() % 2 != 0 {
+        return Err(format!("invalid save rule: {rules}"));
+    }
+    Ok(numbers
+        .chunks(2)
+        .map(|pair| SavePoint {
+            seconds: pair[0],
+            changes: pair[1],
+        })
+        .collect())
+}
+
+pub(crate) fn is_snapshot(data: &[u8]) -> bool {
+    data.starts_with(MAGIC) || data.starts_with(SEALED_MAGIC)
+}
+
+// writes a snapshot as the keyspace is walked. Without compression or encryption as version 1,
+// otherwise as version 3: the version 1 stream in chunks of about CHUNK_SIZE bytes after the
+// codec and whether they are sealed, each chunk a u32 LE length and its bytes, compressed when
+// that makes them smaller (a codec byte tells) and then sealed, bound to `aad` and the chunk's
+// number. A zero length ends it
+pub(crate) struct SnapshotWriter<W: Write> {
+    out: W,
+    codec: Codec,
+    keys: Option<Arc<Keyring>>,
+    aad: Vec<u8>,
+    chunked: bool,
+    // the version 1 stream not written out yet, and the checksum of all of it so far
+    pending: Vec<u8>,
+    checksum: crc32fast::Hasher,
+    chunks: u64,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    pub(crate) fn new(
+        mut out: W,
+        codec: Codec,
+        keys: Option<Arc<Keyring>>,
+        aad: &[u8],
+    ) -> io::Result<Self> {
+        let chunked = codec != Codec::None || keys.is_some();
+        if chunked {
+            out.write_all(MAGIC)?;
+            out.write_all(&CHUNKED_VERSION.to_le_bytes())?;
+            out.write_all(&[codec.id(), keys.is_some() as u8])?;
+        }
+        let mut writer = SnapshotWriter {
+            out,
+            codec,
+            keys,
+            aad: aad.to_vec(),
+            chunked,
+            pending: Vec::new(),
+            checksum: crc32fast::Hasher::new(),
+            chunks: 0,
+        };
+        writer.put(MAGIC);
+        writer.put(&VERSION.to_le_bytes());
+        Ok(writer)
+    }
+
+    fn put(&mut self, bytes: &[u8]) {
+        self.checksum.update(bytes);
+        self.pending.extend_from_slice(bytes);
+    }
+
+    fn flush_chunk(&mut self) -> io::Result<()> {
+        let plain = std::mem::take(&mut self.pending);
+        if !self.chunked {
+            return self.out.write_all(&plain);
+        }
+        let mut chunk = match self.codec.compress(&plain) {
+            Some(compressed) => [&[self.codec.id()], &compressed[..]].concat(),
+            None => [&[Codec::None.id()], &plain[..]].concat(),
+        };
+        if let Some(keys) = &self.keys {
+            chunk = keys.seal(&chunk, &chunk_aad(&self.aad, self.chunks));
+        }
+        self.chunks += 1;
+        self.out.write_all(&(chunk.len() as u32).to_le_bytes())?;
+        self.out.write_all(&chunk)
+    }
+
+    fn written(&mut self) -> io::Result<()> {
+        if self.pending.len() >= CHUNK_SIZE {
+            self.flush_chunk()?;
+        }
+        Ok(())
+    }
+
+    // the end and checksum of the version 1 stream, returns the output
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.put(&[OP_EOF]);
+        let checksum = std::mem::take(&mut self.checksum).finalize();
+        self.pending.extend_from_slice(&checksum.to_le_bytes());
+        self.flush_chunk()?;
+        if self.chunked {
+            self.out.write_all(&0u32.to_le_bytes())?;
+        }
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> SnapshotSink for SnapshotWriter<W> {
+    fn select_db(&mut self, db: usize) -> io::Result<()> {
+        self.put(&[OP_SELECT_DB]);
+        self.put(&(db as u32).to_le_bytes());
+        self.written()
+    }
+
+    fn entry(&mut self, key: &str, value: &Value, expires_at: Option<u64>) -> io::Result<()> {
+        let mut out = Vec::new();
+        if let Some(expires_at) = expires_at {
+            out.push(OP_EXPIRE_MS);
+            out.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        out.push(value_type(value));
+        put_string(&mut out, key);
+        put_value(&mut out, value);
+        self.put(&out);
+        self.written()
+    }
+}
+
+fn chunk_aad(aad: &[u8], chunk: u64) -> Vec<u8> {
+    [aad, &chunk.to_le_bytes()].concat()
+}
+
+// reads a snapshot `SnapshotWriter` or an older version wrote from `input` up to its end,
+// handing each entry to `f` as it is decoded, and returns how it was sealed if it was.
+// With `keys` an unencrypted one is refused unless migrating
+pub(crate) fn read_snapshot(
+    input: &mut dyn Read,
+    keys: Option<&Keyring>,
+    aad: &[u8],
+    f: &mut dyn FnMut(usize, Entry) -> Result<(), String>,
+) -> Result<Option<SealedWith>, String> {
+    let mut stream = Stream::new(input);
+    let magic = stream.array::<4>()?;
+    if magic == *SEALED_MAGIC {
+        let keys =
+            keys.ok_or("encrypted snapshot but no encryption key is configured".to_string())?;
+        let (plain, sealed_with) = keys.open(&stream.block()?, aad)?;
+        read_whole(&plain, "encrypted", f)?;
+        return Ok(Some(sealed_with));
+    }
+    if magic != *MAGIC {
+        return Err("not a snapshot file".to_string());
+    }
+    let version = u16::from_le_bytes(stream.array()?);
+    if version == CHUNKED_VERSION {
+        return read_chunks(stream.input, keys, aad, f);
+    }
+    if keys.is_some_and(|keys| !keys.is_migrating()) {
+        return Err("unencrypted snapshot but an encryption key is configured".to_string());
+    }
+    match version {
+        VERSION => stream.entries(f)?,
+        COMPRESSED_VERSION => {
+            let codec = Codec::from_id(stream.byte()?)?;
+            read_whole(&codec.decompress(&stream.block()?)?, "compressed", f)?;
+        }
+        version => return Err(format!("unsupported snapshot version {version}")),
+    }
+    Ok(None)
+}
+
+// the snapshot an older version sealed or compressed whole
+fn read_whole(
+    mut plain: &[u8],
+    what: &str,
+    f: &mut dyn FnMut(usize, Entry) -> Result<(), String>,
+) -> Result<(), String> {
+    read_snapshot(&mut plain, None, &[], f)?;
+    if !plain.is_empty() {
+        return Err(format!("trailing bytes in the {what} snapshot"));
+    }
+    Ok(())
+}
+
+fn read_chunks(
+    input: &mut dyn Read,
+    keys: Option<&Keyring>,
+    aad: &[u8],
+    f: &mut dyn FnMut(usize, Entry) -> Result<(), String>,
+) -> Result<Option<SealedWith>, String> {
+    let mut stream = Stream::new(input);
+    Codec::from_id(stream.byte()?)?;
+    let sealed = stream.byte()? != 0;
+    let keys = match keys {
+        Some(keys) if !sealed && !keys.is_migrating() => {
+            return Err("unencrypted snapshot but an encryption key is configured".to_string())
+        }
+        None if sealed => {
+            return Err("encrypted snapshot but no encryption key is configured".to_string())
+        }
+        keys => keys.filter(|_| sealed),
+    };
+    let mut chunks = Chunks {
+        input: stream.input,
+        keys,
+        aad,
+        next: 0,
+        plain: Vec::new(),
+        pos: 0,
+        done: false,
+        sealed_with: None,
+    };
+    let mut stream = Stream::new(&mut chunks);
+    if stream.array::<4>()? != *MAGIC || u16::from_le_bytes(stream.array()?) != VERSION {
+        return Err("not a snapshot file".to_string());
+    }
+    stream.entries(f)?;
+    if chunks.read(&mut [0]).map_err(|e| e.to_string())? != 0 {
+        return Err("trailing bytes in the chunked snapshot".to_string());
+    }
+    Ok(chunks.sealed_with)
+}
+
+// the version 1 stream of a chunked snapshot, opened a chunk at a time
+struct Chunks<'a> {
+    input: &'a mut dyn Read,
+    keys: Option<&'a Keyring>,
+    aad: &'a [u8],
+    next: u64,
+    plain: Vec<u8>,
+    pos: usize,
+    done: bool,
+    sealed_with: Option<SealedWith>,
+}
+
+impl Chunks<'_> {
+    fn next_chunk(&mut self) -> Result<(), String> {
+        let mut stream = Stream::new(&mut *self.input);
+        let len = u32::from_le_bytes(stream.array()?) as usize;
+        if len == 0 {
+            self.done = true;
+            return Ok(());
+        }
+        let mut chunk = stream.take(len)?;
+        if let Some(keys) = self.keys {
+            let (opened, sealed_with) = keys.open(&chunk, &chunk_aad(self.aad, self.next))?;
+            chunk = opened;
+            self.sealed_with = Some(sealed_with);
+        }
+        self.next += 1;
+        let (codec, bytes) = chunk.split_first().ok_or("empty snapshot chunk")?;
+        self.plain = Codec::from_id(*codec)?.decompress(bytes)?;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl Read for Chunks<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.next_chunk()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::Str(_) | Value::Packed(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+    }
+}
+
+fn put_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Str(value) => put_string(out, value),
+        Value::Packed(packed) => put_string(out, &packed.unpack()),
+        Value::List(list) => {
+            out.extend_from_slice(&(list.len() as u32).to_le_bytes());
+            list.iter().for_each(|item| put_string(out, item));
+        }
+    }
+}
+
+// a single value for DUMP: its type, the value as snapshots store it, then the snapshot
+// version (u16 LE) and a crc32 (u32 LE) of everything before it
+pub(crate) fn dump_value(value: &Value) -> Vec<u8> {
+    let mut out = vec![value_type(value)];
+    put_value(&mut out, value);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    let checksum = crc32fast::hash(&out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+// None when the payload is damaged or from another version
+pub(crate) fn restore_value(payload: &[u8]) -> Option<Value> {
+    let (body, checksum) = payload.split_at_checked(payload.len().checked_sub(4)?)?;
+    if crc32fast::hash(body) != u32::from_le_bytes(checksum.try_into().ok()?) {
+        return None;
+    }
+    let (body, version) = body.split_at_checked(body.len().checked_sub(2)?)?;
+    if u16::from_le_bytes(version.try_into().ok()?) != VERSION {
+        return None;
+    }
+    let mut input = body;
+    let mut stream = Stream::new(&mut input);
+    let kind = stream.byte().ok()?;
+    let value = stream.value(kind).ok()?;
+    input.is_empty().then_some(value)
+}
+
+fn put_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+// reads what it is asked for and no further, checksumming everything it read
+struct Stream<'a> {
+    input: &'a mut dyn Read,
+    checksum: crc32fast::Hasher,
+}
+
+impl<'a> Stream<'a> {
+    fn new(input: &'a mut dyn Read) -> Self {
+        Stream {
+            input,
+            checksum: crc32fast::Hasher::new(),
+        }
+    }
+
+    // grows with what was read rather than the length asked for, which may be damaged
+    fn take(&mut self, n: usize) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        (&mut self.input)
+            .take(n as u64)
+            .read_to_end(&mut bytes)
+            .map_err(|e| e.to_string())?;
+        if bytes.len() != n {
+            return Err("unexpected end of snapshot".to_string());
+        }
+        self.checksum.update(&bytes);
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    // bytes after their u64 LE length
+    fn block(&mut self) -> Result<Vec<u8>, String> {
+        let len = u64::from_le_bytes(self.array()?);
+        self.take(usize::try_from(len).unwrap_or(usize::MAX))
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = u32::from_le_bytes(self.array()?) as usize;
+        String::from_utf8(self.take(len)?).map_err(|e| e.to_string())
+    }
+
+    fn value(&mut self, kind: u8) -> Result<Value, String> {
+        match kind {
+            TYPE_STRING => Ok(Value::Str(self.string()?)),
+            TYPE_LIST => {
+                let len = u32::from_le_bytes(self.array()?);
+                let list = (0..len)
+                    .map(|_| self.string())
+                    .collect::<Result<VecDeque<_>, _>>()?;
+                Ok(Value::List(list))
+            }
+            other => Err(format!("unknown value type {other}")),
+        }
+    }
+
+    // the entries of a version 1 snapshot after its header, up to its checksum
+    fn entries(
+        &mut self,
+        f: &mut dyn FnMut(usize, Entry) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let mut db = None;
+        let mut expires_at = None;
+        loop {
+            match self.byte()? {
+                OP_EOF => break,
+                OP_SELECT_DB => db = Some(u32::from_le_bytes(self.array()?) as usize),
+                OP_EXPIRE_MS => expires_at = Some(u64::from_le_bytes(self.array()?)),
+                kind @ (TYPE_STRING | TYPE_LIST) => {
+                    let key = self.string()?;
+                    let value = self.value(kind)?;
+                    let db = db.ok_or("key outside of a database")?;
+                    f(
+                        db,
+                        Entry {
+                            key,
+                            value,
+                            expires_at: expires_at.take(),
+                        },
+                    )?;
+                }
+                other => return Err(format!("unknown snapshot opcode {other:#04x}")),
+            }
+        }
+        let expected = std::mem::take(&mut self.checksum).finalize();
+        if u32::from_le_bytes(self.array()?) != expected {
+            return Err("snapshot checksum mismatch".to_string());
+        }
+        Ok(())
+    }
+}
+
+// written next to the target first so a crash never leaves a half written snapshot
+fn write_file(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> Result<(), String> {
+    let tmp = path.with_extension("tmp");
+    let write = || -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        write(&mut out)?;
+        out.into_inner()?.sync_data()?;
+        std::fs::rename(&tmp, path)
+    };
+    write().map_err(|e| e.to_string())
+}
+
+impl Store {
+    pub(crate) fn unix_ms(&self) -> u64 {
+        self.clock
+            .system_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+
+    // the store clock instant of a unix deadline, None when it already passed
+    pub(crate) fn deadline_from_unix_ms(&self, at: u64) -> Option<Instant> {
+        let left = at.checked_sub(self.unix_ms()).filter(|left| *left > 0)?;
+        self.clock.now().checked_add(Duration::from_millis(left))
+    }
+
+    pub(crate) fn restore(&self, snapshot: Snapshot) -> Result<(), String> {
+        let mut restore = self.restorer();
+        for (index, entries) in snapshot {
+            for entry in entries {
+                restore(index, entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    // restores entries one at a time as they are read, keys whose deadline passed while
+    // the snapshot was stored are dropped
+    pub(crate) fn restorer(&self) -> impl FnMut(usize, Entry) -> Result<(), String> + '_ {
+        let mut skipped = None;
+        move |index, entry| {
+            let Some(db) = self.db(index) else {
+                if skipped.replace(index) != Some(index) {
+                    eprintln!("snapshot database {index} is out of range, skipping it");
+                }
+                return Ok(());
+            };
+            let mut stored = self.new_data(entry.value);
+            if let Some(at) = entry.expires_at {
+                let Some(deadline) = self.deadline_from_unix_ms(at) else {
+                    return Ok(());
+                };
+                self.expire_at(&db, &mut stored, entry.key.clone(), deadline);
+            }
+            db.shard(&entry.key)
+                .write()
+                .unwrap()
+                .insert(entry.key, stored)
+                .map_err(|e| format!("storage engine: {e}"))?;
+            Ok(())
+        }
+    }
+
+    // read as it is restored, never whole
+    pub fn load_snapshot(&self, path: &Path) -> Result<(), String> {
+        let mut input = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+        let keys = self.keys.get();
+        read_snapshot(&mut input, keys.as_deref(), &[], &mut self.restorer())?;
+        Ok(())
+    }
+
+    pub(crate) fn mark_dirty(&self) {
+        self.snapshots.dirty.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn last_save(&self) -> u64 {
+        self.snapshots.last_save.load(Ordering::Relaxed)
+    }
+
+    pub fn bgsave_in_progress(&self) -> bool {
+        self.snapshots.saving.load(Ordering::SeqCst)
+    }
+
+    // SAVE: blocks the caller (but not other clients) until the snapshot is on disk
+    pub async fn save(&self) -> Result<(), String> {
+        if self.snapshots.saving.swap(true, Ordering::SeqCst) {
+            return Err("Background save already in progress".to_string());
+        }
+        self.run_save().await
+    }
+
+    // the caller has marked the save as started
+    async fn run_save(&self) -> Result<(), String> {
+        // writes go on meanwhile, keeping for the capture what they change
+        let (captured, dirty) = {
+            let _barrier = self.barrier.write().await;
+            (
+                self.begin_capture(),
+                self.snapshots.dirty.load(Ordering::Relaxed),
+            )
+        };
+        let path = Path::new(&self.settings.snapshot_file).to_path_buf();
+        let codec = self.settings.compression;
+        let keys = self.keys.get();
+        let result = tokio::task::spawn_blocking(move || {
+            write_file(&path, |out| {
+                let mut writer = SnapshotWriter::new(out, codec, keys, &[])?;
+                captured.write(&mut writer)?;
+                writer.finish().map(|_| ())
+            })
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+        match &result {
+            Ok(()) => {
+                self.snapshots.dirty.fetch_sub(dirty, Ordering::Relaxed);
+                self.snapshots
+                    .last_save
+                    .store(self.unix_ms() / 1000, Ordering::Relaxed);
+            }
+            Err(e) => eprintln!("snapshot save failed: {e}"),
+        }
+        self.snapshots.saving.store(false, Ordering::SeqCst);
+        result
+    }
+
+    fn spawn_save(&self) -> Result<(), String> {
+        let store = self.me.upgrade().ok_or("the store is shutting down")?;
+        let runtime = tokio::runtime::Handle::try_current()
+            .map_err(|_| "no runtime to save in the background".to_string())?;
+        if self.snapshots.saving.swap(true, Ordering::SeqCst) {
+            return Err("Background save already in progress".to_string());
+        }
+        runtime.spawn(async move {
+            let _ = store.run_save().await;
+        });
+        Ok(())
+    }
+
+    pub(crate) fn bg_save(&self) -> Reply {
+        match self.spawn_save() {
+            Ok(()) => Reply::Simple("Background saving started".to_string()),
+            Err(e) => Reply::error(&e),
+        }
+    }
+
+    pub(crate) fn init_snapshots(&self) {
+        self.snapshots
+            .last_save
+            .store(self.unix_ms() / 1000, Ordering::Relaxed);
+        let save_points = match parse_save_points(&self.settings.save) {
+            Ok(save_points) => save_points,
+            Err(e) => {
+                eprintln!("{e}, automatic snapshots are disabled");
+                return;
+            }
+        };
+        if save_points.is_empty() {
+            return;
+        }
+        let store = self.me.clone();
+        self.scheduler
+            .schedule_every("snapshot-save-check", Duration::from_secs(1), move || {
+                let Some(store) = store.upgrade() else {
+                    return;
+                };
+                let elapsed = (store.unix_ms() / 1000).saturating_sub(store.last_save());
+                let dirty = store.snapshots.dirty.load(Ordering::Relaxed);
+                if save_points
+                    .iter()
+                    .any(|p| dirty >= p.changes.max(1) && elapsed >= p.seconds)
+                {
+                    let _ = store.spawn_save();
+                }
+            });
+    }
+}
+
+pub(crate) fn is_write(cmd: &Command) -> bool {
+    !matches!(
+        cmd,
+        Command::PING
+            | Command::GET { .. }
+            | Command::KEYS { .. }
+            | Command::TTL { .. }
+            | Command::PTTL { .. }
+            | Command::LRANGE { .. }
+            | Command::LLEN { .. }
+            | Command::MGET { .. }
+            | Command::SELECT { .. }
+            | Command::BGREWRITEAOF
+            | Command::SAVE
+            | Command::BGSAVE
+            | Command::LASTSAVE
+            | Command::DUMP { .. }
+            | Command::AUTH { .. }
+    )
+}
@@ -0,0 +1,111 @@
+use crate::services::lsm::LsmEngine;
+use crate::services::store::StoredData;
+use crate::Settings;
+
+use indexmap::IndexMap;
+use serde::Deserialize;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageKind {
+    // every key in memory
+    Memory,
+    // the most recently used keys in memory, the rest in a log-structured merge tree on disk
+    Lsm,
+}
+
+// where a shard keeps its keys. Reads through `get` only see keys in memory, so disk
+// engines bring a key in with `load` before it is read; the shard does that for writes.
+// Whatever reads the disk can fail, and then changes nothing
+pub(crate) trait StorageEngine: Send + Sync {
+    fn get(&self, key: &str) -> Option<&StoredData>;
+    fn get_mut(&mut self, key: &str) -> Option<&mut StoredData>;
+    fn insert(&mut self, key: String, data: StoredData) -> io::Result<Option<StoredData>>;
+    fn remove(&mut self, key: &str) -> io::Result<Option<StoredData>>;
+    fn clear(&mut self);
+    // a random key of those held in memory
+    fn sample(&self) -> Option<(&String, &StoredData)>;
+    // every key, on disk or not
+    fn scan(&self, f: &mut dyn FnMut(&str, &StoredData)) -> io::Result<()>;
+
+    // whether `key` is stored but not in memory
+    fn is_cold(&self, _key: &str) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    fn load(&mut self, _key: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    // the size of the keys kept on disk
+    fn cold_size(&self) -> usize {
+        0
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct MemoryEngine {
+    // indexed so eviction can sample it
+    map: IndexMap<String, StoredData>,
+}
+
+impl StorageEngine for MemoryEngine {
+    fn get(&self, key: &str) -> Option<&StoredData> {
+        self.map.get(key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut StoredData> {
+        self.map.get_mut(key)
+    }
+
+    fn insert(&mut self, key: String, data: StoredData) -> io::Result<Option<StoredData>> {
+        Ok(self.map.insert(key, data))
+    }
+
+    fn remove(&mut self, key: &str) -> io::Result<Option<StoredData>> {
+        Ok(self.map.swap_remove(key))
+    }
+
+    fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    fn sample(&self) -> Option<(&String, &StoredData)> {
+        sample(&self.map)
+    }
+
+    fn scan(&self, f: &mut dyn FnMut(&str, &StoredData)) -> io::Result<()> {
+        self.map.iter().for_each(|(key, data)| f(key, data));
+        Ok(())
+    }
+}
+
+pub(crate) fn sample(map: &IndexMap<String, StoredData>) -> Option<(&String, &StoredData)> {
+    if map.is_empty() {
+        return None;
+    }
+    map.get_index(fastrand::usize(..map.len()))
+}
+
+// one engine per shard of database `db`; disk engines start empty as the log or the
+// snapshot rebuilds their content at startup
+pub(crate) fn open_engines(settings: &Settings, db: usize) -> Vec<Box<dyn StorageEngine>> {
+    let shards = settings.shards.max(1);
+    (0..shards)
+        .map(|shard| -> Box<dyn StorageEngine> {
+            match settings.storage {
+                StorageKind::Memory => Box::new(MemoryEngine::default()),
+                StorageKind::Lsm => Box::new(
+                    LsmEngine::open(
+                        PathBuf::from(&settings.storage_dir).join(format!("{db}-{shard}")),
+                        (settings.hot_keys / shards).max(1),
+                        settings.lsm_memtable_size,
+                    )
+                    .expect("error in creating the storage directory!"),
+                ),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,248 @@
+use crate::services::capture::Capture;
+use crate::services::clock::{Clock, MonotonicClock};
+use crate::services::compression::Packed;
+use crate::services::encryption::{Keyring, Keys};
+use crate::services::keyspace::Keyspace;
+use crate::services::persistence_service::Persistence;
+use crate::services::snapshot::SnapshotState;
+use crate::services::storage::open_engines;
+use crate::services::timer_service::{Scheduler, TaskHandle};
+use crate::Settings;
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Instant;
+
+// approximate bookkeeping cost of a key besides its own bytes
+const KEY_OVERHEAD: usize = 64;
+const LIST_ITEM_OVERHEAD: usize = 16;
+
+// Redis-style logarithmic access counter
+const LFU_INIT_VAL: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+const LFU_DECAY_MS: u64 = 60_000;
+
+#[derive(Clone)]
+pub(crate) enum Value {
+    Str(String),
+    // a long string compressed in memory, read like `Str`
+    Packed(Packed),
+    List(VecDeque<String>),
+}
+
+impl Value {
+    // a packed string is unpacked before it is changed in place
+    pub(crate) fn unpack(&mut self) {
+        if let Value::Packed(packed) = self {
+            *self = Value::Str(packed.unpack());
+        }
+    }
+}
+
+pub(crate) struct StoredData {
+    pub(crate) value: Value,
+    pub(crate) ttl: Option<Instant>,
+    pub(crate) expiry: Option<TaskHandle>,
+    pub(crate) size: usize,
+    // milliseconds on the store clock, updated under a read lock
+    last_access: AtomicU64,
+    lfu_counter: AtomicU8,
+}
+
+impl StoredData {
+    pub(crate) fn new(value: Value) -> Self {
+        StoredData {
+            value,
+            ttl: None,
+            expiry: None,
+            size: 0,
+            last_access: AtomicU64::new(0),
+            lfu_counter: AtomicU8::new(LFU_INIT_VAL),
+        }
+    }
+
+    pub(crate) fn estimate_size(&self, key: &str) -> usize {
+        let value = match &self.value {
+            Value::Str(s) => s.len(),
+            Value::Packed(packed) => packed.data.len(),
+            Value::List(list) => list.iter().map(|v| v.len() + LIST_ITEM_OVERHEAD).sum(),
+        };
+        KEY_OVERHEAD + key.len() + value
+    }
+
+    pub(crate) fn last_access(&self) -> u64 {
+        self.last_access.load(Ordering::Relaxed)
+    }
+
+    // the counter loses one point per decay period without access
+    pub(crate) fn lfu(&self, now_ms: u64) -> u8 {
+        let periods = now_ms.saturating_sub(self.last_access()) / LFU_DECAY_MS;
+        let counter = self.lfu_counter.load(Ordering::Relaxed) as u64;
+        counter.saturating_sub(periods) as u8
+    }
+
+    pub(crate) fn created_at(self, now_ms: u64) -> Self {
+        self.last_access.store(now_ms, Ordering::Relaxed);
+        self
+    }
+
+    pub(crate) fn touch(&self, now_ms: u64) {
+        let mut counter = self.lfu(now_ms);
+        if counter < u8::MAX {
+            let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+            if fastrand::f64() < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+                counter += 1;
+            }
+        }
+        self.lfu_counter.store(counter, Ordering::Relaxed);
+        self.last_access.store(now_ms, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_expired(&self, now: Instant) -> bool {
+        self.ttl.is_some_and(|ttl| ttl <= now)
+    }
+}
+
+// an overwritten or deleted key must not be removed later by a stale expiry task
+impl Drop for StoredData {
+    fn drop(&mut self) {
+        if let Some(expiry) = self.expiry.take() {
+            expiry.cancel();
+        }
+    }
+}
+
+pub struct Store {
+    // SWAPDB swaps the keyspaces behind two indexes, expiry tasks follow their keyspace
+    pub(crate) dbs: RwLock<Vec<Arc<Keyspace>>>,
+    pub(crate) settings: Settings,
+    pub(crate) persistence: Option<Persistence>,
+    // seals the log and snapshots when encryption is on
+    pub(crate) keys: Arc<Keys>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) scheduler: Scheduler,
+    pub(crate) epoch: Instant,
+    // logged writes hold it shared from logging to applying, a save or log rewrite takes it
+    // exclusively to mark its start and capture the keyspace at the same point
+    pub(crate) barrier: tokio::sync::RwLock<()>,
+    // the saves and rewrites writing the keyspace out, writes keep what they change first
+    pub(crate) captures: Mutex<Vec<Arc<Capture>>>,
+    pub(crate) snapshots: SnapshotState,
+    // background jobs only hold a weak reference so they never keep a dropped store alive
+    pub(crate) me: Weak<Store>,
+}
+
+impl Store {
+    pub fn new(settings: Settings) -> Arc<Self> {
+        Self::with_clock(settings, Arc::new(MonotonicClock::new()))
+    }
+
+    pub fn with_clock(settings: Settings, clock: Arc<dyn Clock>) -> Arc<Self> {
+        let keyring = Keyring::load(&settings).expect("error reading the encryption key!");
+        let keys = Arc::new(Keys::new(keyring));
+        let persistence = if settings.persist {
+            Some(Persistence::open(&settings, clock.clone(), keys.clone()))
+        } else {
+            None
+        };
+        let dbs = (0..settings.databases.max(1))
+            .map(|db| Arc::new(Keyspace::new(open_engines(&settings, db))))
+            .collect();
+        let store = Arc::new_cyclic(|me| Store {
+            dbs: RwLock::new(dbs),
+            persistence,
+            keys,
+            scheduler: Scheduler::with_clock(clock.clone()),
+            epoch: clock.now(),
+            clock,
+            settings,
+            barrier: tokio::sync::RwLock::new(()),
+            captures: Mutex::new(Vec::new()),
+            snapshots: SnapshotState::default(),
+            me: me.clone(),
+        });
+        store.init_snapshots();
+        if store.persistence.is_some() {
+            store.schedule_rewrite_check();
+        }
+        store
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    // the command log wins over the snapshot when both exist, like Redis with AOF enabled
+    // an RDB import is applied on top of what was loaded
+    pub async fn load(&self) -> Result<(), String> {
+        if let Some(persistence) = &self.persistence {
+            persistence.load_data(self).await?;
+        } else if !self.settings.recover_to.is_empty() {
+            return Err("recovery needs persistence enabled".to_string());
+        } else {
+            let snapshot = Path::new(&self.settings.snapshot_file);
+            if snapshot.exists() {
+                self.load_snapshot(snapshot)?;
+                // an unencrypted snapshot is written again sealed
+                if self.settings.migrate_encryption {
+                    self.save().await?;
+                }
+            }
+        }
+        if !self.settings.import_rdb.is_empty() {
+            let import = self
+                .import_rdb(Path::new(&self.settings.import_rdb))
+                .await?;
+            println!("{}: {import}", self.settings.import_rdb);
+        }
+        Ok(())
+    }
+
+    pub fn persistence(&self) -> Option<&Persistence> {
+        self.persistence.as_ref()
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.all_dbs().iter().map(|db| db.used_memory()).sum()
+    }
+
+    pub fn databases(&self) -> usize {
+        self.dbs.read().unwrap().len()
+    }
+
+    pub(crate) fn db(&self, index: usize) -> Option<Arc<Keyspace>> {
+        self.dbs.read().unwrap().get(index).cloned()
+    }
+
+    pub(crate) fn all_dbs(&self) -> Vec<Arc<Keyspace>> {
+        self.dbs.read().unwrap().clone()
+    }
+
+    pub(crate) fn new_data(&self, value: Value) -> StoredData {
+        let threshold = self.settings.compress_values_above;
+        let value = match value {
+            Value::Str(s) if threshold > 0 && s.len() > threshold => {
+                match Packed::new(self.settings.compression, &s) {
+                    Some(packed) => Value::Packed(packed),
+                    None => Value::Str(s),
+                }
+            }
+            value => value,
+        };
+        StoredData::new(value).created_at(self.clock_ms())
+    }
+
+    pub(crate) fn clock_ms(&self) -> u64 {
+        self.clock.now().duration_since(self.epoch).as_millis() as u64
+    }
+}
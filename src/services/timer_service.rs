@@ -1,11 +1,300 @@
-use std::time::Duration;
-
-pub fn do_after_delay<F>(f: F, delay: Duration)
-where
-    F: Fn() + Send + 'static,
-{
-    tokio::task::spawn(async move {
-        tokio::time::sleep(delay).await;
-        f();
-    });
+use crate::services::clock::{Clock, MonotonicClock};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+type Job = Arc<dyn Fn() + Send + Sync>;
+
+struct Task {
+    name: String,
+    deadline: Instant,
+    seq: u64,
+    interval: Option<Duration>,
+    job: Job,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    next_seq: u64,
+    // ordered by (deadline, seq) so tasks due at the same instant run in the order they were scheduled
+    queue: BTreeMap<(Instant, u64), u64>,
+    tasks: HashMap<u64, Task>,
+    // finished once its runtime shut down, a new one is then spawned in the current runtime
+    driver: Option<JoinHandle<()>>,
+}
+
+impl State {
+    fn enqueue(&mut self, id: u64, deadline: Instant) {
+        let task = self.tasks.get_mut(&id).unwrap();
+        self.queue.remove(&(task.deadline, task.seq));
+        self.next_seq += 1;
+        task.deadline = deadline;
+        task.seq = self.next_seq;
+        self.queue.insert((deadline, task.seq), id);
+    }
+}
+
+struct Inner {
+    state: Mutex<State>,
+    // outlives the scheduler so the driver is woken to exit when it is dropped
+    notify: Arc<Notify>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.notify.notify_one();
+    }
+}
+
+#[derive(Clone)]
+pub struct Scheduler {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: String,
+    pub deadline: Instant,
+    pub interval: Option<Duration>,
+}
+
+#[derive(Clone)]
+pub struct TaskHandle {
+    id: u64,
+    scheduler: Scheduler,
+}
+
+impl std::fmt::Debug for TaskHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TaskHandle").field("id", &self.id).finish()
+    }
+}
+
+impl TaskHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn cancel(&self) -> bool {
+        self.scheduler.cancel(self.id)
+    }
+
+    pub fn reschedule(&self, delay: Duration) -> bool {
+        self.scheduler.reschedule(self.id, delay)
+    }
+
+    pub fn is_scheduled(&self) -> bool {
+        self.scheduler
+            .inner
+            .state
+            .lock()
+            .unwrap()
+            .tasks
+            .contains_key(&self.id)
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(MonotonicClock::new()))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        let scheduler = Scheduler {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State::default()),
+                notify: Arc::new(Notify::new()),
+                clock: clock.clone(),
+            }),
+        };
+        // a clock jumping ahead runs what became due at once, and the driver sleeps anew
+        let inner = Arc::downgrade(&scheduler.inner);
+        clock.on_advance(Arc::new(move || {
+            let Some(inner) = inner.upgrade() else {
+                return false;
+            };
+            let scheduler = Scheduler { inner };
+            scheduler.run_pending();
+            scheduler.inner.notify.notify_one();
+            true
+        }));
+        scheduler
+    }
+
+    pub fn schedule_once<F>(&self, name: &str, delay: Duration, f: F) -> TaskHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.add(name, delay, None, Arc::new(f))
+    }
+
+    pub fn schedule_every<F>(&self, name: &str, interval: Duration, f: F) -> TaskHandle
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.add(name, interval, Some(interval), Arc::new(f))
+    }
+
+    fn add(&self, name: &str, delay: Duration, interval: Option<Duration>, job: Job) -> TaskHandle {
+        let deadline = self.inner.clock.now() + delay;
+        let id = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.next_id += 1;
+            let id = state.next_id;
+            state.tasks.insert(
+                id,
+                Task {
+                    name: name.to_string(),
+                    deadline,
+                    seq: 0,
+                    interval,
+                    job,
+                },
+            );
+            state.enqueue(id, deadline);
+            id
+        };
+        self.wake_driver();
+        TaskHandle {
+            id,
+            scheduler: self.clone(),
+        }
+    }
+
+    pub fn cancel(&self, id: u64) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+        match state.tasks.remove(&id) {
+            Some(task) => {
+                state.queue.remove(&(task.deadline, task.seq));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn reschedule(&self, id: u64, delay: Duration) -> bool {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            if !state.tasks.contains_key(&id) {
+                return false;
+            }
+            state.enqueue(id, self.inner.clock.now() + delay);
+        }
+        self.wake_driver();
+        true
+    }
+
+    pub fn list(&self) -> Vec<TaskInfo> {
+        let state = self.inner.state.lock().unwrap();
+        state
+            .queue
+            .values()
+            .map(|id| {
+                let task = &state.tasks[id];
+                TaskInfo {
+                    id: *id,
+                    name: task.name.clone(),
+                    deadline: task.deadline,
+                    interval: task.interval,
+                }
+            })
+            .collect()
+    }
+
+    // runs every task due by the clock's `now` in deadline order and returns how many ran
+    pub fn run_pending(&self) -> usize {
+        let now = self.inner.clock.now();
+        let mut ran = 0;
+        loop {
+            let job = {
+                let mut state = self.inner.state.lock().unwrap();
+                let Some((&(deadline, seq), &id)) = state.queue.iter().next() else {
+                    break;
+                };
+                if deadline > now {
+                    break;
+                }
+                state.queue.remove(&(deadline, seq));
+                let task = state.tasks.get(&id).unwrap();
+                let job = task.job.clone();
+                match task.interval {
+                    Some(interval) => {
+                        let mut next = deadline + interval;
+                        if next <= now {
+                            next = now + interval;
+                        }
+                        state.enqueue(id, next);
+                    }
+                    None => {
+                        state.tasks.remove(&id);
+                    }
+                }
+                job
+            };
+            job();
+            ran += 1;
+        }
+        ran
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let state = self.inner.state.lock().unwrap();
+        state.queue.keys().next().map(|(deadline, _)| *deadline)
+    }
+
+    fn wake_driver(&self) {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            if state
+                .driver
+                .as_ref()
+                .is_none_or(|driver| driver.is_finished())
+            {
+                let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+                    return;
+                };
+                let scheduler = Arc::downgrade(&self.inner);
+                let notify = self.inner.notify.clone();
+                state.driver = Some(runtime.spawn(drive(scheduler, notify)));
+            }
+        }
+        self.inner.notify.notify_one();
+    }
+}
+
+// holds the scheduler only while running its tasks, and exits once it is dropped
+async fn drive(inner: Weak<Inner>, notify: Arc<Notify>) {
+    loop {
+        let delay = {
+            let Some(inner) = inner.upgrade() else {
+                return;
+            };
+            let scheduler = Scheduler { inner };
+            scheduler.run_pending();
+            // sleep for the clock's remaining time so a manual clock is never busy-polled
+            scheduler
+                .next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(scheduler.inner.clock.now()))
+        };
+        match delay {
+            Some(delay) => {
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = notify.notified() => {}
+                }
+            }
+            None => notify.notified().await,
+        }
+    }
 }
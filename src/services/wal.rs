@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+// the log is a directory of numbered segments; only the last one is appended to,
+// so closed segments never change again and can be shipped off as they are
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    // the segment replay starts from, it begins with the latest snapshot of the keyspace
+    pub base: u64,
+    // ascending, older segments than the base are kept only as history
+    pub segments: Vec<u64>,
+    // where replay can start from, for recovering an earlier state
+    #[serde(default)]
+    pub snapshots: Vec<SnapshotPoint>,
+}
+
+// a segment holding the whole keyspace as of `unix_ms`, the first segment starts
+// from an empty one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotPoint {
+    pub segment: u64,
+    pub unix_ms: u64,
+}
+
+impl Manifest {
+    pub(crate) fn new() -> Self {
+        Manifest {
+            base: 1,
+            segments: vec![1],
+            snapshots: vec![SnapshotPoint {
+                segment: 1,
+                unix_ms: 0,
+            }],
+        }
+    }
+
+    pub fn read(dir: &Path) -> io::Result<Option<Manifest>> {
+        match std::fs::read(dir.join(MANIFEST_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // written aside and renamed so a crash leaves either the old or the new manifest
+    pub(crate) fn write(&self, dir: &Path) -> io::Result<()> {
+        let tmp = dir.join(format!("{MANIFEST_FILE}.tmp"));
+        let file = File::create(&tmp)?;
+        serde_json::to_writer_pretty(&file, self).map_err(io::Error::other)?;
+        file.sync_data()?;
+        std::fs::rename(tmp, dir.join(MANIFEST_FILE))
+    }
+
+    pub fn active(&self) -> u64 {
+        self.segments.last().copied().unwrap_or(self.base)
+    }
+
+    // the segments replayed at startup
+    pub fn live(&self) -> impl Iterator<Item = u64> + '_ {
+        self.segments.iter().copied().filter(|id| *id >= self.base)
+    }
+
+    // the segments replayed from `start` on, skipping later snapshots since replaying up to
+    // them already rebuilt what they hold
+    pub(crate) fn replayed_from(&self, start: u64) -> impl Iterator<Item = u64> + '_ {
+        self.segments.iter().copied().filter(move |id| {
+            *id == start || (*id > start && !self.snapshots.iter().any(|s| s.segment == *id))
+        })
+    }
+
+    // the closed segments, everything but the one being appended to
+    pub fn closed(&self) -> &[u64] {
+        &self.segments[..self.segments.len().saturating_sub(1)]
+    }
+
+    // takes the segments in `range` out of the log, returning them
+    pub(crate) fn remove(&mut self, range: Range<u64>) -> Vec<u64> {
+        let start = self.segments.partition_point(|id| *id < range.start);
+        let end = self.segments.partition_point(|id| *id < range.end);
+        let removed: Vec<u64> = self.segments.drain(start..end).collect();
+        self.snapshots.retain(|s| !removed.contains(&s.segment));
+        removed
+    }
+
+    // drops all but the newest `keep` segments older than the base, returning the dropped ones
+    pub(crate) fn retain(&mut self, keep: usize) -> Vec<u64> {
+        let older = self.segments.iter().filter(|id| **id < self.base).count();
+        let dropped: Vec<u64> = self.segments.drain(..older.saturating_sub(keep)).collect();
+        self.snapshots.retain(|s| !dropped.contains(&s.segment));
+        dropped
+    }
+}
+
+pub fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id:08}.wal"))
+}
+
+pub(crate) fn open_segment(dir: &Path, id: u64) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .read(true)
+        .open(segment_path(dir, id))
+}
@@ -1,8 +1,17 @@
-use kvds::{app_server::parser::Command, connector::connector::Connector};
+use std::{sync::mpsc, sync::Arc, thread};
+
+use kvds::{
+    app_server::{parser::Command, socket_server::AppServer},
+    connector::connector::Connector,
+    services::store::Store,
+};
 
 #[cfg(test)]
 mod tests {
-    use kvds::app_server::parser::{parse_command, Command};
+    use kvds::app_server::{
+        parser::{command_len, parse_command, Command},
+        reply::Reply,
+    };
 
     #[test]
     fn parse_get_command() {
@@ -16,39 +25,67 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn variadic_commands_round_trip() {
+        let cmd = Command::cmd_lpush("list", &["a", "b", "c"]);
+        assert_eq!(parse_command(cmd.to_string()).unwrap(), cmd);
+
+        let cmd = Command::cmd_lrange("list", 0, -1);
+        assert_eq!(parse_command(cmd.to_string()).unwrap(), cmd);
+    }
+
+    #[test]
+    fn bulk_lengths_count_bytes() {
+        let cmd = Command::cmd_set("clé", "a\r\nb ✓");
+        assert_eq!(parse_command(cmd.to_string()).unwrap(), cmd);
+        assert!(parse_command("*2\r\n$3\r\nGET\r\n$9\r\nkey".to_string()).is_err());
+    }
+
+    #[test]
+    fn command_len_waits_for_the_whole_command() {
+        let get = Command::cmd_get("key").to_string();
+        let two = format!("{get}{get}");
+        assert_eq!(command_len(two.as_bytes(), 64), Ok(Some(get.len())));
+        assert_eq!(command_len(&get.as_bytes()[..get.len() - 1], 64), Ok(None));
+        assert_eq!(command_len(b"*2\r\n$3\r\nGE", 64), Ok(None));
+        assert_eq!(command_len(b"PING\r\n", 64), Ok(Some(6)));
+    }
+
+    #[test]
+    fn command_len_rejects_oversized_bulks() {
+        let huge = format!("*1\r\n${}\r\n", usize::MAX);
+        assert!(command_len(huge.as_bytes(), usize::MAX).is_err());
+        assert!(command_len(huge.as_bytes(), 64).is_err());
+        assert!(command_len(b"*1\r\n$65\r\n", 64).is_err());
+        assert_eq!(command_len(b"*1\r\n$64\r\n", 64), Ok(None));
+        assert_eq!(Reply::decode(huge.as_bytes()), None);
+    }
 }
 
 #[cfg(test)]
 mod base_command_tests {
-    use std::{
-        thread::{self, sleep},
-        time::Duration,
-    };
+    use std::{sync::Arc, thread, time::Duration};
 
-    use ctor::ctor;
     use kvds::{
-        app_server::{parser::Command, socket_server::AppServer},
+        app_server::parser::Command,
         connector::connector::Connector,
+        services::{clock::ManualClock, store::Store},
+        Settings,
     };
-    use serial_test::serial;
 
-    use crate::flush_all;
+    use crate::{flush_all, start_server};
 
-    #[ctor]
-    fn global_setup() {
-        thread::spawn(|| {
-            tokio::runtime::Runtime::new()
-                .unwrap()
-                .block_on(async { AppServer::new("7878", false).start().await });
-        });
-        thread::sleep(Duration::from_millis(300));
+    fn with_manual_clock() -> (Arc<Store>, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        let store = Store::with_clock(Settings::default(), clock.clone());
+        (store, clock)
     }
 
-    #[serial]
     #[test]
     fn set_then_get_and_delete() {
         // ======================= SET A VALUE =======================
-        let c = Connector::with_port("7878");
+        let c = Connector::with_port(&start_server(Store::new(Settings::default())));
 
         let resp = c.call_server(Command::cmd_set("some-key", "some-value"));
         assert_eq!(resp, "+OK\r\n");
@@ -68,14 +105,13 @@ mod base_command_tests {
         let resp = c.call_server(Command::cmd_get("some-key"));
         assert_eq!(resp, "$-1\r\n");
 
-        flush_all()
+        flush_all(&c)
     }
 
-    #[serial]
     #[test]
     fn keys_by_pattern() {
         // ======================= SET SOME VALUES ========================
-        let c = Connector::with_port("7878");
+        let c = Connector::with_port(&start_server(Store::new(Settings::default())));
 
         let keys = vec!["key1", "key2", "key3", "key4", "key5"];
         for k in &keys {
@@ -88,14 +124,14 @@ mod base_command_tests {
         result.sort();
         assert_eq!(keys, result);
 
-        flush_all()
+        flush_all(&c)
     }
 
-    #[serial]
     #[test]
     fn expire_time_for_a_key() {
+        let (store, clock) = with_manual_clock();
         // ======================= SET A KEY WITH EXPIRATION ========================
-        let c = Connector::with_port("7878");
+        let c = Connector::with_port(&start_server(store));
 
         let resp = c.call_server(Command::cmd_set("some-key", "some-value"));
         assert_eq!(resp, "+OK\r\n");
@@ -105,23 +141,43 @@ mod base_command_tests {
         let resp = c.call_server(Command::cmd_expire("some-key", 1));
         assert_eq!(resp, ":1\r\n");
         // ======
+        clock.advance(Duration::from_millis(999));
         let resp = c.call_server(Command::cmd_get("some-key"));
         assert_eq!(resp, "$10\r\nsome-value\r\n");
 
         // =================== GET THE VALUE AFTER A SECOND =========================
 
-        sleep(Duration::from_millis(1_001));
+        clock.advance(Duration::from_millis(1));
         let resp = c.call_server(Command::cmd_get("some-key"));
         assert_eq!(resp, "$-1\r\n");
 
-        flush_all()
+        flush_all(&c)
+    }
+
+    #[test]
+    fn overwrite_cancels_expiration() {
+        let (store, clock) = with_manual_clock();
+        let c = Connector::with_port(&start_server(store.clone()));
+
+        c.call_server(Command::cmd_set("some-key", "old-value"));
+        let resp = c.call_server(Command::cmd_expire("some-key", 1));
+        assert_eq!(resp, ":1\r\n");
+
+        // =============== SET AGAIN BEFORE THE KEY EXPIRES =====================
+        c.call_server(Command::cmd_set("some-key", "new-value"));
+
+        clock.advance(Duration::from_secs(1));
+        let resp = c.call_server(Command::cmd_get("some-key"));
+        assert_eq!(resp, "$9\r\nnew-value\r\n");
+
+        flush_all(&c)
     }
 
-    #[serial]
     #[test]
     fn ttl_for_a_key() {
+        let (store, clock) = with_manual_clock();
         // ======================= SET A KEY WITH EXPIRATION ========================
-        let c = Connector::with_port("7878");
+        let c = Connector::with_port(&start_server(store));
 
         let resp = c.call_server(Command::cmd_set("a-key", "some-value"));
         assert_eq!(resp, "+OK\r\n");
@@ -132,21 +188,70 @@ mod base_command_tests {
         assert_eq!(resp, ":1\r\n");
         // ======
         let resp = c.call_server(Command::cmd_ttl("a-key"));
+        assert_eq!(resp, ":2\r\n");
+
+        clock.advance(Duration::from_secs(1));
+        let resp = c.call_server(Command::cmd_ttl("a-key"));
         assert_eq!(resp, ":1\r\n");
 
-        // =================== GET THE VALUE AFTER A SECOND =========================
+        // =================== GET THE VALUE AFTER TWO SECONDS ======================
 
-        sleep(Duration::from_millis(2_001));
+        clock.advance(Duration::from_secs(1));
         let resp = c.call_server(Command::cmd_get("a-key"));
         assert_eq!(resp, "$-1\r\n");
+        let resp = c.call_server(Command::cmd_ttl("a-key"));
+        assert_eq!(resp, ":-2\r\n");
+
+        flush_all(&c)
+    }
+
+    #[test]
+    fn scheduled_expiry_removes_the_key() {
+        let (store, clock) = with_manual_clock();
+        let c = Connector::with_port(&start_server(store.clone()));
+
+        c.call_server(Command::cmd_set("some-key", "some-value"));
+        c.call_server(Command::cmd_expire("some-key", 5));
+
+        // ============ THE SCHEDULER FIRES ONLY ONCE THE CLOCK IS DUE ==============
+        clock.advance(Duration::from_secs(4));
+        assert!(store
+            .scheduler()
+            .list()
+            .iter()
+            .any(|t| t.name == "expire:some-key"));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(!store
+            .scheduler()
+            .list()
+            .iter()
+            .any(|t| t.name == "expire:some-key"));
+        let resp = c.call_server(Command::cmd_keys("*"));
+        assert_eq!(resp, "*0\r\n");
 
-        flush_all()
+        flush_all(&c)
+    }
+
+    #[test]
+    fn independent_stores_do_not_share_keys() {
+        let first = Connector::with_port(&start_server(Store::new(Settings::default())));
+        let second = Connector::with_port(&start_server(Store::new(Settings::default())));
+
+        first.call_server(Command::cmd_set("some-key", "first"));
+        second.call_server(Command::cmd_set("some-key", "second"));
+
+        assert_eq!(first.get("some-key"), Some("first".to_string()));
+        assert_eq!(second.get("some-key"), Some("second".to_string()));
+
+        flush_all(&first);
+        assert_eq!(second.get("some-key"), Some("second".to_string()));
     }
 
-    #[serial]
     #[test]
     fn incre_and_decr() {
-        let c = Connector::with_port("7878");
+        let port = start_server(Store::new(Settings::default()));
+        let c = Connector::with_port(&port);
         // ======================= SET A KEY ========================
         let resp = c.call_server(Command::cmd_set("a-key", "100"));
         assert_eq!(resp, "+OK\r\n");
@@ -154,8 +259,9 @@ mod base_command_tests {
         // =================== INCR AND DECR ========================
         let mut hs = Vec::new();
         for _ in 1..101 {
-            let h = thread::spawn(|| {
-                let cn = Connector::with_port("7878");
+            let port = port.clone();
+            let h = thread::spawn(move || {
+                let cn = Connector::with_port(&port);
                 cn.call_server(Command::cmd_incr("a-key"));
                 thread::sleep(Duration::from_millis(1));
                 cn.call_server(Command::cmd_decr("a-key"));
@@ -178,57 +284,2891 @@ mod base_command_tests {
         let resp = c.call_server(Command::cmd_decr("a-key"));
         assert_eq!(resp, "-ERR value is not an integer or out of range\r\n");
 
-        flush_all()
+        flush_all(&c)
     }
 }
 
-mod connector_tests {
-    use std::{thread, time::Duration};
+#[cfg(test)]
+mod timer_tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        thread::sleep,
+        time::Duration,
+    };
+
+    use kvds::services::timer_service::Scheduler;
+
+    #[test]
+    fn due_tasks_run_in_scheduled_order() {
+        let scheduler = Scheduler::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..5 {
+            let order = order.clone();
+            scheduler.schedule_once("task", Duration::ZERO, move || {
+                order.lock().unwrap().push(i);
+            });
+        }
 
-    use kvds::{app_server::socket_server::AppServer, connector::connector::Connector};
-    use serial_test::serial;
+        assert_eq!(scheduler.run_pending(), 5);
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+        assert!(scheduler.list().is_empty());
+    }
 
-    use crate::flush_all;
-    use ctor::ctor;
+    #[test]
+    fn cancel_reschedule_and_list() {
+        let scheduler = Scheduler::new();
+        let count = Arc::new(AtomicUsize::new(0));
 
-    #[ctor]
-    fn global_setup() {
-        thread::spawn(|| {
-            tokio::runtime::Runtime::new()
-                .unwrap()
-                .block_on(async { AppServer::new("7879", false).start().await });
+        let c = count.clone();
+        let first = scheduler.schedule_once("first", Duration::ZERO, move || {
+            c.fetch_add(1, Ordering::SeqCst);
         });
-        thread::sleep(Duration::from_millis(300));
+        let c = count.clone();
+        let second = scheduler.schedule_once("second", Duration::from_secs(60), move || {
+            c.fetch_add(10, Ordering::SeqCst);
+        });
+
+        let names = scheduler
+            .list()
+            .into_iter()
+            .map(|t| t.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["first", "second"]);
+
+        // ===================== CANCEL AND RESCHEDULE =======================
+        assert!(first.cancel());
+        assert!(!first.cancel());
+        assert!(second.reschedule(Duration::ZERO));
+
+        assert_eq!(scheduler.run_pending(), 1);
+        assert_eq!(count.load(Ordering::SeqCst), 10);
+        assert!(!second.is_scheduled());
     }
 
-    #[serial]
     #[test]
-    fn test_connector() {
-        // ======================= SET SOME VALUES ========================
-        let c = Connector::with_port("7879");
+    fn recurring_job_runs_until_cancelled() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        let scheduler = Scheduler::new();
 
-        let keys = vec!["key1", "key2", "key3", "key4", "key5"];
-        for k in &keys {
-            c.insert(k, "some-value");
+        let c = count.clone();
+        let handle = runtime.block_on(async {
+            scheduler.schedule_every("tick", Duration::from_millis(20), move || {
+                c.fetch_add(1, Ordering::SeqCst);
+            })
+        });
+        sleep(Duration::from_millis(150));
+        assert!(count.load(Ordering::SeqCst) >= 3);
+        assert_eq!(
+            scheduler.list()[0].interval,
+            Some(Duration::from_millis(20))
+        );
+
+        // ========================= STOP THE JOB ============================
+        handle.cancel();
+        let stopped_at = count.load(Ordering::SeqCst);
+        sleep(Duration::from_millis(60));
+        assert_eq!(count.load(Ordering::SeqCst), stopped_at);
+    }
+
+    #[test]
+    fn driver_follows_the_runtime_and_ends_with_the_scheduler() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let scheduler = Scheduler::new();
+        let first = tokio::runtime::Runtime::new().unwrap();
+        first.block_on(async {
+            scheduler.schedule_once("never", Duration::from_secs(60), || {});
+        });
+        drop(first);
+
+        // ===================== A NEW RUNTIME DRIVES IT =====================
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let c = count.clone();
+        runtime.block_on(async {
+            scheduler.schedule_once("soon", Duration::from_millis(10), move || {
+                c.fetch_add(1, Ordering::SeqCst);
+            });
+        });
+        sleep(Duration::from_millis(100));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // ================ DROPPING THE SCHEDULER FREES ITS TASKS ===========
+        let c = count.clone();
+        scheduler.schedule_once("later", Duration::from_secs(60), move || {
+            c.fetch_add(1, Ordering::SeqCst);
+        });
+        drop(scheduler);
+        sleep(Duration::from_millis(50));
+        assert_eq!(Arc::strong_count(&count), 1);
+    }
+}
+
+#[cfg(test)]
+mod embedded_tests {
+    use std::{sync::Arc, time::Duration};
+
+    use kvds::{
+        app_server::{parser::Command, reply::Reply},
+        embedded::Db,
+        services::{clock::ManualClock, persistence_service::AppendFsync, store::Store},
+        Settings,
+    };
+
+    use crate::temp_wal_dir;
+
+    #[tokio::test]
+    async fn typed_string_and_counter_commands() {
+        let db = Db::with_store(Store::new(Settings::default()));
+
+        db.set("some-key", "some-value").await.unwrap();
+        assert_eq!(db.get("some-key").await, Ok(Some("some-value".to_string())));
+        assert!(db.del("some-key").await.unwrap());
+        assert_eq!(db.get("some-key").await, Ok(None));
+
+        // ========================== COUNTERS ===============================
+        db.set("counter", "41").await.unwrap();
+        assert_eq!(db.incr("counter").await, Ok(42));
+        assert_eq!(db.decr("counter").await, Ok(41));
+        assert_eq!(db.get("counter").await, Ok(Some("41".to_string())));
+
+        db.set("counter", "not-number").await.unwrap();
+        assert!(db.incr("counter").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn lists_and_wrong_type() {
+        let db = Db::with_store(Store::new(Settings::default()));
+
+        assert_eq!(db.lpush("list", &["b", "a"]).await, Ok(2));
+        assert_eq!(db.rpush("list", &["c"]).await, Ok(3));
+        assert_eq!(db.lrange("list", 0, -1).await.unwrap(), vec!["a", "b", "c"]);
+        assert_eq!(db.lrange("list", -2, 10).await.unwrap(), vec!["b", "c"]);
+
+        assert_eq!(db.lpop("list").await, Ok(Some("a".to_string())));
+        assert_eq!(db.rpop("list").await, Ok(Some("c".to_string())));
+        assert_eq!(db.llen("list").await, Ok(1));
+
+        // ======================= WRONG TYPE ================================
+        let err = db.get("list").await.unwrap_err();
+        assert!(err.0.starts_with("WRONGTYPE"));
+
+        // ================ THE EMPTIED LIST IS REMOVED ======================
+        db.lpop("list").await.unwrap();
+        assert!(db.keys("*").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn multi_key_commands_across_shards() {
+        let db = Db::with_store(Store::new(Settings {
+            shards: 4,
+            ..Settings::default()
+        }));
+        let pairs = (0..32)
+            .map(|i| (format!("key{i}"), format!("value{i}")))
+            .collect::<Vec<_>>();
+        let pairs = pairs
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(db.execute(Command::cmd_mset(&pairs)).await, Reply::ok());
+        assert_eq!(db.keys("*").await.unwrap().len(), 32);
+
+        let reply = db
+            .execute(Command::cmd_mget(&["key3", "missing", "key31"]))
+            .await;
+        assert_eq!(
+            reply,
+            Reply::Array(vec![
+                Reply::bulk("value3"),
+                Reply::nil(),
+                Reply::bulk("value31")
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn incr_keeps_the_expiration() {
+        let clock = Arc::new(ManualClock::new());
+        let db = Db::with_store(Store::with_clock(Settings::default(), clock));
+
+        db.set_ex("counter", "1", 100).await.unwrap();
+        assert_eq!(db.incr("counter").await, Ok(2));
+        assert_eq!(db.ttl("counter").await, Ok(100));
+    }
+
+    #[tokio::test]
+    async fn set_ex_expires_with_the_store_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let db = Db::with_store(Store::with_clock(Settings::default(), clock.clone()));
+
+        db.set_ex("session", "data", 10).await.unwrap();
+        assert_eq!(db.ttl("session").await, Ok(10));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(db.get("session").await, Ok(None));
+        assert_eq!(db.ttl("session").await, Ok(-2));
+    }
+
+    #[tokio::test]
+    async fn shares_persistence_with_the_server() {
+        let settings = Settings {
+            wal_dir: temp_wal_dir("embedded"),
+            persist: true,
+            ..Settings::default()
+        };
+
+        let db = Db::open(settings.clone()).await;
+        db.set("some-key", "some-value").await.unwrap();
+        db.rpush("list", &["a", "b"]).await.unwrap();
+        db.incr("counter").await.unwrap();
+        // give the log writer a moment to flush the queue
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let reopened = Db::open(settings).await;
+        assert_eq!(
+            reopened.get("some-key").await,
+            Ok(Some("some-value".to_string()))
+        );
+        assert_eq!(
+            reopened.lrange("list", 0, -1).await.unwrap(),
+            vec!["a", "b"]
+        );
+        assert_eq!(reopened.get("counter").await, Ok(Some("1".to_string())));
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_keep_each_others_select() {
+        let settings = Settings {
+            wal_dir: temp_wal_dir("embedded-concurrent"),
+            persist: true,
+            appendfsync: AppendFsync::Always,
+            ..Settings::default()
+        };
+        let db = Db::open(settings).await;
+
+        // the SET waits for its fsync while the SELECT runs
+        let (set, select) = tokio::join!(db.set("first", "value"), db.select(1));
+        set.unwrap();
+        select.unwrap();
+        db.set("second", "value").await.unwrap();
+        assert_eq!(db.keys("*").await, Ok(vec!["second".to_string()]));
+        assert_eq!(db.clone().keys("*").await, Ok(vec!["second".to_string()]));
+    }
+}
+
+#[cfg(test)]
+mod database_tests {
+    use std::{sync::Arc, time::Duration};
+
+    use kvds::{
+        app_server::parser::{parse_command, Command},
+        embedded::Db,
+        services::{clock::ManualClock, store::Store},
+        Settings,
+    };
+
+    use crate::temp_wal_dir;
+
+    #[test]
+    fn database_commands_round_trip() {
+        for cmd in [
+            Command::cmd_select(3),
+            Command::cmd_move("key", 1),
+            Command::cmd_swapdb(0, 2),
+            Command::FLUSHDB,
+        ] {
+            assert_eq!(parse_command(cmd.to_string()).unwrap(), cmd);
         }
+    }
 
-        // ==================== GET KEYS BY PATTERN =======================
+    #[tokio::test]
+    async fn select_isolates_keys() {
+        let db = Db::with_store(Store::new(Settings::default()));
+        let other = db.clone();
 
-        let mut result = c.keys("*");
-        result.sort();
-        assert_eq!(keys, result);
+        db.set("key", "zero").await.unwrap();
+        other.select(1).await.unwrap();
+        assert_eq!(other.get("key").await, Ok(None));
+        other.set("key", "one").await.unwrap();
+        assert_eq!(db.get("key").await, Ok(Some("zero".to_string())));
 
-        let value = c.get("key1");
-        assert_eq!(value, Some("some-value".to_string()));
+        let err = db.select(16).await.unwrap_err();
+        assert_eq!(err.0, "ERR DB index is out of range");
 
-        let value = c.get("unknown");
-        assert_eq!(value, None);
+        // ================ FLUSHDB ONLY CLEARS ONE DATABASE =================
+        other.flush_db().await.unwrap();
+        assert_eq!(other.get("key").await, Ok(None));
+        assert_eq!(db.get("key").await, Ok(Some("zero".to_string())));
+
+        other.set("key", "one").await.unwrap();
+        db.flush_all().await.unwrap();
+        assert_eq!(other.get("key").await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn move_keeps_the_expiration() {
+        let clock = Arc::new(ManualClock::new());
+        let db = Db::with_store(Store::with_clock(Settings::default(), clock.clone()));
 
-        flush_all()
+        db.set_ex("key", "value", 10).await.unwrap();
+        assert!(db.move_key("key", 2).await.unwrap());
+        assert!(!db.move_key("key", 2).await.unwrap());
+        assert_eq!(db.get("key").await, Ok(None));
+
+        db.select(2).await.unwrap();
+        assert_eq!(db.ttl("key").await, Ok(10));
+
+        // ============ AN EXISTING TARGET KEY IS NOT OVERWRITTEN ============
+        db.set("taken", "two").await.unwrap();
+        db.select(0).await.unwrap();
+        db.set("taken", "zero").await.unwrap();
+        assert!(!db.move_key("taken", 2).await.unwrap());
+        assert!(db.move_key("taken", 0).await.is_err());
+
+        db.select(2).await.unwrap();
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(db.keys("*").await.unwrap(), vec!["taken"]);
+    }
+
+    #[tokio::test]
+    async fn swapdb_swaps_contents() {
+        let db = Db::with_store(Store::new(Settings {
+            databases: 4,
+            ..Settings::default()
+        }));
+
+        db.set("key", "zero").await.unwrap();
+        db.swap_db(0, 3).await.unwrap();
+        assert_eq!(db.get("key").await, Ok(None));
+
+        db.select(3).await.unwrap();
+        assert_eq!(db.get("key").await, Ok(Some("zero".to_string())));
+        assert!(db.swap_db(0, 4).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn persisted_commands_replay_into_their_database() {
+        let settings = Settings {
+            wal_dir: temp_wal_dir("databases"),
+            persist: true,
+            ..Settings::default()
+        };
+
+        let db = Db::open(settings.clone()).await;
+        db.set("key", "zero").await.unwrap();
+        db.select(5).await.unwrap();
+        db.set("key", "five").await.unwrap();
+        db.rpush("list", &["a"]).await.unwrap();
+        db.move_key("list", 6).await.unwrap();
+        db.select(1).await.unwrap();
+        db.swap_db(1, 6).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let reopened = Db::open(settings).await;
+        assert_eq!(reopened.get("key").await, Ok(Some("zero".to_string())));
+        reopened.select(5).await.unwrap();
+        assert_eq!(reopened.get("key").await, Ok(Some("five".to_string())));
+        reopened.select(1).await.unwrap();
+        assert_eq!(reopened.lrange("list", 0, -1).await.unwrap(), vec!["a"]);
     }
 }
 
-pub fn flush_all() {
-    let resp = Connector::with_port("7878").call_server(Command::FLUSHALL);
-    assert_eq!(resp, "+OK\r\n");
+#[cfg(test)]
+mod persistence_tests {
+    use std::{
+        io::Write,
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use kvds::{
+        app_server::{
+            parser::{parse_command, Command},
+            reply::Reply,
+        },
+        embedded::Db,
+        services::{
+            clock::{Clock, ManualClock},
+            log_format::check_log,
+            persistence_service::AppendFsync,
+            store::Store,
+            wal::{segment_path, Manifest},
+        },
+        Settings,
+    };
+
+    use crate::temp_wal_dir;
+
+    fn logged(wal_dir: &str, appendfsync: AppendFsync) -> Settings {
+        Settings {
+            db_file: legacy_file(wal_dir),
+            wal_dir: wal_dir.to_string(),
+            persist: true,
+            appendfsync,
+            ..Settings::default()
+        }
+    }
+
+    // where a log from before segments is picked up
+    fn legacy_file(wal_dir: &str) -> String {
+        format!("{wal_dir}.log")
+    }
+
+    fn manifest(wal_dir: &str) -> Manifest {
+        Manifest::read(wal_dir.as_ref()).unwrap().unwrap()
+    }
+
+    fn active_segment(wal_dir: &str) -> std::path::PathBuf {
+        segment_path(wal_dir.as_ref(), manifest(wal_dir).active())
+    }
+
+    fn base_segment(wal_dir: &str) -> std::path::PathBuf {
+        segment_path(wal_dir.as_ref(), manifest(wal_dir).base)
+    }
+
+    #[tokio::test]
+    async fn always_replies_after_the_entry_is_written() {
+        let wal_dir = temp_wal_dir("always");
+        let db = Db::open(logged(&wal_dir, AppendFsync::Always)).await;
+
+        db.set("some-key", "some-value").await.unwrap();
+        let log = check_log(&std::fs::read(active_segment(&wal_dir)).unwrap(), 1, None);
+        assert_eq!(log.records, 2);
+
+        // a FLUSHALL is logged like any other write, history is kept
+        db.flush_all().await.unwrap();
+        let log = check_log(&std::fs::read(active_segment(&wal_dir)).unwrap(), 1, None);
+        assert_eq!(log.commands.last(), Some(&Command::FLUSHALL));
+        let reopened = Db::open(logged(&wal_dir, AppendFsync::No)).await;
+        assert_eq!(reopened.keys("*").await, Ok(vec![]));
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_share_the_log() {
+        let wal_dir = temp_wal_dir("group-commit");
+        let db = Db::open(logged(&wal_dir, AppendFsync::Always)).await;
+
+        let writes = (0..50).map(|i| {
+            let db = db.clone();
+            tokio::spawn(async move { db.set(&format!("key{i}"), "value").await })
+        });
+        for write in writes.collect::<Vec<_>>() {
+            write.await.unwrap().unwrap();
+        }
+
+        let reopened = Db::open(logged(&wal_dir, AppendFsync::No)).await;
+        assert_eq!(reopened.keys("*").await.unwrap().len(), 50);
+    }
+
+    #[tokio::test]
+    async fn rewrite_compacts_the_log() {
+        let wal_dir = temp_wal_dir("rewrite");
+        let settings = Settings {
+            aof_use_rdb_preamble: false,
+            ..logged(&wal_dir, AppendFsync::Always)
+        };
+        let db = Db::open(settings).await;
+
+        for _ in 0..100 {
+            db.incr("counter").await.unwrap();
+        }
+        db.rpush("list", &["a", "b"]).await.unwrap();
+        db.set("deleted", "value").await.unwrap();
+        db.del("deleted").await.unwrap();
+        db.select(3).await.unwrap();
+        db.set("other", "three").await.unwrap();
+        let before = db.store().persistence().unwrap().size();
+
+        db.store().rewrite_log().await.unwrap();
+        let log = std::fs::read(base_segment(&wal_dir)).unwrap();
+        assert!((log.len() as u64) < before);
+        // SELECT 0, counter, list, SELECT 3, other
+        assert_eq!(check_log(&log, 1, None).records, 5);
+
+        db.set("after", "rewrite").await.unwrap();
+        let reopened = Db::open(logged(&wal_dir, AppendFsync::No)).await;
+        assert_eq!(reopened.get("counter").await, Ok(Some("100".to_string())));
+        assert_eq!(
+            reopened.lrange("list", 0, -1).await.unwrap(),
+            vec!["a", "b"]
+        );
+        assert_eq!(reopened.get("deleted").await, Ok(None));
+        assert_eq!(reopened.get("after").await, Ok(None));
+        reopened.select(3).await.unwrap();
+        assert_eq!(reopened.get("other").await, Ok(Some("three".to_string())));
+        assert_eq!(reopened.get("after").await, Ok(Some("rewrite".to_string())));
+    }
+
+    #[tokio::test]
+    async fn rewrite_writes_a_snapshot_preamble() {
+        let wal_dir = temp_wal_dir("rewrite-hybrid");
+        let db = Db::open(logged(&wal_dir, AppendFsync::Always)).await;
+
+        for _ in 0..100 {
+            db.incr("counter").await.unwrap();
+        }
+        db.set_ex("volatile", "value", 100).await.unwrap();
+        db.store().rewrite_log().await.unwrap();
+        db.select(1).await.unwrap();
+        db.rpush("list", &["a", "b"]).await.unwrap();
+
+        let log = std::fs::read(base_segment(&wal_dir)).unwrap();
+        assert!(log.starts_with(b"KVDS"));
+
+        let reopened = Db::open(logged(&wal_dir, AppendFsync::No)).await;
+        assert_eq!(reopened.get("counter").await, Ok(Some("100".to_string())));
+        assert!((99..=100).contains(&reopened.ttl("volatile").await.unwrap()));
+        reopened.select(1).await.unwrap();
+        assert_eq!(
+            reopened.lrange("list", 0, -1).await.unwrap(),
+            vec!["a", "b"]
+        );
+    }
+
+    #[tokio::test]
+    async fn expirations_survive_a_restart() {
+        let wal_dir = temp_wal_dir("expirations");
+        let db = Db::open(logged(&wal_dir, AppendFsync::Always)).await;
+
+        db.set("expiring", "value").await.unwrap();
+        db.expire("expiring", 100).await.unwrap();
+        db.set_ex("setex", "value", 50).await.unwrap();
+        db.set("durable", "value").await.unwrap();
+
+        let reopen = |clock: Arc<ManualClock>| {
+            let settings = logged(&wal_dir, AppendFsync::No);
+            async move {
+                let store = Store::with_clock(settings, clock);
+                store.load().await.unwrap();
+                Db::with_store(store)
+            }
+        };
+
+        let reopened = reopen(Arc::new(ManualClock::new())).await;
+        assert!((99..=100).contains(&reopened.ttl("expiring").await.unwrap()));
+        assert!((49..=50).contains(&reopened.ttl("setex").await.unwrap()));
+        assert_eq!(reopened.ttl("durable").await, Ok(-1));
+
+        // =============== DEADLINES PASSED WHILE THE STORE WAS DOWN =============
+        let clock = Arc::new(ManualClock::new());
+        clock.advance(Duration::from_secs(60));
+        let reopened = reopen(clock).await;
+        assert!((39..=40).contains(&reopened.ttl("expiring").await.unwrap()));
+        assert_eq!(reopened.get("setex").await, Ok(None));
+        let mut keys = reopened.keys("*").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["durable", "expiring"]);
+    }
+
+    #[tokio::test]
+    async fn setex_is_logged_as_one_record() {
+        let wal_dir = temp_wal_dir("setex-record");
+        let clock = Arc::new(ManualClock::new());
+        let settings = logged(&wal_dir, AppendFsync::Always);
+        let db = Db::with_store(Store::with_clock(settings.clone(), clock.clone()));
+        db.set("first", "value").await.unwrap();
+        let records = |wal_dir: &str| {
+            check_log(&std::fs::read(active_segment(wal_dir)).unwrap(), 1, None)
+                .ends
+                .len()
+        };
+        let before = records(&wal_dir);
+        db.set_ex("setex", "value", 50).await.unwrap();
+        assert_eq!(records(&wal_dir), before + 1);
+
+        let store = Store::with_clock(settings, clock);
+        store.load().await.unwrap();
+        let reopened = Db::with_store(store);
+        assert_eq!(reopened.ttl("setex").await, Ok(50));
+        assert_eq!(reopened.get("setex").await, Ok(Some("value".to_string())));
+    }
+
+    #[test]
+    fn pexpireat_round_trip() {
+        let cmd = Command::cmd_pexpireat("key", 1_700_000_000_000);
+        assert_eq!(parse_command(cmd.to_string()).unwrap(), cmd);
+    }
+
+    async fn write_records(wal_dir: &str) -> u64 {
+        let db = Db::open(logged(wal_dir, AppendFsync::Always)).await;
+        db.set("some-key", "some-value").await.unwrap();
+        db.rpush("list", &["a", "b"]).await.unwrap();
+        std::fs::metadata(active_segment(wal_dir)).unwrap().len()
+    }
+
+    fn append(wal_dir: &str, bytes: &[u8]) {
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(active_segment(wal_dir))
+            .unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_torn_tail_is_truncated_at_startup() {
+        let wal_dir = temp_wal_dir("torn-tail");
+        let valid_len = write_records(&wal_dir).await;
+        append(&wal_dir, b"37 5c1f0e2a *3\\r\\n$3\\r\\nSET");
+
+        let check = check_log(&std::fs::read(active_segment(&wal_dir)).unwrap(), 1, None);
+        assert_eq!(check.valid_len as u64, valid_len);
+        assert!(check.corruption.unwrap().at_tail);
+
+        let reopened = Db::open(logged(&wal_dir, AppendFsync::No)).await;
+        assert_eq!(
+            reopened.get("some-key").await,
+            Ok(Some("some-value".to_string()))
+        );
+        assert_eq!(
+            std::fs::metadata(active_segment(&wal_dir)).unwrap().len(),
+            valid_len
+        );
+    }
+
+    #[tokio::test]
+    async fn strict_loading_refuses_a_torn_tail() {
+        let wal_dir = temp_wal_dir("torn-tail-strict");
+        write_records(&wal_dir).await;
+        append(&wal_dir, b"37 5c1f");
+
+        let settings = Settings {
+            aof_load_truncated: false,
+            ..logged(&wal_dir, AppendFsync::No)
+        };
+        let err = Store::new(settings).load().await.unwrap_err();
+        assert!(err.contains("incomplete record"));
+    }
+
+    #[tokio::test]
+    async fn corruption_before_valid_records_is_not_truncated() {
+        let wal_dir = temp_wal_dir("corrupted-middle");
+        let valid_len = write_records(&wal_dir).await;
+        let segment = active_segment(&wal_dir);
+        let mut bytes = std::fs::read(&segment).unwrap();
+        // inside the payload of the SET record, after the SELECT one
+        let set = bytes.windows(10).position(|w| w == b"some-value").unwrap();
+        bytes[set] = b'S';
+        std::fs::write(&segment, bytes).unwrap();
+
+        let err = Store::new(logged(&wal_dir, AppendFsync::No))
+            .load()
+            .await
+            .unwrap_err();
+        assert!(err.contains("checksum mismatch"));
+        assert_eq!(
+            std::fs::metadata(active_segment(&wal_dir)).unwrap().len(),
+            valid_len
+        );
+    }
+
+    #[tokio::test]
+    async fn a_corrupted_length_before_valid_records_is_not_truncated() {
+        let wal_dir = temp_wal_dir("corrupted-length");
+        let valid_len = write_records(&wal_dir).await;
+        let segment = active_segment(&wal_dir);
+        let mut bytes = std::fs::read(&segment).unwrap();
+        // the length of the SET record, which now runs past the end of the segment
+        let set = bytes.windows(10).position(|w| w == b"some-value").unwrap();
+        let record = (0..set)
+            .rev()
+            .find(|at| bytes[*at] == 0xFA && bytes[at + 9] == b'*')
+            .unwrap();
+        bytes[record + 4] = 0x7F;
+        std::fs::write(&segment, &bytes).unwrap();
+
+        let check = check_log(&bytes, 1, None);
+        assert!(!check.corruption.unwrap().at_tail);
+        let err = Store::new(logged(&wal_dir, AppendFsync::No))
+            .load()
+            .await
+            .unwrap_err();
+        assert!(err.contains("invalid record length"));
+        assert_eq!(
+            std::fs::metadata(active_segment(&wal_dir)).unwrap().len(),
+            valid_len
+        );
+    }
+
+    #[tokio::test]
+    async fn older_formats_are_loaded_and_migrated() {
+        let wal_dir = temp_wal_dir("unframed");
+        let line = Command::cmd_set("old-key", "old-value")
+            .to_string()
+            .replace("\r\n", "\\r\\n");
+        let framed = Command::cmd_rpush("list", &["a", "b"])
+            .to_string()
+            .replace("\r\n", "\\r\\n");
+        let framed = format!(
+            "{} {:08x} {}",
+            framed.len(),
+            crc32fast::hash(framed.as_bytes()),
+            framed
+        );
+        let legacy = legacy_file(&wal_dir);
+        std::fs::write(&legacy, format!("{line}\r\n{framed}\r\n")).unwrap();
+        let check = check_log(&std::fs::read(&legacy).unwrap(), 1, None);
+        assert_eq!((check.records, check.legacy_records), (2, 2));
+
+        let db = Db::open(logged(&wal_dir, AppendFsync::Always)).await;
+        assert_eq!(db.get("old-key").await, Ok(Some("old-value".to_string())));
+        assert_eq!(db.lrange("list", 0, -1).await.unwrap(), vec!["a", "b"]);
+
+        assert!(!std::path::Path::new(&legacy).exists());
+        let check = check_log(&std::fs::read(base_segment(&wal_dir)).unwrap(), 1, None);
+        assert!(check.corruption.is_none());
+        assert_eq!(check.legacy_records, 0);
+    }
+
+    #[tokio::test]
+    async fn values_round_trip_whatever_they_hold() {
+        let wal_dir = temp_wal_dir("lossless");
+        let values = [
+            "line\r\nbreak",
+            "escaped \\r\\n",
+            "new\nline",
+            "héllo wörld",
+        ];
+        let db = Db::open(logged(&wal_dir, AppendFsync::Always)).await;
+        for (i, value) in values.iter().enumerate() {
+            db.set(&format!("key{i}"), value).await.unwrap();
+        }
+        db.rpush("list", &values).await.unwrap();
+
+        let reopened = Db::open(logged(&wal_dir, AppendFsync::No)).await;
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(
+                reopened.get(&format!("key{i}")).await,
+                Ok(Some(value.to_string()))
+            );
+        }
+        assert_eq!(reopened.lrange("list", 0, -1).await.unwrap(), values);
+    }
+
+    #[tokio::test]
+    async fn check_tool_reports_and_repairs() {
+        let wal_dir = temp_wal_dir("check-tool");
+        let valid_len = write_records(&wal_dir).await;
+        let tool = env!("CARGO_BIN_EXE_kvds-check-aof");
+
+        let status = std::process::Command::new(tool).arg(&wal_dir).status();
+        assert!(status.unwrap().success());
+
+        append(&wal_dir, b"12 00");
+        let output = std::process::Command::new(tool)
+            .arg(&wal_dir)
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("corrupted at offset"));
+
+        let status = std::process::Command::new(tool)
+            .args(["--fix", &wal_dir])
+            .status();
+        assert!(status.unwrap().success());
+        assert_eq!(
+            std::fs::metadata(active_segment(&wal_dir)).unwrap().len(),
+            valid_len
+        );
+    }
+
+    #[tokio::test]
+    async fn check_tool_keeps_valid_records_after_a_corruption() {
+        let wal_dir = temp_wal_dir("check-tool-middle");
+        write_records(&wal_dir).await;
+        let segment = active_segment(&wal_dir);
+        let mut bytes = std::fs::read(&segment).unwrap();
+        let set = bytes.windows(10).position(|w| w == b"some-value").unwrap();
+        bytes[set] = b'S';
+        std::fs::write(&segment, &bytes).unwrap();
+        let tool = env!("CARGO_BIN_EXE_kvds-check-aof");
+
+        let status = std::process::Command::new(tool)
+            .args(["--fix", &wal_dir])
+            .status();
+        assert!(!status.unwrap().success());
+        assert_eq!(std::fs::read(&segment).unwrap(), bytes);
+
+        let status = std::process::Command::new(tool)
+            .args(["--fix", "--force", &wal_dir])
+            .status();
+        assert!(status.unwrap().success());
+        assert!(std::fs::metadata(&segment).unwrap().len() < bytes.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn segments_rotate_by_size() {
+        let wal_dir = temp_wal_dir("rotate-size");
+        let settings = Settings {
+            wal_segment_size: 200,
+            ..logged(&wal_dir, AppendFsync::Always)
+        };
+        let db = Db::open(settings).await;
+        for i in 0..50 {
+            db.set(&format!("key{i}"), "value").await.unwrap();
+        }
+
+        let manifest = manifest(&wal_dir);
+        assert!(manifest.segments.len() > 5);
+        // every closed segment starts with its database and stands on its own
+        for &id in manifest.closed() {
+            let check = check_log(
+                &std::fs::read(segment_path(wal_dir.as_ref(), id)).unwrap(),
+                id,
+                None,
+            );
+            assert!(check.corruption.is_none());
+            assert_eq!(check.commands[0], Command::SELECT { db: 0 });
+        }
+
+        let reopened = Db::open(logged(&wal_dir, AppendFsync::No)).await;
+        assert_eq!(reopened.keys("*").await.unwrap().len(), 50);
+    }
+
+    #[tokio::test]
+    async fn segments_rotate_by_age() {
+        let wal_dir = temp_wal_dir("rotate-age");
+        let settings = Settings {
+            wal_segment_seconds: 1,
+            ..logged(&wal_dir, AppendFsync::Always)
+        };
+        let db = Db::open(settings).await;
+        db.set("some-key", "some-value").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(2_100)).await;
+
+        let manifest = manifest(&wal_dir);
+        assert_eq!(manifest.segments, vec![1, 2]);
+        // nothing was written to the new segment, it is not rotated again
+        tokio::time::sleep(Duration::from_millis(1_100)).await;
+        assert_eq!(manifest.segments, self::manifest(&wal_dir).segments);
+    }
+
+    #[tokio::test]
+    async fn rewrite_deletes_segments_older_than_the_snapshot() {
+        for keep in [0, 1] {
+            let wal_dir = temp_wal_dir(&format!("retention-{keep}"));
+            let settings = Settings {
+                wal_segment_size: 200,
+                wal_keep_segments: keep,
+                ..logged(&wal_dir, AppendFsync::Always)
+            };
+            let db = Db::open(settings).await;
+            for i in 0..20 {
+                db.set(&format!("key{i}"), "value").await.unwrap();
+            }
+            let before = manifest(&wal_dir);
+
+            db.store().rewrite_log().await.unwrap();
+            db.set("after", "rewrite").await.unwrap();
+            let after = manifest(&wal_dir);
+            assert!(after.base > before.active());
+            assert_eq!(after.segments.len(), keep + 2);
+            for id in before.segments {
+                let exists = segment_path(wal_dir.as_ref(), id).exists();
+                assert_eq!(exists, after.segments.contains(&id));
+            }
+
+            let reopened = Db::open(logged(&wal_dir, AppendFsync::No)).await;
+            assert_eq!(reopened.keys("*").await.unwrap().len(), 21);
+        }
+    }
+
+    fn unix_ms(clock: &ManualClock) -> u64 {
+        clock
+            .system_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    async fn recovered(
+        settings: &Settings,
+        target: &str,
+        clock: Arc<ManualClock>,
+    ) -> Result<Db, String> {
+        let settings = Settings {
+            recover_to: target.to_string(),
+            ..settings.clone()
+        };
+        let store = Store::with_clock(settings, clock);
+        store.load().await?;
+        Ok(Db::with_store(store))
+    }
+
+    #[tokio::test]
+    async fn recovers_the_state_before_a_flushall() {
+        let wal_dir = temp_wal_dir("recover-time");
+        let clock = Arc::new(ManualClock::new());
+        let settings = logged(&wal_dir, AppendFsync::Always);
+        let db = Db::with_store(Store::with_clock(settings.clone(), clock.clone()));
+        db.set("first", "value").await.unwrap();
+        let target = unix_ms(&clock);
+        clock.advance(Duration::from_secs(1));
+        db.set("second", "value").await.unwrap();
+        clock.advance(Duration::from_secs(1));
+        db.flush_all().await.unwrap();
+
+        let db = recovered(&settings, &target.to_string(), clock.clone())
+            .await
+            .unwrap();
+        assert_eq!(db.keys("*").await, Ok(vec!["first".to_string()]));
+
+        // the segment holding the FLUSHALL left the log without being deleted
+        let aside: Vec<_> = std::fs::read_dir(&wal_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_dir())
+            .collect();
+        assert_eq!(aside.len(), 1);
+        let moved: Vec<_> = std::fs::read_dir(&aside[0]).unwrap().collect();
+        assert_eq!(moved.len(), 1);
+        assert!(!manifest(&wal_dir).segments.contains(&1));
+
+        // the recovered state is the new base, later restarts keep it
+        db.set("third", "value").await.unwrap();
+        let reopened = Db::open(logged(&wal_dir, AppendFsync::No)).await;
+        let mut keys = reopened.keys("*").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["first", "third"]);
+    }
+
+    #[tokio::test]
+    async fn recovers_up_to_a_log_offset() {
+        let wal_dir = temp_wal_dir("recover-offset");
+        let settings = logged(&wal_dir, AppendFsync::Always);
+        let db = Db::open(settings.clone()).await;
+        db.set("first", "value").await.unwrap();
+        db.set("second", "value").await.unwrap();
+
+        let check = check_log(&std::fs::read(active_segment(&wal_dir)).unwrap(), 1, None);
+        // SELECT, SET first, SET second
+        let target = format!("{}:{}", manifest(&wal_dir).active(), check.ends[1]);
+        let db = recovered(&settings, &target, Arc::new(ManualClock::new()))
+            .await
+            .unwrap();
+        assert_eq!(db.keys("*").await, Ok(vec!["first".to_string()]));
+
+        let err = recovered(&settings, "99:0", Arc::new(ManualClock::new())).await;
+        assert!(err.err().unwrap().contains("not kept"));
+        let err = recovered(&settings, "yesterday", Arc::new(ManualClock::new())).await;
+        assert!(err.err().unwrap().contains("invalid recovery target"));
+    }
+
+    #[tokio::test]
+    async fn recovery_starts_from_the_newest_snapshot_kept() {
+        for keep in [0, 10] {
+            let wal_dir = temp_wal_dir(&format!("recover-snapshots-{keep}"));
+            let clock = Arc::new(ManualClock::new());
+            let settings = Settings {
+                wal_keep_segments: keep,
+                ..logged(&wal_dir, AppendFsync::Always)
+            };
+            let db = Db::with_store(Store::with_clock(settings.clone(), clock.clone()));
+            db.set("first", "value").await.unwrap();
+            let before_rewrite = unix_ms(&clock);
+            clock.advance(Duration::from_secs(1));
+            db.set("second", "value").await.unwrap();
+            db.store().rewrite_log().await.unwrap();
+            db.set("third", "value").await.unwrap();
+            let before_flush = unix_ms(&clock);
+            clock.advance(Duration::from_secs(1));
+            db.flush_all().await.unwrap();
+
+            let db = recovered(&settings, &before_flush.to_string(), clock.clone())
+                .await
+                .unwrap();
+            assert_eq!(db.keys("*").await.unwrap().len(), 3);
+
+            let db = recovered(&settings, &before_rewrite.to_string(), clock.clone()).await;
+            if keep == 0 {
+                assert!(db.err().unwrap().contains("no snapshot"));
+            } else {
+                assert_eq!(db.unwrap().keys("*").await, Ok(vec!["first".to_string()]));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_during_a_rewrite_are_kept() {
+        let wal_dir = temp_wal_dir("rewrite-concurrent");
+        let db = Db::open(logged(&wal_dir, AppendFsync::Always)).await;
+        for i in 0..100 {
+            db.set(&format!("key{i}"), "old").await.unwrap();
+        }
+
+        let store = db.store().clone();
+        let rewrite = tokio::spawn(async move { store.rewrite_log().await });
+        for i in 50..150 {
+            db.set(&format!("key{i}"), "new").await.unwrap();
+        }
+        rewrite.await.unwrap().unwrap();
+
+        let reopened = Db::open(logged(&wal_dir, AppendFsync::No)).await;
+        assert_eq!(reopened.keys("*").await.unwrap().len(), 150);
+        assert_eq!(reopened.get("key10").await, Ok(Some("old".to_string())));
+        assert_eq!(reopened.get("key149").await, Ok(Some("new".to_string())));
+    }
+
+    #[tokio::test]
+    async fn bgrewriteaof_and_growth_threshold() {
+        let wal_dir = temp_wal_dir("rewrite-growth");
+        let clock = Arc::new(ManualClock::new());
+        let settings = Settings {
+            auto_aof_rewrite_min_size: 1_000,
+            ..logged(&wal_dir, AppendFsync::Always)
+        };
+        let db = Db::with_store(Store::with_clock(settings, clock.clone()));
+        let persistence = db.store().persistence().unwrap();
+
+        assert_eq!(
+            db.execute(Command::BGREWRITEAOF).await,
+            Reply::Simple("Background append only file rewriting started".to_string())
+        );
+        while persistence.rewrite_in_progress() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        for _ in 0..100 {
+            db.set("key", "value").await.unwrap();
+        }
+        let grown = persistence.size();
+        assert!(grown > 1_000);
+
+        clock.advance(Duration::from_secs(1));
+        while persistence.rewrite_in_progress() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(persistence.size() < grown);
+        assert_eq!(persistence.size(), persistence.base_size());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn write_errors_are_returned_to_the_client() {
+        // every write to /dev/full fails with ENOSPC
+        let wal_dir = temp_wal_dir("dev-full");
+        std::fs::create_dir_all(&wal_dir).unwrap();
+        std::os::unix::fs::symlink("/dev/full", segment_path(wal_dir.as_ref(), 1)).unwrap();
+        let db = Db::with_store(Store::new(logged(&wal_dir, AppendFsync::Always)));
+        let err = db.set("some-key", "some-value").await.unwrap_err();
+        assert!(err.0.starts_with("MISCONF"));
+        assert_eq!(db.get("some-key").await, Ok(None));
+
+        let db = Db::with_store(Store::new(logged(&wal_dir, AppendFsync::Everysec)));
+        db.set("some-key", "some-value").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(db.set("other-key", "value").await.is_err());
+    }
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use std::{sync::Arc, time::Duration};
+
+    use kvds::{
+        app_server::{parser::Command, reply::Reply},
+        connector::connector::Connector,
+        embedded::Db,
+        services::{clock::ManualClock, snapshot::parse_save_points, store::Store},
+        Settings,
+    };
+
+    use crate::{start_server, temp_db_file};
+
+    fn with_snapshot(snapshot_file: &str) -> Settings {
+        Settings {
+            snapshot_file: snapshot_file.to_string(),
+            ..Settings::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn save_and_load_all_databases() {
+        let snapshot_file = temp_db_file("snapshot");
+        let db = Db::with_store(Store::new(with_snapshot(&snapshot_file)));
+
+        db.set("some-key", "some-value").await.unwrap();
+        db.rpush("list", &["a", "b"]).await.unwrap();
+        db.set_ex("volatile", "value", 100).await.unwrap();
+        db.select(2).await.unwrap();
+        db.set("some-key", "two").await.unwrap();
+        assert_eq!(db.execute(Command::SAVE).await, Reply::ok());
+        assert!(matches!(db.execute(Command::LASTSAVE).await, Reply::Integer(t) if t > 0));
+
+        let restored = Db::open(with_snapshot(&snapshot_file)).await;
+        assert_eq!(
+            restored.get("some-key").await,
+            Ok(Some("some-value".to_string()))
+        );
+        assert_eq!(
+            restored.lrange("list", 0, -1).await.unwrap(),
+            vec!["a", "b"]
+        );
+        assert!((99..=100).contains(&restored.ttl("volatile").await.unwrap()));
+        restored.select(2).await.unwrap();
+        assert_eq!(restored.get("some-key").await, Ok(Some("two".to_string())));
+    }
+
+    #[tokio::test]
+    async fn passed_deadlines_are_not_restored() {
+        let snapshot_file = temp_db_file("snapshot-expired");
+        let db = Db::with_store(Store::new(with_snapshot(&snapshot_file)));
+        db.set_ex("volatile", "value", 1).await.unwrap();
+        db.set("durable", "value").await.unwrap();
+        db.store().save().await.unwrap();
+
+        let clock = Arc::new(ManualClock::new());
+        clock.advance(Duration::from_secs(2));
+        let store = Store::with_clock(with_snapshot(&snapshot_file), clock);
+        store.load().await.unwrap();
+        assert_eq!(
+            Db::with_store(store).keys("*").await.unwrap(),
+            vec!["durable"]
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupted_snapshots_are_rejected() {
+        let snapshot_file = temp_db_file("snapshot-corrupted");
+        let db = Db::with_store(Store::new(with_snapshot(&snapshot_file)));
+        db.set("some-key", "some-value").await.unwrap();
+        db.store().save().await.unwrap();
+
+        let mut bytes = std::fs::read(&snapshot_file).unwrap();
+        let last = bytes.len() - 6;
+        bytes[last] ^= 0x01;
+        std::fs::write(&snapshot_file, bytes).unwrap();
+
+        let store = Store::new(with_snapshot(&snapshot_file));
+        assert_eq!(
+            store.load().await,
+            Err("snapshot checksum mismatch".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn save_points_trigger_a_background_save() {
+        assert_eq!(parse_save_points("").unwrap(), vec![]);
+        assert!(parse_save_points("900").is_err());
+
+        let snapshot_file = temp_db_file("snapshot-rules");
+        let clock = Arc::new(ManualClock::new());
+        let settings = Settings {
+            save: "10 2".to_string(),
+            ..with_snapshot(&snapshot_file)
+        };
+        let db = Db::with_store(Store::with_clock(settings, clock.clone()));
+
+        db.set("first", "value").await.unwrap();
+        clock.advance(Duration::from_secs(10));
+        assert!(!db.store().bgsave_in_progress());
+
+        db.set("second", "value").await.unwrap();
+        clock.advance(Duration::from_secs(1));
+        while db.store().bgsave_in_progress() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(std::path::Path::new(&snapshot_file).exists());
+
+        assert_eq!(
+            db.execute(Command::BGSAVE).await,
+            Reply::Simple("Background saving started".to_string())
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn background_saves_capture_one_point_in_time() {
+        let snapshot_file = temp_db_file("snapshot-point-in-time");
+        let db = Db::with_store(Store::new(with_snapshot(&snapshot_file)));
+        let keys = 5000;
+        for i in 0..keys {
+            db.set(&format!("key{i:05}"), "value").await.unwrap();
+        }
+
+        // deleted in order while the save runs, so the snapshot holds the keys from some point on
+        assert_eq!(
+            db.execute(Command::BGSAVE).await,
+            Reply::Simple("Background saving started".to_string())
+        );
+        for i in 0..keys {
+            db.del(&format!("key{i:05}")).await.unwrap();
+        }
+        while db.store().bgsave_in_progress() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let restored = Db::open(with_snapshot(&snapshot_file)).await;
+        let mut saved = restored.keys("*").await.unwrap();
+        saved.sort();
+        let first = keys - saved.len();
+        let expected: Vec<_> = (first..keys).map(|i| format!("key{i:05}")).collect();
+        assert_eq!(saved, expected);
+    }
+
+    #[test]
+    fn the_server_loads_the_snapshot_at_startup() {
+        let snapshot_file = temp_db_file("snapshot-server");
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let db = Db::with_store(Store::new(with_snapshot(&snapshot_file)));
+            db.set("some-key", "some-value").await.unwrap();
+            db.store().save().await.unwrap();
+        });
+
+        let c = Connector::with_port(&start_server(Store::new(with_snapshot(&snapshot_file))));
+        assert_eq!(c.get("some-key"), Some("some-value".to_string()));
+    }
+}
+
+#[cfg(test)]
+mod dump_tests {
+    use std::{sync::Arc, time::Duration};
+
+    use kvds::{
+        app_server::{
+            parser::{parse_command, Command},
+            reply::Reply,
+        },
+        embedded::Db,
+        services::{
+            clock::ManualClock, compression::Codec, persistence_service::AppendFsync, store::Store,
+        },
+        Settings,
+    };
+
+    use crate::temp_wal_dir;
+
+    #[test]
+    fn dump_commands_round_trip() {
+        for cmd in [
+            Command::cmd_dump("key"),
+            Command::cmd_restore("key", 0, "00ff", false),
+            Command::RESTORE {
+                key: "key".to_string(),
+                ttl: 1_700_000_000_000,
+                payload: "00ff".to_string(),
+                replace: true,
+                absttl: true,
+            },
+        ] {
+            assert_eq!(parse_command(cmd.to_string()).unwrap(), cmd);
+        }
+    }
+
+    #[tokio::test]
+    async fn dump_and_restore_move_keys_between_stores() {
+        let source = Db::open(Settings {
+            compression: Codec::Lz4,
+            compress_values_above: 64,
+            ..Settings::default()
+        })
+        .await;
+        let target = Db::with_store(Store::new(Settings::default()));
+        let long = "long value ".repeat(20);
+        source.set("string", "value").await.unwrap();
+        source.set("packed", &long).await.unwrap();
+        source.rpush("list", &["a", "b", "c"]).await.unwrap();
+        assert_eq!(source.dump("missing").await, Ok(None));
+
+        for key in ["string", "packed", "list"] {
+            let payload = source.dump(key).await.unwrap().unwrap();
+            target.restore(key, 0, &payload, false).await.unwrap();
+        }
+        assert_eq!(target.get("string").await, Ok(Some("value".to_string())));
+        assert_eq!(target.get("packed").await, Ok(Some(long)));
+        assert_eq!(
+            target.lrange("list", 0, -1).await.unwrap(),
+            vec!["a", "b", "c"]
+        );
+        assert_eq!(target.ttl("list").await, Ok(-1));
+
+        let payload = source.dump("string").await.unwrap().unwrap();
+        let busy = target.restore("string", 0, &payload, false).await;
+        assert!(busy.unwrap_err().to_string().starts_with("BUSYKEY"));
+        target.restore("list", 5000, &payload, true).await.unwrap();
+        assert_eq!(target.get("list").await, Ok(Some("value".to_string())));
+        assert!(matches!(target.ttl("list").await, Ok(4 | 5)));
+
+        let mut damaged = payload.into_bytes();
+        damaged[1] = if damaged[1] == b'0' { b'1' } else { b'0' };
+        let damaged = String::from_utf8(damaged).unwrap();
+        assert_eq!(
+            target
+                .execute(Command::cmd_restore("other", 0, &damaged, false))
+                .await,
+            Reply::error("DUMP payload version or checksum are wrong")
+        );
+    }
+
+    #[tokio::test]
+    async fn restore_ttl_is_logged_as_a_deadline() {
+        let wal_dir = temp_wal_dir("restore");
+        let settings = Settings {
+            db_file: format!("{wal_dir}.log"),
+            wal_dir: wal_dir.clone(),
+            persist: true,
+            appendfsync: AppendFsync::Always,
+            ..Settings::default()
+        };
+        let clock = Arc::new(ManualClock::new());
+        let db = Db::with_store(Store::with_clock(settings.clone(), clock.clone()));
+        db.set("key", "value").await.unwrap();
+        let payload = db.dump("key").await.unwrap().unwrap();
+        db.restore("volatile", 100_000, &payload, false)
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(40));
+
+        let store = Store::with_clock(settings, clock.clone());
+        store.load().await.unwrap();
+        let reopened = Db::with_store(store);
+        assert_eq!(reopened.ttl("volatile").await, Ok(60));
+
+        // a deadline already passed deletes the key instead
+        let passed = Command::RESTORE {
+            key: "key".to_string(),
+            ttl: 1,
+            payload,
+            replace: true,
+            absttl: true,
+        };
+        assert_eq!(reopened.execute(passed).await, Reply::ok());
+        assert_eq!(reopened.get("key").await, Ok(None));
+    }
+}
+
+#[cfg(test)]
+mod encryption_tests {
+    use kvds::{
+        embedded::Db,
+        services::{
+            encryption::{Cipher, Keyring},
+            log_format::check_log,
+            persistence_service::AppendFsync,
+            store::Store,
+            wal::{segment_path, Manifest},
+        },
+        Settings,
+    };
+
+    use crate::{temp_db_file, temp_wal_dir};
+
+    const OLD_KEY: &str = "1111111111111111111111111111111111111111111111111111111111111111";
+    const NEW_KEY: &str = "2222222222222222222222222222222222222222222222222222222222222222";
+
+    fn encrypted(wal_dir: &str, key_file: &str) -> Settings {
+        Settings {
+            db_file: format!("{wal_dir}.log"),
+            wal_dir: wal_dir.to_string(),
+            persist: true,
+            appendfsync: AppendFsync::Always,
+            snapshot_file: format!("{wal_dir}.dump"),
+            encryption_key_file: key_file.to_string(),
+            ..Settings::default()
+        }
+    }
+
+    fn write_keys(key_file: &str, keys: &[&str]) {
+        std::fs::write(key_file, keys.join("\n")).unwrap();
+    }
+
+    // (id, contents) of the segments replayed at startup
+    fn segments(wal_dir: &str) -> Vec<(u64, Vec<u8>)> {
+        let manifest = Manifest::read(wal_dir.as_ref()).unwrap().unwrap();
+        manifest
+            .live()
+            .map(|id| {
+                (
+                    id,
+                    std::fs::read(segment_path(wal_dir.as_ref(), id)).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn contains(data: &[u8], text: &str) -> bool {
+        data.windows(text.len()).any(|w| w == text.as_bytes())
+    }
+
+    #[tokio::test]
+    async fn log_and_snapshots_hold_no_plaintext() {
+        let wal_dir = temp_wal_dir("encrypted");
+        let key_file = temp_db_file("encrypted-key");
+        write_keys(&key_file, &[OLD_KEY]);
+        let settings = encrypted(&wal_dir, &key_file);
+        let db = Db::open(settings.clone()).await;
+
+        db.set("secret-key", "secret-value").await.unwrap();
+        db.select(2).await.unwrap();
+        db.rpush("secret-list", &["secret-item"]).await.unwrap();
+        db.store().save().await.unwrap();
+        db.store().rewrite_log().await.unwrap();
+        db.set("after", "rewrite").await.unwrap();
+
+        let snapshot = std::fs::read(&settings.snapshot_file).unwrap();
+        // chunked, uncompressed, sealed
+        assert!(snapshot.starts_with(b"KVDS\x03\x00\x00\x01"));
+        for (_, data) in segments(&wal_dir) {
+            assert!(!contains(&data, "secret"));
+        }
+        assert!(!contains(&snapshot, "secret"));
+        let reopened = Db::open(settings.clone()).await;
+        assert_eq!(
+            reopened.get("secret-key").await,
+            Ok(Some("secret-value".to_string()))
+        );
+        reopened.select(2).await.unwrap();
+        assert_eq!(reopened.get("after").await, Ok(Some("rewrite".to_string())));
+
+        let keyring = Keyring::parse(OLD_KEY, Cipher::ChaCha20Poly1305).unwrap();
+        for (id, data) in segments(&wal_dir) {
+            assert!(check_log(&data, id, None).corruption.is_some());
+            let check = check_log(&data, id, Some(&keyring));
+            assert_eq!(check.corruption, None);
+            assert_eq!(check.stale_records, 0);
+            // records are bound to their segment
+            assert!(check_log(&data, id + 1, Some(&keyring))
+                .corruption
+                .is_some());
+        }
+        // and to their offset, so a copy appended to the segment does not open
+        let (id, active) = segments(&wal_dir).pop().unwrap();
+        let doubled = [&active[..], &active[..]].concat();
+        let check = check_log(&doubled, id, Some(&keyring));
+        assert_eq!(check.corruption.map(|c| c.offset), Some(active.len()));
+
+        // a wrong key refuses to start rather than dropping the records it cannot read
+        write_keys(&key_file, &[NEW_KEY]);
+        let store = Store::new(settings);
+        assert!(store.load().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rewrite_seals_the_log_with_a_rotated_key() {
+        let wal_dir = temp_wal_dir("encrypted-rotation");
+        let key_file = temp_db_file("encrypted-rotation-key");
+        write_keys(&key_file, &[OLD_KEY]);
+        let settings = Settings {
+            aof_use_rdb_preamble: false,
+            ..encrypted(&wal_dir, &key_file)
+        };
+        let db = Db::open(settings.clone()).await;
+        db.set("before", "rotation").await.unwrap();
+
+        // the old key stays listed so the segments it sealed can still be read meanwhile
+        write_keys(&key_file, &[NEW_KEY, OLD_KEY]);
+        db.store().rewrite_log().await.unwrap();
+        db.set("after", "rotation").await.unwrap();
+
+        write_keys(&key_file, &[NEW_KEY]);
+        let new_only = Keyring::parse(NEW_KEY, Cipher::ChaCha20Poly1305).unwrap();
+        for (id, data) in segments(&wal_dir) {
+            assert_eq!(check_log(&data, id, Some(&new_only)).corruption, None);
+        }
+        let reopened = Db::open(settings).await;
+        assert_eq!(
+            reopened.get("before").await,
+            Ok(Some("rotation".to_string()))
+        );
+        assert_eq!(
+            reopened.get("after").await,
+            Ok(Some("rotation".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn plaintext_log_is_encrypted_by_a_migration() {
+        let wal_dir = temp_wal_dir("encrypted-migration");
+        let key_file = temp_db_file("encrypted-migration-key");
+        write_keys(&key_file, &[OLD_KEY]);
+        let plain = Settings {
+            encryption_key_file: String::new(),
+            ..encrypted(&wal_dir, &key_file)
+        };
+        let db = Db::open(plain).await;
+        db.set("some-key", "some-value").await.unwrap();
+        db.store().rewrite_log().await.unwrap();
+        db.set("plain", "text").await.unwrap();
+        assert!(segments(&wal_dir)
+            .iter()
+            .any(|(_, data)| contains(data, "some-value")));
+
+        // refused unless asked to migrate
+        let settings = Settings {
+            encryption_cipher: Cipher::Aes256Gcm,
+            ..encrypted(&wal_dir, &key_file)
+        };
+        let err = Store::new(settings.clone()).load().await.unwrap_err();
+        assert!(err.contains("unencrypted"));
+
+        let migrating = Settings {
+            migrate_encryption: true,
+            ..settings.clone()
+        };
+        let reopened = Db::open(migrating).await;
+        assert_eq!(
+            reopened.get("some-key").await,
+            Ok(Some("some-value".to_string()))
+        );
+        let keyring = Keyring::parse(OLD_KEY, Cipher::Aes256Gcm).unwrap();
+        for (id, data) in segments(&wal_dir) {
+            assert!(!contains(&data, "some-value") && !contains(&data, "plain"));
+            assert_eq!(check_log(&data, id, Some(&keyring)).stale_records, 0);
+        }
+        let reopened = Db::open(settings).await;
+        assert_eq!(reopened.get("plain").await, Ok(Some("text".to_string())));
+    }
+}
+
+#[cfg(test)]
+mod compression_tests {
+    use kvds::{
+        app_server::{parser::Command, reply::Reply},
+        embedded::Db,
+        services::{
+            compression::Codec,
+            log_format::check_log,
+            persistence_service::AppendFsync,
+            wal::{segment_path, Manifest},
+        },
+        Settings,
+    };
+
+    use crate::{temp_db_file, temp_wal_dir};
+
+    fn compressed(wal_dir: &str, compression: Codec) -> Settings {
+        Settings {
+            db_file: format!("{wal_dir}.log"),
+            wal_dir: wal_dir.to_string(),
+            persist: true,
+            appendfsync: AppendFsync::Always,
+            snapshot_file: format!("{wal_dir}.dump"),
+            compression,
+            ..Settings::default()
+        }
+    }
+
+    fn document(i: usize) -> String {
+        format!(
+            r#"{{"id":{i},"tags":["{}"]}}"#,
+            ["some-tag"; 50].join(r#"",""#)
+        )
+    }
+
+    fn log_size(wal_dir: &str) -> u64 {
+        let manifest = Manifest::read(wal_dir.as_ref()).unwrap().unwrap();
+        manifest
+            .live()
+            .map(|id| {
+                std::fs::metadata(segment_path(wal_dir.as_ref(), id))
+                    .unwrap()
+                    .len()
+            })
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn log_and_snapshots_are_compressed() {
+        for (name, codec, encrypted) in [("lz4", Codec::Lz4, false), ("zstd", Codec::Zstd, true)] {
+            let wal_dir = temp_wal_dir(&format!("compressed-{name}"));
+            let key_file = temp_db_file(&format!("compressed-{name}-key"));
+            std::fs::write(&key_file, "33".repeat(32)).unwrap();
+            let settings = Settings {
+                encryption_key_file: if encrypted { key_file } else { String::new() },
+                ..compressed(&wal_dir, codec)
+            };
+            let db = Db::open(settings.clone()).await;
+
+            let written: usize = (0..20).map(|i| document(i).len()).sum();
+            for i in 0..10 {
+                db.set(&format!("doc{i}"), &document(i)).await.unwrap();
+            }
+            db.store().save().await.unwrap();
+            db.store().rewrite_log().await.unwrap();
+            for i in 10..20 {
+                db.set(&format!("doc{i}"), &document(i)).await.unwrap();
+            }
+            assert!(log_size(&wal_dir) < written as u64 / 2);
+            let snapshot = std::fs::read(&settings.snapshot_file).unwrap();
+            assert!(snapshot.len() < written / 2);
+            if !encrypted {
+                // the snapshot header records the codec
+                assert_eq!(&snapshot[..7], b"KVDS\x03\x00\x01");
+            }
+
+            let reopened = Db::open(settings.clone()).await;
+            for i in 0..20 {
+                assert_eq!(
+                    reopened.get(&format!("doc{i}")).await,
+                    Ok(Some(document(i)))
+                );
+            }
+            let from_snapshot = Db::open(Settings {
+                persist: false,
+                ..settings
+            })
+            .await;
+            assert_eq!(from_snapshot.get("doc9").await, Ok(Some(document(9))));
+            assert_eq!(from_snapshot.get("doc10").await, Ok(None));
+        }
+    }
+
+    #[tokio::test]
+    async fn codec_changes_keep_older_records_readable() {
+        let wal_dir = temp_wal_dir("compressed-mixed");
+        let db = Db::open(compressed(&wal_dir, Codec::None)).await;
+        db.set("plain", &document(0)).await.unwrap();
+
+        let db = Db::open(compressed(&wal_dir, Codec::Lz4)).await;
+        db.set("lz4", &document(1)).await.unwrap();
+        let db = Db::open(compressed(&wal_dir, Codec::Zstd)).await;
+        db.set("zstd", &document(2)).await.unwrap();
+
+        let manifest = Manifest::read(wal_dir.as_ref()).unwrap().unwrap();
+        let log = std::fs::read(segment_path(wal_dir.as_ref(), manifest.active())).unwrap();
+        let check = check_log(&log, 1, None);
+        assert_eq!(check.corruption, None);
+        assert_eq!(check.legacy_records, 0);
+
+        let reopened = Db::open(compressed(&wal_dir, Codec::None)).await;
+        assert_eq!(reopened.get("plain").await, Ok(Some(document(0))));
+        assert_eq!(reopened.get("lz4").await, Ok(Some(document(1))));
+        assert_eq!(reopened.get("zstd").await, Ok(Some(document(2))));
+    }
+
+    #[tokio::test]
+    async fn large_values_are_compressed_in_memory() {
+        let db = Db::open(Settings {
+            compression: Codec::Lz4,
+            compress_values_above: 100,
+            ..Settings::default()
+        })
+        .await;
+
+        db.set("doc", &document(0)).await.unwrap();
+        assert!(db.store().used_memory() < document(0).len());
+        assert_eq!(db.get("doc").await, Ok(Some(document(0))));
+        assert_eq!(
+            db.execute(Command::MGET {
+                keys: vec!["doc".to_string()]
+            })
+            .await,
+            Reply::Array(vec![Reply::bulk(&document(0))])
+        );
+
+        // changed in place like any other string
+        let counter = format!("{}1", "0".repeat(200));
+        db.set("counter", &counter).await.unwrap();
+        assert_eq!(db.incr("counter").await, Ok(2));
+        assert_eq!(db.get("counter").await, Ok(Some("2".to_string())));
+        assert!(db.rpush("doc", &["item"]).await.is_err());
+    }
+}
+
+#[cfg(test)]
+mod rdb_tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use kvds::{
+        embedded::Db,
+        services::{persistence_service::AppendFsync, rdb::crc64, store::Store},
+        Settings,
+    };
+
+    use crate::{temp_db_file, temp_wal_dir};
+
+    fn string(out: &mut Vec<u8>, s: &[u8]) {
+        assert!(s.len() < 64);
+        out.push(s.len() as u8);
+        out.extend_from_slice(s);
+    }
+
+    fn key(out: &mut Vec<u8>, kind: u8, name: &str) {
+        out.push(kind);
+        string(out, name.as_bytes());
+    }
+
+    // a header whose sizes are not checked, the entries and the end marker
+    fn blob(out: &mut Vec<u8>, header: usize, entries: &[u8]) {
+        let blob = [&vec![0; header][..], entries, &[0xFF]].concat();
+        string(out, &blob);
+    }
+
+    fn unix_ms() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    // strings, lists in each encoding, expiries and types kvds does not have, in two databases
+    fn dump() -> Vec<u8> {
+        let mut out = b"REDIS0011".to_vec();
+        out.push(0xFA);
+        string(&mut out, b"redis-ver");
+        string(&mut out, b"7.2.0");
+        out.extend_from_slice(&[0xFE, 0, 0xFB, 9, 2]);
+        key(&mut out, 0, "plain");
+        string(&mut out, b"value");
+        key(&mut out, 0, "int");
+        out.extend_from_slice(&[0xC1, 0xD2, 0x04]);
+        key(&mut out, 0, "lzf");
+        out.extend_from_slice(&[0xC3, 7, 12, 0x02, b'a', b'b', b'c', 0xE0, 0x00, 0x02]);
+        out.push(0xFC);
+        out.extend_from_slice(&(unix_ms() + 100_000).to_le_bytes());
+        key(&mut out, 0, "volatile");
+        string(&mut out, b"value");
+        out.push(0xFC);
+        out.extend_from_slice(&(unix_ms() - 1000).to_le_bytes());
+        key(&mut out, 0, "expired");
+        string(&mut out, b"value");
+        key(&mut out, 1, "linked");
+        out.push(2);
+        string(&mut out, b"a");
+        string(&mut out, b"b");
+        // ziplist: "x", 5 and 300
+        key(&mut out, 10, "ziplist");
+        blob(&mut out, 10, &[0, 0x01, b'x', 3, 0xF6, 2, 0xC0, 0x2C, 0x01]);
+        // quicklist of a listpack with "lp", 7 and -2, then a plain node
+        key(&mut out, 18, "quicklist");
+        out.push(2);
+        out.push(2);
+        blob(&mut out, 6, &[0x82, b'l', b'p', 3, 0x07, 1, 0xDF, 0xFE, 2]);
+        out.push(1);
+        string(&mut out, b"plain-node");
+        key(&mut out, 11, "intset");
+        string(&mut out, &[2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 2, 0]);
+        key(&mut out, 16, "hash");
+        blob(&mut out, 6, &[0x81, b'f', 2, 0x81, b'v', 2]);
+        out.extend_from_slice(&[0xFE, 1]);
+        key(&mut out, 0, "other");
+        string(&mut out, b"one");
+        out.push(0xFF);
+        let checksum = crc64(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    fn importing(name: &str, rdb: &[u8]) -> Settings {
+        let rdb_file = temp_db_file(name);
+        std::fs::write(&rdb_file, rdb).unwrap();
+        Settings {
+            import_rdb: rdb_file,
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn crc64_matches_redis() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[tokio::test]
+    async fn imports_strings_and_lists_in_every_encoding() {
+        let settings = importing("rdb-encodings", &dump());
+        let store = Store::new(Settings::default());
+        let import = store
+            .import_rdb(settings.import_rdb.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(import.keys, 9);
+        assert_eq!(
+            import.skipped.into_iter().collect::<Vec<_>>(),
+            vec![("hash", 1), ("set", 1)]
+        );
+
+        let db = Db::with_store(store);
+        assert_eq!(db.get("plain").await, Ok(Some("value".to_string())));
+        assert_eq!(db.get("int").await, Ok(Some("1234".to_string())));
+        assert_eq!(db.get("lzf").await, Ok(Some("abcabcabcabc".to_string())));
+        assert!((99..=100).contains(&db.ttl("volatile").await.unwrap()));
+        assert_eq!(db.get("expired").await, Ok(None));
+        assert_eq!(db.lrange("linked", 0, -1).await.unwrap(), vec!["a", "b"]);
+        assert_eq!(
+            db.lrange("ziplist", 0, -1).await.unwrap(),
+            vec!["x", "5", "300"]
+        );
+        assert_eq!(
+            db.lrange("quicklist", 0, -1).await.unwrap(),
+            vec!["lp", "7", "-2", "plain-node"]
+        );
+        assert_eq!(db.get("hash").await, Ok(None));
+        db.select(1).await.unwrap();
+        assert_eq!(db.get("other").await, Ok(Some("one".to_string())));
+    }
+
+    // a listpack entry of 16383 bytes has a 3 byte backlen, the next entry follows it
+    #[tokio::test]
+    async fn long_listpack_entries_are_read_whole() {
+        let long = "a".repeat(16_378);
+        let mut listpack = vec![0; 6];
+        listpack.push(0xF0);
+        listpack.extend_from_slice(&(long.len() as u32).to_le_bytes());
+        listpack.extend_from_slice(long.as_bytes());
+        listpack.extend_from_slice(&[0, 0, 0]);
+        listpack.extend_from_slice(&[0x81, b'b', 2, 0xFF]);
+        let mut rdb = b"REDIS0011".to_vec();
+        key(&mut rdb, 18, "long");
+        rdb.extend_from_slice(&[1, 2, 0x80]);
+        rdb.extend_from_slice(&(listpack.len() as u32).to_be_bytes());
+        rdb.extend_from_slice(&listpack);
+        rdb.push(0xFF);
+        let checksum = crc64(&rdb);
+        rdb.extend_from_slice(&checksum.to_le_bytes());
+
+        let settings = importing("rdb-long-entry", &rdb);
+        let store = Store::new(Settings::default());
+        store
+            .import_rdb(settings.import_rdb.as_ref())
+            .await
+            .unwrap();
+        let db = Db::with_store(store);
+        assert_eq!(
+            db.lrange("long", 0, -1).await.unwrap(),
+            vec![long, "b".into()]
+        );
+    }
+
+    // a stream with a consumer group and hashes with field expiries, around a string
+    #[tokio::test]
+    async fn streams_and_hashes_with_field_expiries_are_skipped() {
+        let mut rdb = b"REDIS0012".to_vec();
+        key(&mut rdb, 21, "stream");
+        rdb.push(1);
+        string(&mut rdb, &[0; 16]);
+        blob(&mut rdb, 6, &[0x81, b'f', 2, 0x81, b'v', 2]);
+        // length, last, first and max deleted IDs, entries added
+        rdb.extend_from_slice(&[1, 5, 0, 5, 0, 0, 0, 1]);
+        rdb.push(1);
+        string(&mut rdb, b"group");
+        rdb.extend_from_slice(&[5, 0, 1]);
+        rdb.push(1);
+        rdb.extend_from_slice(&[0; 24]);
+        rdb.push(1);
+        rdb.push(1);
+        string(&mut rdb, b"consumer");
+        rdb.extend_from_slice(&[0; 16]);
+        rdb.push(1);
+        rdb.extend_from_slice(&[0; 16]);
+        key(&mut rdb, 24, "metadata");
+        rdb.extend_from_slice(&unix_ms().to_le_bytes());
+        rdb.push(2);
+        rdb.push(1);
+        string(&mut rdb, b"f");
+        string(&mut rdb, b"v");
+        rdb.push(0);
+        string(&mut rdb, b"g");
+        string(&mut rdb, b"w");
+        key(&mut rdb, 25, "listpack-ex");
+        rdb.extend_from_slice(&unix_ms().to_le_bytes());
+        blob(&mut rdb, 6, &[0x81, b'f', 2, 0x81, b'v', 2, 0x00, 1]);
+        key(&mut rdb, 0, "after");
+        string(&mut rdb, b"value");
+        rdb.push(0xFF);
+        let checksum = crc64(&rdb);
+        rdb.extend_from_slice(&checksum.to_le_bytes());
+
+        let settings = importing("rdb-streams", &rdb);
+        let store = Store::new(Settings::default());
+        let import = store
+            .import_rdb(settings.import_rdb.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(import.keys, 1);
+        assert_eq!(
+            import.skipped.into_iter().collect::<Vec<_>>(),
+            vec![("hash", 2), ("stream", 1)]
+        );
+        let db = Db::with_store(store);
+        assert_eq!(db.get("after").await, Ok(Some("value".to_string())));
+    }
+
+    #[tokio::test]
+    async fn unknown_value_types_name_the_key() {
+        let mut rdb = b"REDIS0012".to_vec();
+        key(&mut rdb, 7, "module");
+        rdb.push(0xFF);
+        let checksum = crc64(&rdb);
+        rdb.extend_from_slice(&checksum.to_le_bytes());
+
+        let settings = importing("rdb-unknown-type", &rdb);
+        let store = Store::new(Settings::default());
+        let err = store.import_rdb(settings.import_rdb.as_ref()).await;
+        assert_eq!(
+            err.unwrap_err(),
+            "unsupported RDB value type 7 at key \"module\""
+        );
+    }
+
+    #[tokio::test]
+    async fn lzf_lengths_past_what_the_input_expands_to_are_refused() {
+        let mut rdb = b"REDIS0011".to_vec();
+        key(&mut rdb, 0, "huge");
+        rdb.extend_from_slice(&[0xC3, 2, 0x80]);
+        rdb.extend_from_slice(&u32::MAX.to_be_bytes());
+        rdb.extend_from_slice(&[0x00, b'a', 0xFF]);
+        let checksum = crc64(&rdb);
+        rdb.extend_from_slice(&checksum.to_le_bytes());
+
+        let settings = importing("rdb-lzf-length", &rdb);
+        let store = Store::new(Settings::default());
+        let err = store.import_rdb(settings.import_rdb.as_ref()).await;
+        assert!(err.unwrap_err().contains("invalid LZF data"));
+    }
+
+    #[tokio::test]
+    async fn a_corrupted_rdb_is_refused() {
+        let mut rdb = dump();
+        rdb[20] ^= 0xFF;
+        let store = Store::new(importing("rdb-corrupted", &rdb));
+        assert!(store.load().await.is_err());
+        assert_eq!(store.used_memory(), 0);
+    }
+
+    #[tokio::test]
+    async fn imported_keys_are_persisted_once() {
+        let wal_dir = temp_wal_dir("rdb-import");
+        let logged = Settings {
+            db_file: format!("{wal_dir}.log"),
+            wal_dir: wal_dir.clone(),
+            persist: true,
+            appendfsync: AppendFsync::Always,
+            ..Settings::default()
+        };
+        let db = Db::open(Settings {
+            import_rdb: importing("rdb-persisted", &dump()).import_rdb,
+            ..logged.clone()
+        })
+        .await;
+        db.set("plain", "changed").await.unwrap();
+
+        let reopened = Db::open(logged).await;
+        assert_eq!(reopened.get("plain").await, Ok(Some("changed".to_string())));
+        assert_eq!(
+            reopened.lrange("quicklist", 0, -1).await.unwrap(),
+            vec!["lp", "7", "-2", "plain-node"]
+        );
+    }
+}
+
+#[cfg(test)]
+mod ndjson_tests {
+    use std::io::Cursor;
+
+    use kvds::{
+        app_server::{parser::Command, reply::Reply},
+        connector::connector::Connector,
+        embedded::Db,
+        services::{
+            ndjson::{self, Entry, EntryValue, ImportOptions, ImportStats, TtlMode},
+            persistence_service::AppendFsync,
+            store::Store,
+        },
+        Settings,
+    };
+
+    use crate::{start_server, temp_db_file, temp_wal_dir};
+
+    async fn sample() -> Db {
+        let db = Db::with_store(Store::new(Settings::default()));
+        db.set("name", "kvds").await.unwrap();
+        db.set_ex("session", "abc", 100).await.unwrap();
+        db.rpush("list", &["a", "b"]).await.unwrap();
+        db.select(2).await.unwrap();
+        db.set("other", "line\nbreak \"quoted\"").await.unwrap();
+        db.select(0).await.unwrap();
+        db
+    }
+
+    async fn export(db: &Db) -> Vec<Entry> {
+        let mut out = Vec::new();
+        ndjson::export(db, &mut out).await.unwrap();
+        let mut entries: Vec<Entry> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        entries.sort_by(|a, b| (a.db, &a.key).cmp(&(b.db, &b.key)));
+        entries
+    }
+
+    #[tokio::test]
+    async fn export_writes_one_line_per_key() {
+        let mut entries = export(&sample().await).await;
+        let pttl = entries[2].pttl.take();
+        assert!(pttl.is_some_and(|pttl| pttl > 99_000 && pttl <= 100_000));
+        assert_eq!(
+            entries,
+            vec![
+                Entry {
+                    db: 0,
+                    key: "list".to_string(),
+                    value: EntryValue::List(vec!["a".to_string(), "b".to_string()]),
+                    pttl: None,
+                    ttl: None,
+                },
+                Entry {
+                    db: 0,
+                    key: "name".to_string(),
+                    value: EntryValue::String("kvds".to_string()),
+                    pttl: None,
+                    ttl: None,
+                },
+                Entry {
+                    db: 0,
+                    key: "session".to_string(),
+                    value: EntryValue::String("abc".to_string()),
+                    pttl: None,
+                    ttl: None,
+                },
+                Entry {
+                    db: 2,
+                    key: "other".to_string(),
+                    value: EntryValue::String("line\nbreak \"quoted\"".to_string()),
+                    pttl: None,
+                    ttl: None,
+                },
+            ]
+        );
+        entries[2].pttl = Some(1500);
+        assert_eq!(
+            serde_json::to_string(&entries[2]).unwrap(),
+            r#"{"db":0,"key":"session","type":"string","value":"abc","pttl":1500}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn import_restores_an_export() {
+        let mut out = Vec::new();
+        ndjson::export(&sample().await, &mut out).await.unwrap();
+        let target = Db::with_store(Store::new(Settings::default()));
+        let stats = ndjson::import(&target, Cursor::new(out), ImportOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(stats.to_string(), "imported 4 keys");
+        assert_eq!(target.get("name").await, Ok(Some("kvds".to_string())));
+        assert_eq!(target.lrange("list", 0, -1).await.unwrap(), vec!["a", "b"]);
+        assert!(matches!(target.ttl("session").await, Ok(98 | 99)));
+        target.select(2).await.unwrap();
+        assert_eq!(
+            target.get("other").await,
+            Ok(Some("line\nbreak \"quoted\"".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn import_options_control_overwrites_and_ttls() {
+        let input = concat!(
+            r#"{"key":"name","type":"string","value":"new","ttl":30}"#,
+            "\n\n",
+            r#"{"key":"list","type":"list","value":["x"]}"#,
+            "\n"
+        );
+        let db = Db::with_store(Store::new(Settings::default()));
+        db.set("name", "old").await.unwrap();
+
+        let stats = ndjson::import(&db, Cursor::new(input), ImportOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            stats,
+            ImportStats {
+                imported: 1,
+                skipped: 1
+            }
+        );
+        assert_eq!(db.get("name").await, Ok(Some("old".to_string())));
+
+        let options = ImportOptions {
+            replace: true,
+            ttl: TtlMode::Drop,
+        };
+        ndjson::import(&db, Cursor::new(input), options)
+            .await
+            .unwrap();
+        assert_eq!(db.get("name").await, Ok(Some("new".to_string())));
+        assert_eq!(db.ttl("name").await, Ok(-1));
+
+        let bad = "{\"key\":\"a\",\"type\":\"string\",\"value\":\"b\"}\n{\"key\":1}\n";
+        let err = ndjson::import(&db, Cursor::new(bad), ImportOptions::default())
+            .await
+            .unwrap_err();
+        assert!(err.starts_with("line 2:"));
+
+        let huge = format!(
+            r#"{{"key":"a","type":"string","value":"b","ttl":{}}}"#,
+            u64::MAX
+        );
+        let err = ndjson::import(&db, Cursor::new(huge), ImportOptions::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err, "line 1: TTL out of range");
+    }
+
+    #[tokio::test]
+    async fn sub_second_ttls_survive_an_export() {
+        let db = Db::with_store(Store::new(Settings::default()));
+        db.set("short", "lived").await.unwrap();
+        let payload = db.dump("short").await.unwrap().unwrap();
+        db.restore("short", 800, &payload, true).await.unwrap();
+        let mut out = Vec::new();
+        ndjson::export(&db, &mut out).await.unwrap();
+
+        let target = Db::with_store(Store::new(Settings::default()));
+        ndjson::import(&target, Cursor::new(out), ImportOptions::default())
+            .await
+            .unwrap();
+        assert!(matches!(target.pttl("short").await, Ok(1..=800)));
+    }
+
+    #[tokio::test]
+    async fn offline_export_leaves_the_files_alone() {
+        let wal_dir = temp_wal_dir("ndjson-offline");
+        let settings = Settings {
+            persist: true,
+            wal_dir: wal_dir.clone(),
+            db_file: format!("{wal_dir}.log"),
+            appendfsync: AppendFsync::Always,
+            ..Settings::default()
+        };
+        let db = Db::open_read_only(settings.clone()).await.unwrap();
+        assert_eq!(export(&db).await, vec![]);
+        assert!(!std::path::Path::new(&wal_dir).exists());
+
+        let db = Db::open(settings.clone()).await;
+        db.set("name", "kvds").await.unwrap();
+        drop(db);
+        let manifest = std::fs::read(format!("{wal_dir}/manifest.json")).unwrap();
+        let db = Db::open_read_only(settings).await.unwrap();
+        assert_eq!(db.get("name").await, Ok(Some("kvds".to_string())));
+        db.set("name", "changed").await.unwrap();
+        assert_eq!(
+            std::fs::read(format!("{wal_dir}/manifest.json")).unwrap(),
+            manifest
+        );
+
+        // a snapshot-only deployment is read from its snapshot file
+        let snapshot_file = temp_db_file("ndjson-offline-snapshot");
+        let settings = Settings {
+            snapshot_file: snapshot_file.clone(),
+            ..Settings::default()
+        };
+        let db = Db::open(settings.clone()).await;
+        db.set("saved", "1").await.unwrap();
+        db.store().save().await.unwrap();
+        let db = Db::open_read_only(settings).await.unwrap();
+        assert_eq!(db.keys("*").await.unwrap(), vec!["saved"]);
+        let _ = std::fs::remove_file(snapshot_file);
+        let _ = std::fs::remove_dir_all(wal_dir);
+    }
+
+    #[tokio::test]
+    async fn export_and_import_through_a_server() {
+        let source = Connector::with_port(&start_server(Store::new(Settings::default())));
+        let long = "x".repeat(10_000);
+        source.insert("long", &long);
+        let mut out = Vec::new();
+        assert_eq!(ndjson::export(&source, &mut out).await, Ok(1));
+
+        let target = Connector::with_port(&start_server(Store::new(Settings::default())));
+        ndjson::import(&target, Cursor::new(out), ImportOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            target.call(Command::cmd_get("long")),
+            Reply::Bulk(Some(long))
+        );
+    }
+}
+
+#[cfg(test)]
+mod storage_tests {
+    use std::{sync::Arc, time::Duration};
+
+    use kvds::{
+        embedded::Db,
+        services::{clock::ManualClock, storage::StorageKind, store::Store},
+        Settings,
+    };
+
+    use crate::{temp_db_file, temp_wal_dir};
+
+    // a handful of keys in memory and tiny tables, so most keys live on disk
+    fn on_disk(name: &str) -> Settings {
+        Settings {
+            storage: StorageKind::Lsm,
+            storage_dir: temp_db_file(name),
+            hot_keys: 8,
+            lsm_memtable_size: 512,
+            shards: 2,
+            ..Settings::default()
+        }
+    }
+
+    async fn fill(db: &Db) {
+        for i in 0..300 {
+            db.set(&format!("key{i}"), &format!("value{i}"))
+                .await
+                .unwrap();
+        }
+        for i in 0..20 {
+            db.rpush(&format!("list{i}"), &["a", "b"]).await.unwrap();
+        }
+        for i in 0..50 {
+            db.del(&format!("key{i}")).await.unwrap();
+        }
+        for i in 50..100 {
+            db.set(&format!("key{i}"), "overwritten").await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn keys_beyond_the_hot_set_are_served_from_disk() {
+        let settings = on_disk("lsm");
+        let db = Db::with_store(Store::new(settings.clone()));
+        fill(&db).await;
+
+        let tables = table_files(&settings.storage_dir);
+        assert!(tables > 0);
+        assert_eq!(db.keys("*").await.unwrap().len(), 270);
+        assert_eq!(db.get("key10").await, Ok(None));
+        assert_eq!(db.get("key60").await, Ok(Some("overwritten".to_string())));
+        assert_eq!(db.get("key299").await, Ok(Some("value299".to_string())));
+        assert_eq!(
+            db.incr("key100").await.unwrap_err().0,
+            "ERR value is not an integer or out of range"
+        );
+        assert_eq!(db.lpush("list3", &["z"]).await, Ok(3));
+        assert_eq!(
+            db.lrange("list3", 0, -1).await.unwrap(),
+            vec!["z", "a", "b"]
+        );
+
+        // only the keys kept in memory count as used memory
+        let memory = Db::with_store(Store::new(Settings::default()));
+        fill(&memory).await;
+        memory.lpush("list3", &["z"]).await.unwrap();
+        assert!(db.store().used_memory() > 0);
+        assert!(db.store().used_memory() < memory.store().used_memory() / 10);
+
+        db.flush_all().await.unwrap();
+        assert_eq!(db.keys("*").await, Ok(vec![]));
+        assert_eq!(db.store().used_memory(), 0);
+        assert_eq!(table_files(&settings.storage_dir), 0);
+    }
+
+    #[tokio::test]
+    async fn keys_on_disk_still_expire() {
+        let clock = Arc::new(ManualClock::new());
+        let db = Db::with_store(Store::with_clock(on_disk("lsm-expire"), clock.clone()));
+        db.set_ex("volatile", "value", 10).await.unwrap();
+        fill(&db).await;
+        assert!((9..=10).contains(&db.ttl("volatile").await.unwrap()));
+
+        fill(&db).await;
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(db.keys("vol*").await, Ok(vec![]));
+        assert_eq!(db.get("volatile").await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn disk_engine_is_rebuilt_from_the_log() {
+        let settings = Settings {
+            persist: true,
+            wal_dir: temp_wal_dir("lsm"),
+            ..on_disk("lsm-log")
+        };
+        let db = Db::open(settings.clone()).await;
+        fill(&db).await;
+        drop(db);
+
+        let reopened = Db::open(settings).await;
+        assert_eq!(reopened.keys("*").await.unwrap().len(), 270);
+        assert_eq!(
+            reopened.get("key299").await,
+            Ok(Some("value299".to_string()))
+        );
+        assert_eq!(
+            reopened.lrange("list7", 0, -1).await.unwrap(),
+            vec!["a", "b"]
+        );
+    }
+
+    #[tokio::test]
+    async fn unreadable_tables_fail_the_command() {
+        let settings = on_disk("lsm-unreadable");
+        let db = Db::with_store(Store::new(settings.clone()));
+        fill(&db).await;
+        for shard in std::fs::read_dir(&settings.storage_dir).unwrap().flatten() {
+            for table in std::fs::read_dir(shard.path()).unwrap().flatten() {
+                // past the first key length, so reading any record runs out
+                let file = std::fs::OpenOptions::new().write(true).open(table.path());
+                file.unwrap().set_len(5).unwrap();
+            }
+        }
+
+        let mut failed = 0;
+        for i in 100..300 {
+            if let Err(e) = db.set(&format!("key{i}"), "new").await {
+                assert!(e.0.starts_with("ERR storage engine: "), "{}", e.0);
+                failed += 1;
+            }
+        }
+        assert!(failed > 0);
+        assert!(db.keys("*").await.is_err());
+    }
+
+    // table files under the storage directory
+    fn table_files(dir: &str) -> usize {
+        std::fs::read_dir(dir)
+            .map(|shards| {
+                shards
+                    .flatten()
+                    .flat_map(|shard| std::fs::read_dir(shard.path()).into_iter().flatten())
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod eviction_tests {
+    use std::{sync::Arc, time::Duration};
+
+    use kvds::{
+        embedded::Db,
+        services::{
+            clock::ManualClock, eviction::MaxmemoryPolicy, storage::StorageKind, store::Store,
+        },
+        Settings,
+    };
+
+    use crate::temp_db_file;
+
+    fn limited(maxmemory: usize, policy: MaxmemoryPolicy) -> (Db, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        let settings = Settings {
+            shards: 1,
+            maxmemory,
+            maxmemory_policy: policy,
+            maxmemory_samples: 300,
+            ..Settings::default()
+        };
+        (
+            Db::with_store(Store::with_clock(settings, clock.clone())),
+            clock,
+        )
+    }
+
+    #[tokio::test]
+    async fn used_memory_follows_the_keyspace() {
+        let db = Db::with_store(Store::new(Settings::default()));
+        assert_eq!(db.store().used_memory(), 0);
+
+        db.set("some-key", "some-value").await.unwrap();
+        let one_key = db.store().used_memory();
+        assert!(one_key > "some-key".len() + "some-value".len());
+
+        db.rpush("list", &["a", "b", "c"]).await.unwrap();
+        let with_list = db.store().used_memory();
+        db.rpop("list").await.unwrap();
+        assert!(db.store().used_memory() < with_list);
+
+        db.del("list").await.unwrap();
+        assert_eq!(db.store().used_memory(), one_key);
+        db.flush_all().await.unwrap();
+        assert_eq!(db.store().used_memory(), 0);
+    }
+
+    #[tokio::test]
+    async fn noeviction_refuses_writes() {
+        let (db, _) = limited(500, MaxmemoryPolicy::Noeviction);
+
+        let mut refused = None;
+        for i in 0..100 {
+            if let Err(e) = db.set(&format!("key{i}"), "some-value").await {
+                refused = Some(e);
+                break;
+            }
+        }
+        assert!(refused.unwrap().0.starts_with("OOM"));
+        // ================ READS AND DELETES STILL WORK ====================
+        assert_eq!(db.get("key0").await, Ok(Some("some-value".to_string())));
+        assert!(db.del("key0").await.unwrap());
+    }
+
+    // every "keyNN" -> "value" entry is accounted as 74 bytes
+    const TEN_KEYS: usize = 10 * 74;
+
+    #[tokio::test]
+    async fn allkeys_lru_evicts_the_least_recently_used() {
+        let (db, clock) = limited(TEN_KEYS, MaxmemoryPolicy::AllkeysLru);
+        for i in 0..10 {
+            db.set(&format!("key{i:02}"), "value").await.unwrap();
+            clock.advance(Duration::from_secs(1));
+        }
+        // ================ TOUCH THE OLDEST KEY ===========================
+        db.get("key00").await.unwrap();
+
+        for i in 10..12 {
+            clock.advance(Duration::from_secs(1));
+            db.set(&format!("key{i:02}"), "value").await.unwrap();
+        }
+        assert!(db.store().used_memory() <= TEN_KEYS + 74);
+        assert_eq!(db.get("key00").await, Ok(Some("value".to_string())));
+        assert_eq!(db.get("key01").await, Ok(None));
+        assert_eq!(db.keys("*").await.unwrap().len(), 11);
+    }
+
+    #[tokio::test]
+    async fn allkeys_lfu_keeps_frequently_used_keys() {
+        let (db, _) = limited(TEN_KEYS, MaxmemoryPolicy::AllkeysLfu);
+        for i in 0..11 {
+            db.set(&format!("key{i:02}"), "value").await.unwrap();
+        }
+        for _ in 0..20 {
+            for i in 1..11 {
+                db.get(&format!("key{i:02}")).await.unwrap();
+            }
+        }
+
+        db.set("key11", "value").await.unwrap();
+        assert_eq!(db.get("key00").await, Ok(None));
+        assert_eq!(db.keys("*").await.unwrap().len(), 11);
+    }
+
+    #[tokio::test]
+    async fn volatile_policies_only_evict_keys_with_ttl() {
+        let (db, _) = limited(TEN_KEYS, MaxmemoryPolicy::VolatileTtl);
+        for i in 0..9 {
+            db.set(&format!("key{i:02}"), "value").await.unwrap();
+        }
+        db.set_ex("ttl-a", "value", 10).await.unwrap();
+        db.set_ex("ttl-b", "value", 1000).await.unwrap();
+
+        // ============== THE NEAREST EXPIRATION GOES FIRST ================
+        db.set("key09", "value").await.unwrap();
+        assert_eq!(db.get("ttl-a").await, Ok(None));
+        assert_eq!(db.get("ttl-b").await, Ok(Some("value".to_string())));
+
+        db.set("key10", "value").await.unwrap();
+        assert_eq!(db.get("ttl-b").await, Ok(None));
+
+        // ================= NOTHING LEFT TO EVICT =========================
+        let err = db.set("key11", "value").await.unwrap_err();
+        assert!(err.0.starts_with("OOM"));
+        assert_eq!(db.keys("key*").await.unwrap().len(), 11);
+    }
+
+    // only the keys the lsm engine keeps in memory count against maxmemory, every
+    // "keyNNN" -> "value" entry is accounted as 75 bytes
+    #[tokio::test]
+    async fn keys_on_disk_do_not_count_as_used_memory() {
+        let settings = Settings {
+            storage: StorageKind::Lsm,
+            storage_dir: temp_db_file("maxmemory-on-disk"),
+            hot_keys: 4,
+            shards: 1,
+            maxmemory: TEN_KEYS,
+            maxmemory_policy: MaxmemoryPolicy::Noeviction,
+            ..Settings::default()
+        };
+        let db = Db::with_store(Store::new(settings));
+        for i in 0..300 {
+            db.set(&format!("key{i:03}"), "value").await.unwrap();
+        }
+        assert!(db.store().used_memory() <= 4 * 75);
+        assert_eq!(db.keys("*").await.unwrap().len(), 300);
+
+        // ============ KEYS READ BACK PUSH OTHERS TO DISK ==================
+        for i in 0..20 {
+            assert_eq!(
+                db.get(&format!("key{i:03}")).await,
+                Ok(Some("value".to_string()))
+            );
+        }
+        assert!(db.store().used_memory() <= 4 * 75);
+        for i in 0..300 {
+            db.del(&format!("key{i:03}")).await.unwrap();
+        }
+        assert_eq!(db.store().used_memory(), 0);
+    }
+}
+
+#[cfg(test)]
+mod network_tests {
+    use std::{
+        io::{Read, Write},
+        net::{IpAddr, TcpStream},
+        sync::mpsc,
+        thread,
+    };
+
+    use kvds::{
+        app_server::{
+            parser::{parse_command, Command},
+            reply::Reply,
+            socket_server::{refuses, AppServer},
+        },
+        connector::connector::Connector,
+        services::store::Store,
+        Settings,
+    };
+
+    use crate::start_server;
+
+    fn reply(stream: &mut TcpStream, cmd: Command) -> String {
+        stream.write_all(cmd.to_string().as_bytes()).unwrap();
+        let mut buffer = [0; 512];
+        let n = stream.read(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..n]).to_string()
+    }
+
+    #[test]
+    fn listens_on_every_bind_address() {
+        let settings = Settings {
+            bind: "127.0.0.1 ::1".to_string(),
+            ..Settings::default()
+        };
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let server = AppServer::new("0", Store::new(settings));
+                let listeners = server.bind().await.unwrap();
+                let addrs = listeners
+                    .tcp
+                    .iter()
+                    .map(|l| l.local_addr().unwrap())
+                    .collect();
+                tx.send(addrs).unwrap();
+                server.serve(listeners).await
+            })
+        });
+        let addrs: Vec<std::net::SocketAddr> = rx.recv().unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert!(addrs[0].is_ipv4() && addrs[1].is_ipv6());
+
+        let v4 = Connector::with_url(&addrs[0].to_string());
+        let v6 = Connector::with_url(&addrs[1].to_string());
+        v4.insert("key", "value");
+        assert_eq!(v6.get("key"), Some("value".to_string()));
+    }
+
+    #[test]
+    fn protected_mode_refuses_remote_clients_without_a_password() {
+        let remote: IpAddr = "10.1.2.3".parse().unwrap();
+        let settings = Settings::default();
+        assert!(refuses(&settings, remote));
+        for local in ["127.0.0.1", "::1", "::ffff:127.0.0.1"] {
+            assert!(!refuses(&settings, local.parse().unwrap()));
+        }
+
+        let with_password = Settings {
+            requirepass: "secret".to_string(),
+            ..Settings::default()
+        };
+        assert!(!refuses(&with_password, remote));
+        let unprotected = Settings {
+            protected_mode: false,
+            ..Settings::default()
+        };
+        assert!(!refuses(&unprotected, remote));
+    }
+
+    #[test]
+    fn requirepass_needs_auth_first() {
+        let cmd = Command::cmd_auth("secret");
+        assert_eq!(parse_command(cmd.to_string()).unwrap(), cmd);
+
+        let port = start_server(Store::new(Settings {
+            requirepass: "secret".to_string(),
+            ..Settings::default()
+        }));
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{port}")).unwrap();
+        assert_eq!(
+            reply(&mut stream, Command::PING),
+            "-NOAUTH Authentication required.\r\n"
+        );
+        assert!(reply(&mut stream, Command::cmd_auth("wrong")).starts_with("-WRONGPASS"));
+        assert!(reply(&mut stream, Command::cmd_auth("secre")).starts_with("-WRONGPASS"));
+        assert!(reply(&mut stream, Command::cmd_auth("secrets")).starts_with("-WRONGPASS"));
+        assert_eq!(reply(&mut stream, Command::cmd_auth("secret")), "+OK\r\n");
+        assert_eq!(reply(&mut stream, Command::PING), "+PONG\r\n");
+
+        let c = Connector::with_password(&format!("127.0.0.1:{port}"), "secret");
+        c.insert("key", "value");
+        assert_eq!(c.call(Command::cmd_get("key")), Reply::bulk("value"));
+    }
+
+    #[test]
+    fn refuses_values_that_are_not_utf8() {
+        let port = start_server(Store::new(Settings::default()));
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{port}")).unwrap();
+        stream
+            .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$2\r\n\xff\xfe\r\n")
+            .unwrap();
+        let mut buffer = [0; 512];
+        let n = stream.read(&mut buffer).unwrap();
+        assert_eq!(
+            &buffer[..n],
+            b"-ERR keys and values must be valid UTF-8\r\n"
+        );
+        assert_eq!(reply(&mut stream, Command::cmd_get("key")), "$-1\r\n");
+        stream.write_all(b"*1\r\n$5\r\nNOPE!\r\n").unwrap();
+        let n = stream.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"-ERR unknown command or wrong arguments\r\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serves_the_same_protocol_on_a_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("kvds-{}.sock", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        // a socket file left behind by an earlier run is replaced
+        std::fs::write(&path, "stale").unwrap();
+        let port = start_server(Store::new(Settings {
+            unixsocket: path.clone(),
+            unixsocketperm: "700".to_string(),
+            requirepass: "secret".to_string(),
+            ..Settings::default()
+        }));
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        let local = Connector::with_unix_socket(&path, Some("secret"));
+        local.insert("key", "value");
+        let tcp = Connector::with_password(&format!("127.0.0.1:{port}"), "secret");
+        assert_eq!(tcp.get("key"), Some("value".to_string()));
+        assert_eq!(local.call(Command::cmd_get("key")), Reply::bulk("value"));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn auth_without_a_password_is_an_error() {
+        let c = Connector::with_port(&start_server(Store::new(Settings::default())));
+        let Reply::Error(e) = c.call(Command::cmd_auth("secret")) else {
+            panic!("AUTH succeeded without a password");
+        };
+        assert!(e.starts_with("ERR AUTH <password> called without any password configured"));
+    }
+}
+
+#[cfg(test)]
+mod tls_tests {
+    use std::{
+        io::Write,
+        net::TcpStream,
+        panic,
+        sync::{mpsc, Arc},
+        thread,
+    };
+
+    use kvds::{
+        app_server::{
+            parser::Command,
+            reply::Reply,
+            socket_server::AppServer,
+            tls::{client_config, ClientTls, TlsAuthClients},
+        },
+        connector::connector::Connector,
+        services::store::Store,
+        Settings,
+    };
+    use rustls::{
+        pki_types::{pem::PemObject, CertificateDer, ServerName},
+        ClientConnection, StreamOwned,
+    };
+
+    fn cert(name: &str) -> String {
+        format!("{}/tests/certs/{name}", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    fn tls_settings() -> Settings {
+        Settings {
+            tls_port: Some(0),
+            tls_cert_file: cert("server.pem"),
+            tls_key_file: cert("server.key"),
+            ..Settings::default()
+        }
+    }
+
+    fn trusting() -> ClientTls {
+        ClientTls {
+            ca_cert_file: cert("ca.pem"),
+            ..ClientTls::default()
+        }
+    }
+
+    // the server, its plain port and its TLS port
+    fn start_tls_server(settings: Settings) -> (Arc<AppServer>, u16, u16) {
+        let server = Arc::new(AppServer::new("0", Store::new(settings)));
+        let serving = server.clone();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let listeners = serving.bind().await.unwrap();
+                let port = |l: &tokio::net::TcpListener| l.local_addr().unwrap().port();
+                tx.send((port(&listeners.tcp[0]), port(&listeners.tls[0])))
+                    .unwrap();
+                serving.serve(listeners).await
+            })
+        });
+        let (port, tls_port) = rx.recv().unwrap();
+        (server, port, tls_port)
+    }
+
+    // the certificate the server presents to a new connection
+    fn presented(tls_port: u16) -> CertificateDer<'static> {
+        let config = client_config(&trusting()).unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let connection = ClientConnection::new(config, name).unwrap();
+        let socket = TcpStream::connect(("127.0.0.1", tls_port)).unwrap();
+        let mut stream = StreamOwned::new(connection, socket);
+        stream.flush().unwrap();
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock).unwrap();
+        }
+        stream.conn.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[test]
+    fn serves_tls_next_to_plain_tcp() {
+        let (_server, port, tls_port) = start_tls_server(tls_settings());
+        let secure = Connector::with_tls(&format!("127.0.0.1:{tls_port}"), &trusting(), None);
+        secure.insert("key", "value");
+        let by_name = Connector::with_tls(&format!("localhost:{tls_port}"), &trusting(), None);
+        assert_eq!(by_name.get("key"), Some("value".to_string()));
+        let plain = Connector::with_port(&port.to_string());
+        assert_eq!(plain.call(Command::cmd_get("key")), Reply::bulk("value"));
+    }
+
+    #[test]
+    fn client_certificates_are_verified() {
+        let with_client_cert = ClientTls {
+            cert_file: cert("client.pem"),
+            key_file: cert("client.key"),
+            ..trusting()
+        };
+        let (_server, _, tls_port) = start_tls_server(Settings {
+            tls_ca_cert_file: cert("ca.pem"),
+            ..tls_settings()
+        });
+        let url = format!("127.0.0.1:{tls_port}");
+        let verified = Connector::with_tls(&url, &with_client_cert, None);
+        assert_eq!(
+            verified.call(Command::PING),
+            Reply::Simple("PONG".to_string())
+        );
+        let anonymous = panic::catch_unwind(|| Connector::with_tls(&url, &trusting(), None));
+        assert!(anonymous.is_err());
+
+        let (_server, _, tls_port) = start_tls_server(Settings {
+            tls_ca_cert_file: cert("ca.pem"),
+            tls_auth_clients: TlsAuthClients::Optional,
+            ..tls_settings()
+        });
+        let url = format!("127.0.0.1:{tls_port}");
+        Connector::with_tls(&url, &trusting(), None);
+        Connector::with_tls(&url, &with_client_cert, None);
+    }
+
+    #[test]
+    fn certificates_reload_without_a_restart() {
+        let dir = std::env::temp_dir().join(format!("kvds-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_file, key_file) = (dir.join("server.pem"), dir.join("server.key"));
+        std::fs::copy(cert("server.pem"), &cert_file).unwrap();
+        std::fs::copy(cert("server.key"), &key_file).unwrap();
+        let (server, _, tls_port) = start_tls_server(Settings {
+            tls_cert_file: cert_file.to_string_lossy().to_string(),
+            tls_key_file: key_file.to_string_lossy().to_string(),
+            ..tls_settings()
+        });
+        let first = CertificateDer::from_pem_file(cert("server.pem")).unwrap();
+        let renewed = CertificateDer::from_pem_file(cert("server-renewed.pem")).unwrap();
+        assert_eq!(presented(tls_port), first);
+        let open = Connector::with_tls(&format!("127.0.0.1:{tls_port}"), &trusting(), None);
+
+        // a broken file keeps the certificate in use
+        std::fs::write(&key_file, "not a key").unwrap();
+        assert!(server.reload_tls().is_err());
+        assert_eq!(presented(tls_port), first);
+
+        std::fs::copy(cert("server-renewed.pem"), &cert_file).unwrap();
+        std::fs::copy(cert("server-renewed.key"), &key_file).unwrap();
+        server.reload_tls().unwrap();
+        assert_eq!(presented(tls_port), renewed);
+        assert_eq!(open.call(Command::PING), Reply::Simple("PONG".to_string()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
+
+mod connector_tests {
+    use kvds::{connector::connector::Connector, services::store::Store, Settings};
+
+    use crate::{flush_all, start_server};
+
+    #[test]
+    fn test_connector() {
+        // ======================= SET SOME VALUES ========================
+        let c = Connector::with_port(&start_server(Store::new(Settings::default())));
+
+        let keys = vec!["key1", "key2", "key3", "key4", "key5"];
+        for k in &keys {
+            c.insert(k, "some-value");
+        }
+
+        // ==================== GET KEYS BY PATTERN =======================
+
+        let mut result = c.keys("*");
+        result.sort();
+        assert_eq!(keys, result);
+
+        let value = c.get("key1");
+        assert_eq!(value, Some("some-value".to_string()));
+
+        let value = c.get("unknown");
+        assert_eq!(value, None);
+
+        flush_all(&c)
+    }
+}
+
+pub fn flush_all(c: &Connector) {
+    let resp = c.call_server(Command::FLUSHALL);
+    assert_eq!(resp, "+OK\r\n");
+}
+
+// serves the store on a free port from its own runtime and returns the port
+pub fn start_server(store: Arc<Store>) -> String {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let server = AppServer::new("0", store);
+            let listeners = server.bind().await.unwrap();
+            tx.send(listeners.tcp[0].local_addr().unwrap().port().to_string())
+                .unwrap();
+            server.serve(listeners).await
+        })
+    });
+    rx.recv().unwrap()
+}
+
+// a path under the system temp directory with nothing at it yet
+pub fn temp_db_file(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("kvds-{}-{}.db", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path.to_string_lossy().to_string()
+}
+
+// a log directory under the system temp directory, removed if a previous run left it
+pub fn temp_wal_dir(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("kvds-{}-{}.wal", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let _ = std::fs::remove_file(format!("{}.log", path.display()));
+    path.to_string_lossy().to_string()
 }